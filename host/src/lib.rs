@@ -0,0 +1,40 @@
+//! The parts of rustberry that do not touch the hardware, built for the host.
+//!
+//! The modules are compiled straight from the firmware sources, so whatever is tested here is
+//! exactly what runs on the Pi. Anything included this way must only depend on `core`.
+//!
+//! `rpc_client` is the exception: the host's end of the RPC channel, for tools to build on.
+
+#[path = "../../src/ps2/decoder.rs"]
+pub mod ps2_decoder;
+
+#[path = "../../src/keyboard/scancode.rs"]
+pub mod scancode;
+
+#[path = "../../src/keyboard/layout.rs"]
+pub mod layout;
+
+#[path = "../../src/mouse/packet.rs"]
+pub mod mouse_packet;
+
+#[path = "../../src/shell/line.rs"]
+pub mod line_editor;
+
+#[path = "../../src/bootloader/xmodem.rs"]
+pub mod xmodem;
+
+#[path = "../../src/gdb/packet.rs"]
+pub mod gdb_packet;
+
+#[path = "../../src/gdb/step.rs"]
+pub mod gdb_step;
+
+// The RPC modules refer to each other through `super`, so they keep a module of their own
+#[path = "../../src/rpc"]
+pub mod rpc {
+    pub mod frame;
+    pub mod message;
+    pub mod server;
+}
+
+pub mod rpc_client;
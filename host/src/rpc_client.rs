@@ -0,0 +1,244 @@
+//! The host's end of the RPC channel, for tools that talk to a Pi running the shell's `rpc`
+//! command.
+//!
+//! The client works on anything that reads and writes bytes, normally the serial device opened
+//! as a file. Set the port up first so that reads return after a short while without data,
+//! e.g. `stty -F /dev/ttyUSB0 115200 raw -echo min 0 time 1`:
+//!
+//! ```no_run
+//! use rustberry_host::rpc_client::Client;
+//! use std::fs::OpenOptions;
+//!
+//! let port = OpenOptions::new().read(true).write(true).open("/dev/ttyUSB0")?;
+//! let mut client = Client::new(port);
+//! println!("up for {} us", client.ticks()?);
+//! client.close()?;
+//! # Ok::<(), rustberry_host::rpc_client::ClientError>(())
+//! ```
+//!
+//! Console text the Pi prints meanwhile is collected rather than getting in the way, see
+//! `Client::take_console()`.
+
+use crate::rpc::frame::{self, FrameReader, Received, CRC_SIZE, HEADER_SIZE, MAX_FRAME};
+use crate::rpc::message::{ErrorCode, Request, Response, MAX_DATA};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to wait for a response before sending the request again.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many times a request is sent again before giving up.
+pub const DEFAULT_RETRIES: u32 = 5;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// No response arrived, however often the request was sent.
+    Timeout,
+    /// The Pi could not carry out the request.
+    Remote(ErrorCode),
+    /// The response does not fit the request, or could not be decoded.
+    Unexpected,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "{}", err),
+            ClientError::Timeout => write!(f, "no response"),
+            ClientError::Remote(code) => write!(f, "the Pi answered {:?}", code),
+            ClientError::Unexpected => write!(f, "unexpected response"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+pub struct Client<P: Read + Write> {
+    port: P,
+    reader: FrameReader,
+    seq: u8,
+    timeout: Duration,
+    retries: u32,
+    console: Vec<u8>,
+}
+
+impl<P: Read + Write> Client<P> {
+    pub fn new(port: P) -> Self {
+        // start where a client before this one is unlikely to have stopped, so that the first
+        // request is not taken for one the Pi has answered already
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        Client {
+            port,
+            reader: FrameReader::new(),
+            seq: seed as u8,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            console: Vec::new(),
+        }
+    }
+
+    /// Wait `timeout` for each response, and send each request up to `retries` more times.
+    pub fn set_timeout(&mut self, timeout: Duration, retries: u32) {
+        self.timeout = timeout;
+        self.retries = retries;
+    }
+
+    /// Returns the text the Pi printed between frames since the last call.
+    pub fn take_console(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.console)
+    }
+
+    pub fn into_port(self) -> P {
+        self.port
+    }
+
+    /// Send `request` until its response arrives, and return what `f` makes of the response.
+    pub fn call<R>(
+        &mut self,
+        request: &Request,
+        f: impl FnOnce(Response) -> Result<R, ClientError>,
+    ) -> Result<R, ClientError> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut frame = [0; MAX_FRAME];
+        let body_len = request
+            .encode(&mut frame[HEADER_SIZE..MAX_FRAME - CRC_SIZE])
+            .ok_or(ClientError::Remote(ErrorCode::TooLong))?;
+        let len = frame::seal(&mut frame, frame::REQUEST, self.seq, body_len);
+        let mut encoded = Vec::new();
+        frame::send(&frame[..len], &mut |byte| encoded.push(byte));
+
+        for _ in 0..=self.retries {
+            self.port.write_all(&encoded)?;
+            self.port.flush()?;
+            if let Some(body) = self.wait_response()? {
+                return match Response::decode(&body) {
+                    Ok(Response::Error(code)) => Err(ClientError::Remote(code)),
+                    Ok(response) => f(response),
+                    Err(_) => Err(ClientError::Unexpected),
+                };
+            }
+        }
+        Err(ClientError::Timeout)
+    }
+
+    // Returns the body of the response to the current request, or None if it is time to send
+    // the request again
+    fn wait_response(&mut self) -> Result<Option<Vec<u8>>, ClientError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = [0; 256];
+        while Instant::now() < deadline {
+            let count = match self.port.read(&mut buffer) {
+                Ok(count) => count,
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted => 0,
+                    _ => return Err(err.into()),
+                },
+            };
+            for &byte in &buffer[..count] {
+                match self.reader.feed(byte) {
+                    Some(Received::Frame(frame))
+                        if frame.kind == frame::RESPONSE && frame.seq == self.seq =>
+                    {
+                        return Ok(Some(frame.body.to_vec()));
+                    }
+                    Some(Received::Frame(frame)) if frame.kind == frame::NAK => return Ok(None),
+                    // a late response to an earlier request
+                    Some(Received::Frame(_)) => {}
+                    Some(Received::Rejected { raw, .. }) => self.console.extend_from_slice(raw),
+                    None => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `value` back from the Pi.
+    pub fn ping(&mut self, value: u32) -> Result<u32, ClientError> {
+        self.call(&Request::Ping(value), |response| match response {
+            Response::Pong(value) => Ok(value),
+            _ => Err(ClientError::Unexpected),
+        })
+    }
+
+    /// The Pi's system timer, in microseconds since boot.
+    pub fn ticks(&mut self) -> Result<u32, ClientError> {
+        self.call(&Request::Ticks, |response| match response {
+            Response::Ticks(ticks) => Ok(ticks),
+            _ => Err(ClientError::Unexpected),
+        })
+    }
+
+    /// Reads `len` bytes from `address` on, in as many requests as it takes.
+    pub fn read_memory(&mut self, address: u32, len: usize) -> Result<Vec<u8>, ClientError> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let chunk = (len - data.len()).min(MAX_DATA);
+            let request = Request::ReadMemory {
+                address: address.wrapping_add(data.len() as u32),
+                len: chunk as u16,
+            };
+            let bytes = self.call(&request, |response| match response {
+                Response::Memory(bytes) if bytes.len() == chunk => Ok(bytes.to_vec()),
+                _ => Err(ClientError::Unexpected),
+            })?;
+            data.extend_from_slice(&bytes);
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` from `address` on, in as many requests as it takes.
+    pub fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), ClientError> {
+        for (i, chunk) in data.chunks(MAX_DATA).enumerate() {
+            let request = Request::WriteMemory {
+                address: address.wrapping_add((i * MAX_DATA) as u32),
+                data: chunk,
+            };
+            self.call(&request, done)?;
+        }
+        Ok(())
+    }
+
+    /// The function select value and the level of GPIO `pin`.
+    pub fn read_gpio(&mut self, pin: u8) -> Result<(u8, bool), ClientError> {
+        self.call(&Request::ReadGpio { pin }, |response| match response {
+            Response::Gpio { function, level } => Ok((function, level)),
+            _ => Err(ClientError::Unexpected),
+        })
+    }
+
+    /// Makes GPIO `pin` an output and drives it to `level`.
+    pub fn write_gpio(&mut self, pin: u8, level: bool) -> Result<(), ClientError> {
+        self.call(&Request::WriteGpio { pin, level }, done)
+    }
+
+    /// Bytes used and free on the Pi's heap.
+    pub fn heap(&mut self) -> Result<(u32, u32), ClientError> {
+        self.call(&Request::Heap, |response| match response {
+            Response::Heap { used, free } => Ok((used, free)),
+            _ => Err(ClientError::Unexpected),
+        })
+    }
+
+    /// Ends the session, returning the Pi to its shell.
+    pub fn close(&mut self) -> Result<(), ClientError> {
+        self.call(&Request::Close, done)
+    }
+}
+
+fn done(response: Response) -> Result<(), ClientError> {
+    match response {
+        Response::Done => Ok(()),
+        _ => Err(ClientError::Unexpected),
+    }
+}
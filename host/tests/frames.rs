@@ -0,0 +1,31 @@
+use rustberry_host::ps2_decoder::{
+    decode_frame, encode_frame, FrameDecoder, FrameError, FRAME_TIMEOUT_US,
+};
+
+#[test]
+fn every_byte_round_trips() {
+    for data in 0..=0xff_u8 {
+        let frame = encode_frame(data);
+        assert_eq!(frame & 1, 0);
+        assert_eq!((frame >> 10) & 1, 1);
+        assert_eq!(frame.count_ones() % 2, 0); // data + parity is odd, plus the stop bit
+        assert_eq!(decode_frame(frame), Ok(data));
+    }
+}
+
+#[test]
+fn bad_frames_are_rejected() {
+    let frame = encode_frame(0x1C);
+    assert_eq!(decode_frame(frame ^ (1 << 9)), Err(FrameError::Parity));
+    assert_eq!(decode_frame(frame | 1), Err(FrameError::Framing));
+    assert_eq!(decode_frame(frame & !(1 << 10)), Err(FrameError::Framing));
+}
+
+#[test]
+fn receiving_until_the_frame_times_out() {
+    let mut decoder = FrameDecoder::new();
+    assert!(!decoder.is_receiving(0));
+    assert_eq!(decoder.falling_edge(false, 100), None); // start bit
+    assert!(decoder.is_receiving(150));
+    assert!(!decoder.is_receiving(100 + FRAME_TIMEOUT_US + 1));
+}
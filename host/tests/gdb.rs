@@ -0,0 +1,149 @@
+use rustberry_host::gdb_packet::{
+    checksum, decode_hex, frame, parse_hex, parse_word, Event, PacketReader, Response, INTERRUPT,
+    PACKET_SIZE,
+};
+use rustberry_host::gdb_step::next_pc;
+
+// Feeds `bytes` to `reader`, returning the events as owned data
+fn read(reader: &mut PacketReader, bytes: &[u8]) -> Vec<Result<Vec<u8>, &'static str>> {
+    bytes
+        .iter()
+        .filter_map(|&byte| match reader.feed(byte)? {
+            Event::Packet(data) => Some(Ok(data.to_vec())),
+            Event::BadPacket => Some(Err("bad")),
+            Event::Interrupt => Some(Err("interrupt")),
+        })
+        .collect()
+}
+
+#[test]
+fn packets() {
+    let mut reader = PacketReader::new();
+    let events = read(&mut reader, b"+$g#67$m8000,4#95-$?#3f");
+    assert_eq!(
+        events,
+        [
+            Ok(b"g".to_vec()),
+            Ok(b"m8000,4".to_vec()),
+            Ok(b"?".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn bad_packets_and_interrupts() {
+    let mut reader = PacketReader::new();
+    assert_eq!(read(&mut reader, b"$g#68"), [Err("bad")]);
+    assert_eq!(read(&mut reader, b"$g#x7"), [Err("bad")]);
+    assert_eq!(read(&mut reader, &[INTERRUPT]), [Err("interrupt")]);
+    // inside a packet 0x03 is data, and a '$' starts over
+    assert_eq!(read(&mut reader, b"$\x03$g#67"), [Ok(b"g".to_vec())]);
+
+    let mut long = vec![b'$'];
+    long.resize(PACKET_SIZE + 2, b'0');
+    long.push(b'#');
+    let sum = checksum(&long[1..long.len() - 1]);
+    long.extend(format!("{:02x}", sum).bytes());
+    assert_eq!(read(&mut reader, &long), [Err("bad")]);
+}
+
+#[test]
+fn framing_round_trip() {
+    let mut response = Response::new();
+    response.push(b"S");
+    response.push_hex(&[5]);
+    response.push_word(0x0000_8000);
+    assert_eq!(response.as_bytes(), b"S0500800000");
+
+    let mut sent = Vec::new();
+    frame(response.as_bytes(), &mut |byte| sent.push(byte));
+    let mut reader = PacketReader::new();
+    assert_eq!(read(&mut reader, &sent), [Ok(b"S0500800000".to_vec())]);
+}
+
+#[test]
+fn hex() {
+    assert_eq!(parse_hex(b"20200000"), Some(0x2020_0000));
+    assert_eq!(parse_hex(b"1F"), Some(0x1f));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"123456789"), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_word(b"00800000"), Some(0x8000));
+    assert_eq!(parse_word(b"008000"), None);
+
+    let mut out = [0; 4];
+    assert_eq!(decode_hex(b"deadBEEF", &mut out), Some(4));
+    assert_eq!(out, [0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(decode_hex(b"abc", &mut out), None);
+    assert_eq!(decode_hex(b"0011223344", &mut out), None);
+}
+
+const PC: u32 = 0x8000;
+const N: u32 = 1 << 31;
+const Z: u32 = 1 << 30;
+const C: u32 = 1 << 29;
+
+fn registers() -> [u32; 16] {
+    let mut registers = [0; 16];
+    for (index, register) in registers.iter_mut().enumerate() {
+        *register = 0x100 * index as u32;
+    }
+    registers[13] = 0x7f00;
+    registers[15] = PC;
+    registers
+}
+
+// Memory that holds its own address plus 1 in every word
+fn memory(address: u32) -> u32 {
+    address + 1
+}
+
+fn step(instruction: u32, cpsr: u32) -> u32 {
+    next_pc(instruction, &registers(), cpsr, &memory)
+}
+
+#[test]
+fn plain_instructions_fall_through() {
+    assert_eq!(step(0xe3a0_0001, 0), PC + 4); // mov r0, #1
+    assert_eq!(step(0xe591_2004, 0), PC + 4); // ldr r2, [r1, #4]
+    assert_eq!(step(0x012f_ff1e, 0), PC + 4); // bxeq lr, not taken
+    assert_eq!(step(0xe10f_0000, 0), PC + 4); // mrs r0, cpsr
+    assert_eq!(step(0xe000_f291, 0), PC + 4); // mul r0, r1, r2
+}
+
+#[test]
+fn branches() {
+    assert_eq!(step(0xea00_0002, 0), PC + 8 + 8); // b . + 16
+    assert_eq!(step(0xebff_fffe, 0), PC); // bl .
+    assert_eq!(step(0xeaff_fffd, 0), PC - 4); // b . - 4
+    assert_eq!(step(0x0a00_0002, Z), PC + 16); // beq, taken
+    assert_eq!(step(0x0a00_0002, 0), PC + 4); // beq, not taken
+    assert_eq!(step(0xca00_0002, N), PC + 4); // bgt with N != V
+    assert_eq!(step(0x8a00_0002, C), PC + 16); // bhi
+    assert_eq!(step(0xe12f_ff1e, 0), 0xe00); // bx lr
+    assert_eq!(step(0xe12f_ff33, 0), 0x300); // blx r3
+    assert_eq!(step(0xfa00_0000, 0), PC + 8); // blx to Thumb
+}
+
+#[test]
+fn data_processing_into_pc() {
+    assert_eq!(step(0xe1a0_f00e, 0), 0xe00); // mov pc, lr
+    assert_eq!(step(0xe28f_f010, 0), PC + 8 + 0x10); // add pc, pc, #16
+    assert_eq!(step(0xe08f_f101, 0), PC + 8 + 0x400); // add pc, pc, r1, lsl #2
+    assert_eq!(step(0xe24e_f004, 0), 0xe00 - 4); // sub pc, lr, #4
+    assert_eq!(step(0xe3a0_f902, 0), 0x8000); // mov pc, #0x8000
+    assert_eq!(step(0xe2a1_f000, C), 0x101); // adc pc, r1, #0
+    assert_eq!(step(0xe1a0_f062, C), 0x8000_0100); // mov pc, r2, rrx
+}
+
+#[test]
+fn loads_into_pc() {
+    assert_eq!(step(0xe59f_f004, 0), PC + 8 + 4 + 1); // ldr pc, [pc, #4]
+    assert_eq!(step(0xe49d_f004, 0), 0x7f00 + 1); // pop {pc} as ldr pc, [sp], #4
+    assert_eq!(step(0xe791_f102, 0), 0x100 + 0x800 + 1); // ldr pc, [r1, r2, lsl #2]
+    assert_eq!(step(0xe8bd_8010, 0), 0x7f00 + 4 + 1); // pop {r4, pc}
+    assert_eq!(step(0xe991_8003, 0), 0x100 + 12 + 1); // ldmib r1, {r0, r1, pc}
+    assert_eq!(step(0xe811_8003, 0), 0x100 + 1); // ldmda r1, {r0, r1, pc}
+    assert_eq!(step(0xe911_8003, 0), 0x100 - 4 + 1); // ldmdb r1, {r0, r1, pc}
+    assert_eq!(step(0xe8bd_0010, 0), PC + 4); // pop {r4}
+}
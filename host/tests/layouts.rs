@@ -0,0 +1,68 @@
+use rustberry_host::layout::{compose, find, Layout, AZERTY, DVORAK, LAYOUTS, US};
+use rustberry_host::scancode::{Key, Modifiers};
+
+const Q: u8 = 0x15; // top row, first letter
+const TWO: u8 = 0x1E;
+const E: u8 = 0x24;
+const CIRCUMFLEX: u8 = 0x54; // right of P on AZERTY
+
+#[test]
+fn every_layout_covers_every_typing_key() {
+    let typing_keys = (0..=0xff_u8).filter(|&keycode| Layout::is_typing_key(keycode));
+    assert_eq!(typing_keys.clone().count(), 48);
+    for layout in LAYOUTS.iter() {
+        for keycode in typing_keys.clone() {
+            let typed = layout.key(keycode, Modifiers::NONE).is_some()
+                || layout.key(keycode, Modifiers::LEFT_SHIFT).is_some();
+            assert!(typed, "{} types nothing on {:#x}", layout.name, keycode);
+        }
+    }
+}
+
+#[test]
+fn layers() {
+    assert_eq!(US.key(Q, Modifiers::NONE), Some(Key::Char('q')));
+    assert_eq!(DVORAK.key(Q, Modifiers::NONE), Some(Key::Char('\'')));
+    assert_eq!(AZERTY.key(Q, Modifiers::NONE), Some(Key::Char('a')));
+    assert_eq!(AZERTY.key(Q, Modifiers::RIGHT_SHIFT), Some(Key::Char('A')));
+
+    assert_eq!(AZERTY.key(TWO, Modifiers::NONE), Some(Key::Char('é')));
+    assert_eq!(AZERTY.key(TWO, Modifiers::LEFT_SHIFT), Some(Key::Char('2')));
+    assert_eq!(AZERTY.key(TWO, Modifiers::RIGHT_ALT), Some(Key::Char('~')));
+    assert_eq!(AZERTY.key(E, Modifiers::RIGHT_ALT), Some(Key::Char('€')));
+    assert_eq!(AZERTY.key(Q, Modifiers::RIGHT_ALT), None);
+
+    // without an AltGr layer, right alt is just alt
+    assert_eq!(US.key(E, Modifiers::RIGHT_ALT), Some(Key::Char('e')));
+}
+
+#[test]
+fn caps_lock_only_shifts_letters() {
+    assert_eq!(AZERTY.key(Q, Modifiers::CAPS_LOCK), Some(Key::Char('A')));
+    assert_eq!(AZERTY.key(TWO, Modifiers::CAPS_LOCK), Some(Key::Char('é')));
+    let shift_and_caps = Modifiers::LEFT_SHIFT | Modifiers::CAPS_LOCK;
+    assert_eq!(AZERTY.key(Q, shift_and_caps), Some(Key::Char('a')));
+    assert_eq!(US.key(TWO, Modifiers::CAPS_LOCK), Some(Key::Char('2')));
+}
+
+#[test]
+fn dead_keys() {
+    assert_eq!(
+        AZERTY.key(CIRCUMFLEX, Modifiers::NONE),
+        Some(Key::Dead('^'))
+    );
+    assert_eq!(
+        AZERTY.key(CIRCUMFLEX, Modifiers::LEFT_SHIFT),
+        Some(Key::Dead('¨'))
+    );
+    assert_eq!(compose('^', 'e'), Some('ê'));
+    assert_eq!(compose('¨', 'U'), Some('Ü'));
+    assert_eq!(compose('^', 'x'), None);
+    assert_eq!(compose('x', 'e'), None);
+}
+
+#[test]
+fn find_by_name() {
+    assert_eq!(find("dvorak").map(|layout| layout.name), Some("dvorak"));
+    assert!(find("colemak").is_none());
+}
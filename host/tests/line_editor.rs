@@ -0,0 +1,91 @@
+use rustberry_host::line_editor::{LineEditor, HISTORY_LEN, LINE_MAX};
+
+// Feeds `input` to `editor`, returning the lines entered and everything echoed
+fn type_in(editor: &mut LineEditor, input: &[u8]) -> (Vec<String>, Vec<u8>) {
+    let mut lines = Vec::new();
+    let mut echoed = Vec::new();
+    for &byte in input {
+        if let Some(line) = editor.feed(byte, &mut |bytes| echoed.extend_from_slice(bytes)) {
+            lines.push(line.to_string());
+        }
+    }
+    (lines, echoed)
+}
+
+#[test]
+fn lines_and_echo() {
+    let mut editor = LineEditor::new();
+    let (lines, echoed) = type_in(&mut editor, b"peek 0x8000\r\nhelp\n\r");
+    assert_eq!(lines, ["peek 0x8000", "help", ""]);
+    assert_eq!(echoed, b"peek 0x8000\r\nhelp\r\n\r\n");
+}
+
+#[test]
+fn backspace_and_kill_line() {
+    let mut editor = LineEditor::new();
+    let (lines, echoed) = type_in(&mut editor, b"hx\x7fi\x08\x08\x08yo\r");
+    assert_eq!(lines, ["yo"]);
+    assert_eq!(echoed, b"hx\x08 \x08i\x08 \x08\x08 \x08yo\r\n");
+
+    let (lines, _) = type_in(&mut editor, b"reboot\x15time\r");
+    assert_eq!(lines, ["time"]);
+}
+
+#[test]
+fn ctrl_c_abandons_the_line() {
+    let mut editor = LineEditor::new();
+    let (lines, echoed) = type_in(&mut editor, b"poke 0\x03");
+    assert_eq!(lines, [""]);
+    assert!(echoed.ends_with(b"^C\r\n"));
+}
+
+#[test]
+fn control_characters_and_long_lines_are_ignored() {
+    let mut editor = LineEditor::new();
+    let (lines, _) = type_in(&mut editor, b"a\x01\tb\r");
+    assert_eq!(lines, ["ab"]);
+
+    let long = vec![b'x'; LINE_MAX + 10];
+    let (_, echoed) = type_in(&mut editor, &long);
+    assert_eq!(echoed.len(), LINE_MAX);
+    let (lines, _) = type_in(&mut editor, b"\r");
+    assert_eq!(lines[0].len(), LINE_MAX);
+}
+
+#[test]
+fn history() {
+    const UP: &[u8] = b"\x1b[A";
+    const DOWN: &[u8] = b"\x1b[B";
+
+    let mut editor = LineEditor::new();
+    type_in(&mut editor, b"one\rtwo\rtwo\r\r");
+
+    // repeated and empty lines are only kept once
+    let (lines, _) = type_in(&mut editor, &[UP, UP, b"\r"].concat());
+    assert_eq!(lines, ["one"]);
+    // entering a recalled line makes it the newest one again
+    let (lines, _) = type_in(&mut editor, &[UP, UP, b"\r"].concat());
+    assert_eq!(lines, ["two"]);
+    let (lines, _) = type_in(&mut editor, &[UP, UP, UP, UP, UP, b"\r"].concat());
+    assert_eq!(lines, ["one"]);
+
+    // the recalled line replaces what was typed, and down goes back to an empty line
+    let (lines, echoed) = type_in(&mut editor, &[b"x", UP, DOWN, b"y\r"].concat());
+    assert_eq!(lines, ["y"]);
+    assert_eq!(echoed, b"x\x08 \x08one\x08 \x08\x08 \x08\x08 \x08y\r\n");
+
+    // other control sequences, e.g. the right arrow, are skipped
+    let (lines, _) = type_in(&mut editor, b"a\x1b[1;5Cb\r");
+    assert_eq!(lines, ["ab"]);
+}
+
+#[test]
+fn history_keeps_the_newest_lines() {
+    let mut editor = LineEditor::new();
+    for i in 0..HISTORY_LEN + 5 {
+        type_in(&mut editor, format!("{}\r", i).as_bytes());
+    }
+    let ups = b"\x1b[A".repeat(HISTORY_LEN + 3);
+    let (lines, _) = type_in(&mut editor, &[&ups[..], b"\r"].concat());
+    assert_eq!(lines, ["5"]);
+}
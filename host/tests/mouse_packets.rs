@@ -0,0 +1,52 @@
+use rustberry_host::mouse_packet::{Buttons, MouseEvent, PacketDecoder};
+
+fn decode(has_wheel: bool, bytes: &[u8]) -> Vec<MouseEvent> {
+    let mut decoder = PacketDecoder::new(has_wheel);
+    bytes
+        .iter()
+        .filter_map(|&byte| decoder.feed(byte))
+        .collect()
+}
+
+#[test]
+fn movement_and_signs() {
+    // right 5 and up 3, then left 2 and down 7
+    let events = decode(false, &[0x08, 0x05, 0x03, 0x38, 0xFE, 0xF9]);
+    assert_eq!((events[0].dx, events[0].dy), (5, -3));
+    assert_eq!((events[1].dx, events[1].dy), (-2, 7));
+    assert_eq!(events[0].wheel, 0);
+}
+
+#[test]
+fn overflow_saturates() {
+    let events = decode(false, &[0x48, 0x10, 0x00, 0xB8, 0x00, 0x00]);
+    assert_eq!(events[0].dx, 255);
+    assert_eq!(events[1].dy, 256); // y sign and overflow: -256 up is 256 down
+}
+
+#[test]
+fn button_changes() {
+    let events = decode(false, &[0x09, 0, 0, 0x0B, 0, 0, 0x0A, 0, 0]);
+    assert!(events[0].pressed(Buttons::LEFT));
+    assert_eq!(events[1].buttons, Buttons::LEFT | Buttons::RIGHT);
+    assert!(events[1].pressed(Buttons::RIGHT));
+    assert!(!events[1].pressed(Buttons::LEFT));
+    assert!(events[2].released(Buttons::LEFT));
+    assert_eq!(events[2].changed, Buttons::LEFT);
+}
+
+#[test]
+fn wheel() {
+    let events = decode(true, &[0x08, 0, 0, 0x01, 0x08, 0, 0, 0x0F]);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].wheel, 1);
+    assert_eq!(events[1].wheel, -1);
+}
+
+#[test]
+fn resynchronizes_on_bytes_without_bit_3() {
+    // a stray movement byte cannot start a packet and is skipped
+    let events = decode(false, &[0x05, 0x08, 0x01, 0x02]);
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].dx, events[0].dy), (1, -2));
+}
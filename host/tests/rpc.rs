@@ -0,0 +1,384 @@
+use rustberry_host::rpc::frame::{
+    self, cobs_decode, cobs_encode, crc32, FrameError, FrameReader, Received, CRC_SIZE,
+    HEADER_SIZE, MAX_ENCODED, MAX_FRAME,
+};
+use rustberry_host::rpc::message::{ErrorCode, Request, Response, MAX_DATA};
+use rustberry_host::rpc::server::Server;
+use rustberry_host::rpc_client::{Client, ClientError};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    cobs_encode(data, &mut |byte| encoded.push(byte));
+    encoded
+}
+
+// The bytes that send a frame of `kind` with `body`
+fn framed(kind: u8, seq: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0; HEADER_SIZE + body.len() + CRC_SIZE];
+    frame[HEADER_SIZE..HEADER_SIZE + body.len()].copy_from_slice(body);
+    let len = frame::seal(&mut frame, kind, seq, body.len());
+    let mut bytes = Vec::new();
+    frame::send(&frame[..len], &mut |byte| bytes.push(byte));
+    bytes
+}
+
+fn request(seq: u8, request: Request) -> Vec<u8> {
+    let mut body = [0; MAX_FRAME];
+    let len = request.encode(&mut body).unwrap();
+    framed(frame::REQUEST, seq, &body[..len])
+}
+
+#[test]
+fn crc_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(&[]), 0);
+}
+
+#[test]
+fn cobs_round_trip() {
+    let long: Vec<u8> = (0..600).map(|i| (i % 255 + 1) as u8).collect();
+    let mut with_zeros = long.clone();
+    with_zeros[253] = 0;
+    with_zeros[254] = 0;
+    let cases: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![1, 0, 2, 0],
+        long[..254].to_vec(),
+        long[..255].to_vec(),
+        long,
+        with_zeros,
+    ];
+    for data in cases {
+        let encoded = encode(&data);
+        assert!(!encoded.contains(&0), "{:?}", data);
+        assert!(encoded.len() <= data.len() + data.len() / 254 + 1);
+        let mut decoded = vec![0; data.len()];
+        assert_eq!(cobs_decode(&encoded, &mut decoded), Some(data.len()));
+        assert_eq!(decoded, data);
+    }
+    assert_eq!(encode(&[0x11, 0, 0x22]), [2, 0x11, 2, 0x22]);
+    // the length byte runs past the end
+    assert_eq!(cobs_decode(&[5, 1, 2], &mut [0; 8]), None);
+}
+
+#[test]
+fn frames_between_text() {
+    let mut line = b"booting\r\n".to_vec();
+    line.extend(framed(frame::RESPONSE, 7, b"\x84"));
+    line.extend(b"[info] hello\r\n");
+    line.extend(framed(frame::RESPONSE, 8, &[0, 1, 0]));
+
+    let mut reader = FrameReader::new();
+    let mut received = Vec::new();
+    for byte in line {
+        match reader.feed(byte) {
+            Some(Received::Frame(frame)) => {
+                received.push(Ok((frame.kind, frame.seq, frame.body.to_vec())))
+            }
+            Some(Received::Rejected { raw, error }) => received.push(Err((raw.to_vec(), error))),
+            None => {}
+        }
+    }
+    assert_eq!(
+        received,
+        [
+            Err((b"booting\r\n".to_vec(), FrameError::Encoding)),
+            Ok((frame::RESPONSE, 7, vec![0x84])),
+            Err((b"[info] hello\r\n".to_vec(), FrameError::Encoding)),
+            Ok((frame::RESPONSE, 8, vec![0, 1, 0])),
+        ]
+    );
+}
+
+#[test]
+fn garbled_frames_are_rejected() {
+    let errors = |bytes: &[u8]| {
+        let mut reader = FrameReader::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| match reader.feed(byte) {
+                Some(Received::Rejected { error, .. }) => Some(error),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut flipped = framed(frame::REQUEST, 1, b"\x01abcd");
+    flipped[4] ^= 0x08;
+    assert_eq!(errors(&flipped), [FrameError::Checksum]);
+    assert_eq!(errors(&[0, 3, 1, 2, 0]), [FrameError::TooShort]);
+
+    let mut long = vec![0];
+    long.resize(MAX_ENCODED + 10, 1);
+    long.push(0);
+    assert_eq!(errors(&long), [FrameError::TooLong]);
+    // and the next frame is fine again
+    let mut reader = FrameReader::new();
+    long.extend(framed(frame::NAK, 0, &[]));
+    let last = long.iter().filter_map(|&byte| match reader.feed(byte) {
+        Some(Received::Frame(frame)) => Some(frame.kind),
+        _ => None,
+    });
+    assert_eq!(last.collect::<Vec<_>>(), [frame::NAK]);
+}
+
+#[test]
+fn messages_round_trip() {
+    let data = [0xde, 0xad, 0xbe, 0xef, 0];
+    let requests = [
+        Request::Ping(0x1234_5678),
+        Request::Ticks,
+        Request::ReadMemory {
+            address: 0x2020_0034,
+            len: 8,
+        },
+        Request::WriteMemory {
+            address: 0x8000,
+            data: &data,
+        },
+        Request::ReadGpio { pin: 47 },
+        Request::WriteGpio {
+            pin: 20,
+            level: true,
+        },
+        Request::Heap,
+        Request::Close,
+    ];
+    let mut body = [0; MAX_FRAME];
+    for request in requests.iter() {
+        let len = request.encode(&mut body).unwrap();
+        assert_eq!(Request::decode(&body[..len]), Ok(*request));
+    }
+
+    let responses = [
+        Response::Pong(7),
+        Response::Ticks(123_456),
+        Response::Memory(&data),
+        Response::Done,
+        Response::Gpio {
+            function: 1,
+            level: false,
+        },
+        Response::Heap { used: 10, free: 20 },
+        Response::Error(ErrorCode::BadPin),
+    ];
+    for response in responses.iter() {
+        let len = response.encode(&mut body).unwrap();
+        assert_eq!(Response::decode(&body[..len]), Ok(*response));
+    }
+}
+
+#[test]
+fn malformed_messages() {
+    assert_eq!(Request::decode(&[]), Err(ErrorCode::Malformed));
+    assert_eq!(Request::decode(&[0x42]), Err(ErrorCode::Unknown));
+    // cut short, and with a byte too many
+    assert_eq!(Request::decode(&[0x01, 1, 2]), Err(ErrorCode::Malformed));
+    assert_eq!(Request::decode(&[0x02, 0]), Err(ErrorCode::Malformed));
+    assert_eq!(Request::decode(&[0x06, 3, 2]), Err(ErrorCode::Malformed));
+    let too_long = (MAX_DATA as u16 + 1).to_le_bytes();
+    assert_eq!(
+        Request::decode(&[0x03, 0, 0x80, 0, 0, too_long[0], too_long[1]]),
+        Err(ErrorCode::TooLong)
+    );
+    assert_eq!(Request::Ping(0).encode(&mut [0; 4]), None);
+}
+
+// A Pi with a byte array for memory, which counts the requests it carries out
+struct Pi {
+    server: Server,
+    memory: Vec<u8>,
+    dispatched: usize,
+    closed: bool,
+}
+
+impl Pi {
+    fn new() -> Self {
+        Pi {
+            server: Server::new(),
+            memory: vec![0; 4096],
+            dispatched: 0,
+            closed: false,
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let Pi {
+            server,
+            memory,
+            dispatched,
+            closed,
+        } = self;
+        let mut sent = Vec::new();
+        for &byte in bytes {
+            server.feed(
+                byte,
+                &mut |request, scratch| {
+                    *dispatched += 1;
+                    match request {
+                        Request::Ping(value) => Response::Pong(value),
+                        Request::ReadMemory { address, len } => {
+                            let (address, len) = (address as usize, len as usize);
+                            scratch[..len].copy_from_slice(&memory[address..address + len]);
+                            Response::Memory(&scratch[..len])
+                        }
+                        Request::WriteMemory { address, data } => {
+                            let address = address as usize;
+                            memory[address..address + data.len()].copy_from_slice(data);
+                            Response::Done
+                        }
+                        Request::Close => {
+                            *closed = true;
+                            Response::Done
+                        }
+                        _ => Response::Error(ErrorCode::Unknown),
+                    }
+                },
+                &mut |byte| sent.push(byte),
+            );
+        }
+        sent
+    }
+}
+
+#[test]
+fn server_answers_again_without_carrying_out_again() {
+    let mut pi = Pi::new();
+    let write = request(
+        5,
+        Request::WriteMemory {
+            address: 16,
+            data: b"data",
+        },
+    );
+    let first = pi.feed(&write);
+    assert_eq!(first, framed(frame::RESPONSE, 5, &[0x84]));
+    assert_eq!(pi.feed(&write), first);
+    assert_eq!(pi.dispatched, 1);
+    assert_eq!(&pi.memory[16..20], b"data");
+
+    // garbled requests are answered with a NAK
+    assert_eq!(pi.feed(b"\r\n\0"), framed(frame::NAK, 0, &[]));
+    assert_eq!(
+        pi.feed(&framed(frame::REQUEST, 6, &[0x42])),
+        framed(frame::RESPONSE, 6, &[0xff, ErrorCode::Unknown as u8])
+    );
+}
+
+// The line between a Client and a Pi. Frames the client sends are handed to the Pi, and what the
+// Pi answers is read back, except that `drop` decides which of them get lost on the way
+struct Line {
+    pi: Pi,
+    written: Vec<u8>,
+    incoming: VecDeque<u8>,
+    frames: usize,
+    drop: fn(usize) -> Loss,
+}
+
+#[derive(PartialEq)]
+enum Loss {
+    None,
+    Request,
+    Corrupt,
+    Response,
+}
+
+impl Write for Line {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut bytes = std::mem::take(&mut self.written);
+        let loss = (self.drop)(self.frames);
+        self.frames += 1;
+        if loss == Loss::Request {
+            return Ok(());
+        }
+        if loss == Loss::Corrupt {
+            bytes[3] ^= 0x10;
+        }
+        let answer = self.pi.feed(&bytes);
+        if loss != Loss::Response {
+            self.incoming.extend(b"some console text\r\n");
+            self.incoming.extend(answer);
+        }
+        Ok(())
+    }
+}
+
+impl Read for Line {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < buffer.len() {
+            match self.incoming.pop_front() {
+                Some(byte) => buffer[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+fn client(drop: fn(usize) -> Loss) -> Client<Line> {
+    let mut client = Client::new(Line {
+        pi: Pi::new(),
+        written: Vec::new(),
+        incoming: VecDeque::new(),
+        frames: 0,
+        drop,
+    });
+    client.set_timeout(Duration::from_millis(10), 3);
+    client
+}
+
+#[test]
+fn client_calls() {
+    let mut client = client(|_| Loss::None);
+    assert_eq!(client.ping(42).unwrap(), 42);
+    let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    client.write_memory(100, &data).unwrap();
+    assert_eq!(client.read_memory(100, data.len()).unwrap(), data);
+    assert!(matches!(
+        client.heap(),
+        Err(ClientError::Remote(ErrorCode::Unknown))
+    ));
+    client.close().unwrap();
+    assert!(String::from_utf8(client.take_console())
+        .unwrap()
+        .starts_with("some console text\r\n"));
+
+    let line = client.into_port();
+    assert!(line.pi.closed);
+    // one ping, four writes, four reads, the heap and close
+    assert_eq!(line.pi.dispatched, 11);
+}
+
+#[test]
+fn client_sends_again_over_a_lossy_line() {
+    let mut client = client(|frame| match frame {
+        0 => Loss::Request,
+        1 => Loss::Response,
+        2 => Loss::Corrupt,
+        _ => Loss::None,
+    });
+    assert_eq!(client.ping(1).unwrap(), 1);
+    assert_eq!(client.ping(2).unwrap(), 2);
+    let line = client.into_port();
+    // the ping whose response got lost was carried out only once
+    assert_eq!(line.pi.dispatched, 2);
+    assert_eq!(line.frames, 5);
+}
+
+#[test]
+fn client_gives_up() {
+    let mut client = client(|_| Loss::Request);
+    assert!(matches!(client.ping(1), Err(ClientError::Timeout)));
+    assert_eq!(client.into_port().frames, 4);
+}
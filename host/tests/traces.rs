@@ -0,0 +1,102 @@
+//! The PS/2 decoders fed with bit-stream traces of a keyboard.
+//!
+//! Traces are CSV files with one row per change of either line: time in microseconds, then the
+//! clock and data levels. Lines starting with '#' describe the trace.
+
+use rustberry_host::layout::US;
+use rustberry_host::ps2_decoder::{FrameDecoder, FrameError};
+use rustberry_host::scancode::{Key, KeyAction, SequenceDecoder};
+
+fn decode_trace(trace: &str) -> Vec<Result<u8, FrameError>> {
+    let mut decoder = FrameDecoder::new();
+    let mut frames = Vec::new();
+    for line in trace.lines().filter(|line| !line.starts_with('#')).skip(1) {
+        let fields: Vec<u32> = line
+            .split(',')
+            .map(|field| field.parse().unwrap())
+            .collect();
+        if let Some(frame) = decoder.sample(fields[1] != 0, fields[2] != 0, fields[0]) {
+            frames.push(frame);
+        }
+    }
+    frames
+}
+
+fn decode_actions(trace: &str) -> Vec<KeyAction> {
+    let mut decoder = SequenceDecoder::new();
+    decode_trace(trace)
+        .into_iter()
+        .filter_map(|frame| decoder.feed(frame.unwrap()))
+        .collect()
+}
+
+fn action(keycode: u8, extended: bool, pressed: bool) -> KeyAction {
+    KeyAction {
+        keycode,
+        extended,
+        pressed,
+    }
+}
+
+#[test]
+fn make_break() {
+    let trace = include_str!("traces/make_break_a.csv");
+    assert_eq!(decode_trace(trace), vec![Ok(0x1C), Ok(0xF0), Ok(0x1C)]);
+
+    let actions = decode_actions(trace);
+    assert_eq!(
+        actions,
+        vec![action(0x1C, false, true), action(0x1C, false, false)]
+    );
+    assert_eq!(actions[0].key(&US), Some(Key::Char('a')));
+}
+
+#[test]
+fn extended_key() {
+    let actions = decode_actions(include_str!("traces/extended_right_arrow.csv"));
+    assert_eq!(
+        actions,
+        vec![action(0x74, true, true), action(0x74, true, false)]
+    );
+    assert_eq!(actions[0].key(&US), Some(Key::Right));
+}
+
+#[test]
+fn pause_sequence() {
+    let actions = decode_actions(include_str!("traces/pause.csv"));
+    assert_eq!(
+        actions,
+        vec![action(0x77, true, true), action(0x77, true, false)]
+    );
+    assert_eq!(actions[0].key(&US), Some(Key::Pause));
+}
+
+#[test]
+fn parity_error() {
+    let frames = decode_trace(include_str!("traces/parity_error.csv"));
+    assert_eq!(frames, vec![Err(FrameError::Parity), Ok(0x32)]);
+}
+
+#[test]
+fn framing_error() {
+    let frames = decode_trace(include_str!("traces/framing_error.csv"));
+    assert_eq!(frames, vec![Err(FrameError::Framing), Ok(0x32)]);
+}
+
+#[test]
+fn timeout() {
+    let frames = decode_trace(include_str!("traces/timeout.csv"));
+    assert_eq!(frames, vec![Err(FrameError::Timeout), Ok(0x32)]);
+}
+
+#[test]
+fn timeout_while_idle() {
+    let mut decoder = FrameDecoder::new();
+    assert_eq!(decoder.falling_edge(false, 0), None);
+    assert_eq!(decoder.sample(true, true, 1000), None);
+    assert_eq!(
+        decoder.sample(true, true, 3000),
+        Some(Err(FrameError::Timeout))
+    );
+    assert_eq!(decoder.sample(true, true, 9000), None);
+}
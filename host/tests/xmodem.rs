@@ -0,0 +1,237 @@
+use rustberry_host::xmodem::{
+    crc16, Error, Receiver, Step, ACK, BLOCK_1K_SIZE, BLOCK_SIZE, CAN, CRC_REQUEST, EOT,
+    MAX_ERRORS, NAK, SOH, STX,
+};
+
+// What the receiver did with the bytes fed to it
+#[derive(Debug, Default)]
+struct Transcript {
+    replies: Vec<u8>,
+    data: Vec<u8>,
+    complete: bool,
+    error: Option<Error>,
+}
+
+// Feeds `bytes` to `receiver`, replying ACK where a block or EOT asks for it like the firmware does
+fn feed(receiver: &mut Receiver, bytes: &[u8], transcript: &mut Transcript) {
+    for &byte in bytes {
+        handle(receiver.feed(byte), transcript);
+    }
+}
+
+fn handle(step: Step, transcript: &mut Transcript) {
+    match step {
+        Step::Pending => {}
+        Step::Reply(reply) => transcript.replies.push(reply),
+        Step::Block(data) => {
+            transcript.data.extend_from_slice(data);
+            transcript.replies.push(ACK);
+        }
+        Step::Complete => {
+            transcript.replies.push(ACK);
+            transcript.complete = true;
+        }
+        Step::Failed(err) => transcript.error = Some(err),
+    }
+}
+
+// Builds block `number` the way a sender does, padding `data` with SUB
+fn block(number: u8, data: &[u8], size: usize) -> Vec<u8> {
+    let mut payload = data.to_vec();
+    payload.resize(size, 0x1A);
+    let mut block = vec![if size == BLOCK_SIZE { SOH } else { STX }, number, !number];
+    block.extend_from_slice(&payload);
+    block.extend_from_slice(&crc16(&payload).to_be_bytes());
+    block
+}
+
+// A sender that answers each reply in turn: starts on 'C', sends the next block on ACK, and
+// sends the same one again on NAK
+fn simulate(receiver: &mut Receiver, file: &[u8], size: usize) -> Transcript {
+    let mut transcript = Transcript::default();
+    let blocks: Vec<&[u8]> = file.chunks(size).collect();
+    handle(receiver.timeout(), &mut transcript);
+
+    let mut index = 0;
+    let mut answered = 0;
+    while !transcript.complete && transcript.error.is_none() {
+        match transcript.replies[answered] {
+            CRC_REQUEST => assert_eq!(index, 0),
+            ACK => index += 1,
+            NAK => {}
+            reply => panic!("unexpected reply {:#x}", reply),
+        }
+        answered += 1;
+        match blocks.get(index) {
+            Some(data) => feed(
+                receiver,
+                &block((index + 1) as u8, data, size),
+                &mut transcript,
+            ),
+            None => feed(receiver, &[EOT], &mut transcript),
+        }
+    }
+    transcript
+}
+
+fn file(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn crc_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+    assert_eq!(crc16(&[]), 0);
+}
+
+#[test]
+fn transfer_128() {
+    // more than 256 blocks, so that the block number wraps around
+    let file = file(300 * BLOCK_SIZE + 5);
+    let mut receiver = Receiver::new();
+    let transcript = simulate(&mut receiver, &file, BLOCK_SIZE);
+    assert!(transcript.complete);
+    assert_eq!(&transcript.data[..file.len()], &file[..]);
+    assert_eq!(transcript.data.len(), 301 * BLOCK_SIZE);
+    assert!(transcript.data[file.len()..]
+        .iter()
+        .all(|&byte| byte == 0x1A));
+    assert_eq!(transcript.replies[0], CRC_REQUEST);
+    assert!(transcript.replies[1..].iter().all(|&reply| reply == ACK));
+}
+
+#[test]
+fn transfer_1k() {
+    let file = file(3 * BLOCK_1K_SIZE);
+    let mut receiver = Receiver::new();
+    let transcript = simulate(&mut receiver, &file, BLOCK_1K_SIZE);
+    assert!(transcript.complete);
+    assert_eq!(transcript.data, file);
+}
+
+#[test]
+fn noise_before_the_first_block_is_ignored() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+    feed(&mut receiver, b"hello\r\n", &mut transcript);
+    feed(&mut receiver, &[EOT], &mut transcript);
+    assert!(transcript.replies.is_empty());
+    assert!(!receiver.started());
+    assert_eq!(receiver.timeout(), Step::Reply(CRC_REQUEST));
+
+    feed(
+        &mut receiver,
+        &block(1, b"kernel", BLOCK_SIZE),
+        &mut transcript,
+    );
+    assert_eq!(&transcript.data[..6], b"kernel");
+    assert!(receiver.started());
+}
+
+#[test]
+fn bad_blocks_are_requested_again() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+
+    let mut corrupted = block(1, b"first", BLOCK_SIZE);
+    corrupted[10] ^= 0x40;
+    feed(&mut receiver, &corrupted, &mut transcript);
+    let mut bad_complement = block(1, b"first", BLOCK_SIZE);
+    bad_complement[2] = 0;
+    feed(&mut receiver, &bad_complement, &mut transcript);
+    assert_eq!(transcript.replies, [NAK, NAK]);
+    assert!(transcript.data.is_empty());
+
+    feed(
+        &mut receiver,
+        &block(1, b"first", BLOCK_SIZE),
+        &mut transcript,
+    );
+    // our ACK got lost, so the sender sends the block again
+    feed(
+        &mut receiver,
+        &block(1, b"first", BLOCK_SIZE),
+        &mut transcript,
+    );
+    feed(
+        &mut receiver,
+        &block(2, b"second", BLOCK_SIZE),
+        &mut transcript,
+    );
+    feed(&mut receiver, &[EOT], &mut transcript);
+    assert_eq!(transcript.replies, [NAK, NAK, ACK, ACK, ACK, ACK]);
+    assert_eq!(transcript.data.len(), 2 * BLOCK_SIZE);
+    assert_eq!(&transcript.data[BLOCK_SIZE..BLOCK_SIZE + 6], b"second");
+    assert!(transcript.complete);
+}
+
+#[test]
+fn block_cut_short() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+    let first = block(1, b"first", BLOCK_SIZE);
+    feed(&mut receiver, &first[..50], &mut transcript);
+    handle(receiver.timeout(), &mut transcript);
+    feed(&mut receiver, &first, &mut transcript);
+    assert_eq!(transcript.replies, [NAK, ACK]);
+    assert_eq!(receiver.timeout(), Step::Reply(NAK));
+}
+
+#[test]
+fn too_many_errors() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+    feed(
+        &mut receiver,
+        &block(1, b"first", BLOCK_SIZE),
+        &mut transcript,
+    );
+    for _ in 0..MAX_ERRORS - 1 {
+        assert_eq!(receiver.timeout(), Step::Reply(NAK));
+    }
+    assert_eq!(receiver.timeout(), Step::Failed(Error::TooManyErrors));
+}
+
+#[test]
+fn out_of_sequence() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+    feed(
+        &mut receiver,
+        &block(1, b"first", BLOCK_SIZE),
+        &mut transcript,
+    );
+    feed(
+        &mut receiver,
+        &block(3, b"third", BLOCK_SIZE),
+        &mut transcript,
+    );
+    assert_eq!(
+        transcript.error,
+        Some(Error::Sequence {
+            expected: 2,
+            received: 3
+        })
+    );
+}
+
+#[test]
+fn sender_cancels() {
+    let mut receiver = Receiver::new();
+    let mut transcript = Transcript::default();
+    feed(
+        &mut receiver,
+        &block(1, b"first", BLOCK_SIZE),
+        &mut transcript,
+    );
+    // a lone CAN is taken for noise
+    feed(&mut receiver, &[CAN], &mut transcript);
+    feed(
+        &mut receiver,
+        &block(2, b"second", BLOCK_SIZE),
+        &mut transcript,
+    );
+    assert_eq!(transcript.error, None);
+    feed(&mut receiver, &[CAN, CAN], &mut transcript);
+    assert_eq!(transcript.error, Some(Error::Cancelled));
+}
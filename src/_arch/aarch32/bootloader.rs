@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Architectural chain loading code.
+//!
+//! # Orientation
+//!
+//! Since arch modules are imported into generic modules using the path attribute, the path of this
+//! file is:
+//!
+//! crate::bootloader::arch_bootloader
+
+use core::ptr;
+
+// Assembly counterpart to this file. Includes the trampoline that copies the image in place.
+global_asm!(include_str!("bootloader.S"));
+
+//--------------------------------------------------------------------------------------------------
+// Private Definitions
+//--------------------------------------------------------------------------------------------------
+
+// Symbols from bootloader.S.
+extern "Rust" {
+    static chain_load_trampoline: u8;
+    static chain_load_trampoline_end: u8;
+}
+
+// Where the trampoline runs from: right above the IRQ stack (see boot.S), where no kernel
+// linked at 0x8000 reaches.
+const TRAMPOLINE_ADDRESS: usize = 0x8014000;
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Copy `image` to `address` and jump to it, from a copy of the trampoline out of the way.
+///
+/// # Safety
+///
+/// - IRQs must be masked and every interrupt source disabled.
+/// - `image` must start above `address`.
+pub unsafe fn jump(address: usize, image: &[u8]) -> ! {
+    let start = &chain_load_trampoline as *const u8;
+    let len = &chain_load_trampoline_end as *const u8 as usize - start as usize;
+    ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDRESS as *mut u8, len);
+
+    // The copy is run as code, so it must be in memory rather than in the data cache
+    asm!(
+        "mcr p15, 0, {zero}, c7, c10, 0", // clean the data cache
+        "mcr p15, 0, {zero}, c7, c10, 4", // data synchronization barrier
+        "mcr p15, 0, {zero}, c7, c5, 0",  // invalidate the instruction cache
+        "mcr p15, 0, {zero}, c7, c5, 4",  // flush the prefetch buffer
+        zero = in(reg) 0,
+        options(nostack, preserves_flags)
+    );
+
+    let trampoline: extern "C" fn(usize, *const u8, usize) -> ! =
+        core::mem::transmute(TRAMPOLINE_ADDRESS);
+    trampoline(address, image.as_ptr(), image.len())
+}
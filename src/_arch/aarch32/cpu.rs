@@ -28,3 +28,59 @@ pub fn wait_forever() -> ! {
         }
     }
 }
+
+/// Make code written as data visible to instruction fetches, e.g. after planting a breakpoint.
+///
+/// Cleans the data cache to memory, then invalidates the instruction cache, the branch target
+/// cache and the prefetch buffer.
+#[inline(always)]
+pub fn sync_instruction_cache() {
+    unsafe {
+        asm!(
+            "mcr p15, 0, {zero}, c7, c10, 0",
+            "mcr p15, 0, {zero}, c7, c10, 4",
+            "mcr p15, 0, {zero}, c7, c5, 0",
+            "mcr p15, 0, {zero}, c7, c5, 6",
+            "mcr p15, 0, {zero}, c7, c5, 4",
+            zero = in(reg) 0,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Stop at a BKPT, for a debugger to take over. Continues right after it.
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe {
+        asm!("bkpt #0", options(nomem, nostack));
+    }
+}
+
+/// Start the cycle counter of the ARM1176 performance monitor from zero, counting every core
+/// clock cycle.
+#[inline(always)]
+pub fn enable_cycle_counter() {
+    unsafe {
+        // PMNC: E enables the counters, C resets the cycle counter; D clear counts every cycle
+        asm!(
+            "mcr p15, 0, {pmnc}, c15, c12, 0",
+            pmnc = in(reg) 0b101,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Core clock cycles since `enable_cycle_counter()`, wrapping around. At the default 700MHz
+/// that takes six seconds.
+#[inline(always)]
+pub fn cycle_count() -> u32 {
+    let count;
+    unsafe {
+        asm!(
+            "mrc p15, 0, {count}, c15, c12, 1",
+            count = out(reg) count,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    count
+}
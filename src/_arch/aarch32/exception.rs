@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Architectural synchronous and asynchronous exception handling.
+//!
+//! # Orientation
+//!
+//! Since arch modules are imported into generic modules using the path attribute, the path of this
+//! file is:
+//!
+//! crate::exception::arch_exception
+
+use crate::{interrupts, serial};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Assembly counterpart to this file. Includes the vector table and the entry stubs.
+global_asm!(include_str!("exception.S"));
+
+//--------------------------------------------------------------------------------------------------
+// Private Definitions
+//--------------------------------------------------------------------------------------------------
+
+const CPSR_IRQ_MASKED: u32 = 1 << 7;
+
+// Symbols from exception.S.
+extern "Rust" {
+    static __exception_vector_start: u32;
+    static __break_trampoline: u32;
+}
+
+// Fault status of the debug events: BKPT, and breakpoints and watchpoints in monitor mode
+const FSR_DEBUG_EVENT: u32 = 0b00010;
+
+static mut DEBUG_HANDLER: Option<DebugHandler> = None;
+
+// Set by request_break(), and where the interrupted code continues once the breakpoint is handled
+static BREAK_REQUESTED: AtomicBool = AtomicBool::new(false);
+static mut BREAK_RESUME: u32 = 0;
+
+/// The registers __irq_entry in exception.S saves, lowest address first.
+#[repr(C)]
+struct IrqFrame {
+    r: [u32; 4],
+    r12: u32,
+    lr: u32,
+}
+
+const REGISTER_NAMES: [&str; 16] = [
+    "r0 ", "r1 ", "r2 ", "r3 ", "r4 ", "r5 ", "r6 ", "r7 ", "r8 ", "r9 ", "r10", "r11", "r12",
+    "sp ", "lr ", "pc ",
+];
+
+//--------------------------------------------------------------------------------------------------
+// Public Definitions
+//--------------------------------------------------------------------------------------------------
+
+/// The interrupted context, as saved by the entry stubs in `exception.S`.
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// General purpose registers r0-r12.
+    pub r: [u32; 13],
+    /// Banked stack pointer of the interrupted mode.
+    pub sp: u32,
+    /// Banked link register of the interrupted mode.
+    pub lr: u32,
+    /// Address of the instruction that caused the exception.
+    pub pc: u32,
+    /// CPSR of the interrupted mode, i.e. the SPSR of the exception mode.
+    pub cpsr: u32,
+    _reserved: u32,
+}
+
+/// Why a debugger was handed an exception, see `set_debug_handler()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// A BKPT instruction.
+    Breakpoint,
+    /// Somebody called `request_break()`, e.g. on a break character from the debugger.
+    Break,
+    UndefinedInstruction,
+    PrefetchAbort,
+    DataAbort,
+}
+
+/// Called with the interrupted context, which resumes from the frame when the handler returns.
+pub type DebugHandler = fn(DebugEvent, &mut ExceptionFrame);
+
+//--------------------------------------------------------------------------------------------------
+// Private Code
+//--------------------------------------------------------------------------------------------------
+
+impl ExceptionFrame {
+    /// Register `index` of r0-r15.
+    pub fn register(&self, index: usize) -> u32 {
+        match index {
+            0..=12 => self.r[index],
+            13 => self.sp,
+            14 => self.lr,
+            _ => self.pc,
+        }
+    }
+
+    /// Set register `index` of r0-r15 to `value`.
+    pub fn set_register(&mut self, index: usize, value: u32) {
+        match index {
+            0..=12 => self.r[index] = value,
+            13 => self.sp = value,
+            14 => self.lr = value,
+            _ => self.pc = value,
+        }
+    }
+}
+
+fn mode_name(cpsr: u32) -> &'static str {
+    match cpsr & 0x1f {
+        0x10 => "usr",
+        0x11 => "fiq",
+        0x12 => "irq",
+        0x13 => "svc",
+        0x17 => "abt",
+        0x1b => "und",
+        0x1f => "sys",
+        _ => "???",
+    }
+}
+
+/// Decode the fault status bits shared by the DFSR and the IFSR (ARMv6 VMSA encoding).
+fn fault_status_name(fsr: u32) -> &'static str {
+    match ((fsr >> 6) & 0x10) | (fsr & 0xf) {
+        0b00001 | 0b00011 => "alignment fault",
+        0b00100 => "instruction cache maintenance fault",
+        0b01100 => "external abort on first level translation",
+        0b01110 => "external abort on second level translation",
+        0b00101 => "translation fault (section)",
+        0b00111 => "translation fault (page)",
+        0b01001 => "domain fault (section)",
+        0b01011 => "domain fault (page)",
+        0b01101 => "permission fault (section)",
+        0b01111 => "permission fault (page)",
+        0b01000 => "precise external abort",
+        0b10110 => "imprecise external abort",
+        0b00010 => "debug event",
+        _ => "unknown fault",
+    }
+}
+
+fn read_dfsr() -> u32 {
+    let dfsr;
+    unsafe {
+        asm!("mrc p15, 0, {}, c5, c0, 0", out(reg) dfsr, options(nomem, nostack, preserves_flags));
+    }
+    dfsr
+}
+
+fn read_ifsr() -> u32 {
+    let ifsr;
+    unsafe {
+        asm!("mrc p15, 0, {}, c5, c0, 1", out(reg) ifsr, options(nomem, nostack, preserves_flags));
+    }
+    ifsr
+}
+
+fn read_far() -> u32 {
+    let far;
+    unsafe {
+        asm!("mrc p15, 0, {}, c6, c0, 0", out(reg) far, options(nomem, nostack, preserves_flags));
+    }
+    far
+}
+
+fn read_ifar() -> u32 {
+    let ifar;
+    unsafe {
+        asm!("mrc p15, 0, {}, c6, c0, 2", out(reg) ifar, options(nomem, nostack, preserves_flags));
+    }
+    ifar
+}
+
+/// Print the saved registers on the console.
+///
+/// This deliberately avoids `core::fmt` so that as little as possible has to work while the
+/// system is in an unknown state.
+unsafe fn dump(what: &str, frame: &ExceptionFrame) {
+    let console = serial::console();
+    console.put_string("\r\n*** ");
+    console.put_string(what);
+    console.put_string(" ***\r\n");
+
+    console.put_string("cpsr ");
+    console.put_hex(frame.cpsr);
+    console.put_string(" (");
+    console.put_string(mode_name(frame.cpsr));
+    console.put_string(")\r\n");
+
+    for (i, name) in REGISTER_NAMES.iter().enumerate() {
+        console.put_string(name);
+        console.put_string("  ");
+        console.put_hex(frame.register(i));
+        console.put_string(if i % 4 == 3 { "\r\n" } else { "    " });
+    }
+}
+
+unsafe fn dump_fault_status(name: &str, fsr: u32, address_name: &str, address: u32) {
+    let console = serial::console();
+    console.put_string(name);
+    console.put_string(" ");
+    console.put_hex(fsr);
+    console.put_string(" (");
+    console.put_string(fault_status_name(fsr));
+    console.put_string(")\r\n");
+    console.put_string(address_name);
+    console.put_string("  ");
+    console.put_hex(address);
+    console.put_string("\r\n");
+}
+
+//--------------------------------------------------------------------------------------------------
+// Exception handlers, called from exception.S
+//--------------------------------------------------------------------------------------------------
+
+// Hands the exception to the debugger if there is one, returning whether it did
+unsafe fn debug(event: DebugEvent, frame: &mut ExceptionFrame) -> bool {
+    match DEBUG_HANDLER {
+        Some(handler) => {
+            handler(event, frame);
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn undefined_instruction_handler(frame: &mut ExceptionFrame) {
+    if debug(DebugEvent::UndefinedInstruction, frame) {
+        return;
+    }
+    dump("Undefined instruction", frame);
+    panic!("undefined instruction at {:#010x}", frame.pc)
+}
+
+#[no_mangle]
+unsafe extern "C" fn software_interrupt_handler(frame: &ExceptionFrame) -> ! {
+    dump("Software interrupt", frame);
+    panic!("unexpected swi at {:#010x}", frame.pc)
+}
+
+#[no_mangle]
+unsafe extern "C" fn prefetch_abort_handler(frame: &mut ExceptionFrame) {
+    let ifsr = read_ifsr();
+    let event = if frame.pc == &__break_trampoline as *const u32 as u32 {
+        frame.pc = BREAK_RESUME;
+        DebugEvent::Break
+    } else if ifsr & 0x40f == FSR_DEBUG_EVENT {
+        DebugEvent::Breakpoint
+    } else {
+        DebugEvent::PrefetchAbort
+    };
+    // a break nobody handles just continues
+    if debug(event, frame) || event == DebugEvent::Break {
+        return;
+    }
+    dump("Prefetch abort", frame);
+    dump_fault_status("ifsr", ifsr, "ifar", read_ifar());
+    panic!("prefetch abort at {:#010x}", frame.pc)
+}
+
+#[no_mangle]
+unsafe extern "C" fn data_abort_handler(frame: &mut ExceptionFrame) {
+    let dfsr = read_dfsr();
+    if debug(DebugEvent::DataAbort, frame) {
+        return;
+    }
+
+    dump("Data abort", frame);
+    dump_fault_status("dfsr", dfsr, "far ", read_far());
+    serial::console().put_string(if dfsr & (1 << 11) != 0 {
+        "on write\r\n"
+    } else {
+        "on read\r\n"
+    });
+    panic!("data abort at {:#010x}", frame.pc)
+}
+
+#[no_mangle]
+unsafe extern "C" fn reserved_handler(frame: &ExceptionFrame) -> ! {
+    dump("Reserved exception", frame);
+    panic!("reserved exception at {:#010x}", frame.pc)
+}
+
+#[no_mangle]
+unsafe extern "C" fn irq_handler(frame: &mut IrqFrame) {
+    interrupts::dispatch();
+    if BREAK_REQUESTED.swap(false, Ordering::Relaxed) {
+        BREAK_RESUME = frame.lr;
+        frame.lr = &__break_trampoline as *const u32 as u32;
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn fiq_handler(frame: &ExceptionFrame) -> ! {
+    dump("Unexpected FIQ", frame);
+    panic!("unexpected fiq at {:#010x}", frame.pc)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Install the exception vector table.
+///
+/// # Safety
+///
+/// - Changes the HW state of the executing core.
+/// - The vector table and the symbol `__exception_vector_start` from exception.S are trusted as-is.
+pub unsafe fn handling_init() {
+    let vectors = &__exception_vector_start as *const u32 as u32;
+    asm!(
+        "mcr p15, 0, {vbar}, c12, c0, 0",
+        "mcr p15, 0, {zero}, c7, c5, 4", // flush the prefetch buffer (ISB on ARMv6)
+        vbar = in(reg) vectors,
+        zero = in(reg) 0,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// Unmask IRQs on the executing core.
+#[inline(always)]
+pub fn local_irq_unmask() {
+    unsafe {
+        asm!("cpsie i", options(nostack, preserves_flags));
+    }
+}
+
+/// Mask IRQs on the executing core.
+#[inline(always)]
+pub fn local_irq_mask() {
+    unsafe {
+        asm!("cpsid i", options(nostack, preserves_flags));
+    }
+}
+
+/// Mask IRQs on the executing core and return the previous state for `local_irq_restore()`.
+#[inline(always)]
+pub fn local_irq_mask_save() -> u32 {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs {}, cpsr", "cpsid i", out(reg) cpsr, options(nostack, preserves_flags));
+    }
+    cpsr
+}
+
+/// Returns whether IRQs are masked on the executing core.
+#[inline(always)]
+pub fn local_irq_is_masked() -> bool {
+    let cpsr: u32;
+    unsafe {
+        asm!("mrs {}, cpsr", out(reg) cpsr, options(nomem, nostack, preserves_flags));
+    }
+    cpsr & CPSR_IRQ_MASKED != 0
+}
+
+/// Restore the IRQ mask state saved by `local_irq_mask_save()`.
+#[inline(always)]
+pub fn local_irq_restore(saved: u32) {
+    if saved & CPSR_IRQ_MASKED == 0 {
+        local_irq_unmask();
+    }
+}
+
+/// Hand the exceptions a debugger can deal with to `handler` rather than dumping the registers
+/// and panicking. None goes back to panicking.
+///
+/// # Safety
+///
+/// - Must not be called while an exception is being handled.
+pub unsafe fn set_debug_handler(handler: Option<DebugHandler>) {
+    DEBUG_HANDLER = handler;
+}
+
+/// Stop the code that runs outside of interrupt handlers as soon as the current IRQ returns, and
+/// hand it to the debug handler as `DebugEvent::Break`. For use by interrupt handlers.
+pub fn request_break() {
+    BREAK_REQUESTED.store(true, Ordering::Relaxed);
+}
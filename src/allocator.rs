@@ -1,5 +1,6 @@
 // Author: Flynn Dreilinger <flynnd@stanford.edu>
 
+use crate::exception;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -78,7 +79,7 @@ impl Allocator {
             hdr = heap_current as *mut Header;
             if (*hdr).status == 0 && nbytes == (*hdr).payload_size {
                 (*hdr).status = 1;
-                return Ok((heap_current as *mut u8).offset(1 as isize));
+                return Ok((heap_current as *mut u8).offset(8 as isize));
             } else {
                 // if there is enough space for a new Header, split the slot into two
                 if (*hdr).status == 0 && nbytes + 8 < (*hdr).payload_size {
@@ -118,34 +119,95 @@ impl Allocator {
 
     pub unsafe fn deallocate(&self, ptr: *mut u8) {
         if !ptr.is_null() {
-            let mut hdr = (ptr as *mut u8).offset(-8) as *mut Header;
+            let hdr = ptr.offset(-8) as *mut Header;
             (*hdr).status = 0;
 
-            // advance
-            let mut heap_current = hdr.offset(8 + (*hdr).payload_size as isize);
-            let mut last_hdr = heap_current as *mut Header;
-            while (*last_hdr).status == 0 && heap_current != *(self.heap_end.get()) as *mut Header {
-                (*hdr).payload_size += 8 + (*last_hdr).payload_size;
-                heap_current = heap_current
-                    .offset(8)
-                    .offset((*last_hdr).payload_size as isize);
-                last_hdr = heap_current as *mut Header
+            // merge with the free blocks that follow
+            let heap_end = *(self.heap_end.get()) as *mut u8;
+            let mut next = ptr.offset((*hdr).payload_size as isize);
+            while next != heap_end && (*(next as *mut Header)).status == 0 {
+                let next_hdr = next as *mut Header;
+                (*hdr).payload_size += 8 + (*next_hdr).payload_size;
+                next = next.offset(8 + (*next_hdr).payload_size as isize);
             }
+
+            // and with a free block before it. Blocks have no footers, so the heap is walked from
+            // the start to find the one before.
+            let mut previous: *mut Header = ptr::null_mut();
+            let mut current = *(self.heap_start.get()) as *mut Header;
+            while current != hdr {
+                previous = current;
+                current = (current as *mut u8).offset(8 + (*current).payload_size as isize)
+                    as *mut Header;
+            }
+            if !previous.is_null() && (*previous).status == 0 {
+                (*previous).payload_size += 8 + (*hdr).payload_size;
+            }
+        }
+    }
+
+    /// Walk the heap and add up its blocks.
+    pub unsafe fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            start: *(self.heap_start.get()),
+            end: *(self.heap_end.get()),
+            ..HeapStats::default()
+        };
+        let mut heap_current = stats.start;
+        while heap_current != stats.end {
+            let hdr = heap_current as *mut Header;
+            let size = (*hdr).payload_size;
+            if (*hdr).status == 0 {
+                stats.free_blocks += 1;
+                stats.free_bytes += size;
+                stats.largest_free = stats.largest_free.max(size);
+            } else {
+                stats.used_blocks += 1;
+                stats.used_bytes += size;
+            }
+            heap_current += 8 + size;
         }
+        stats
     }
 }
 
+/// What the heap holds, from `stats()`. Sizes are without the 8 byte header of each block.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The heap spans these addresses; it grows up towards the stack as needed.
+    pub start: usize,
+    pub end: usize,
+    pub used_blocks: usize,
+    pub used_bytes: usize,
+    pub free_blocks: usize,
+    pub free_bytes: usize,
+    pub largest_free: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let saved = exception::local_irq_mask_save();
+    let stats = unsafe { ALLOCATOR.stats() };
+    exception::local_irq_restore(saved);
+    stats
+}
+
+// Interrupt handlers allocate and free too, e.g. timer callbacks, so the heap is only changed with
+// IRQs masked
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Ok(alloc_start) = self.allocate(layout.size()) {
-            return alloc_start;
-        } else {
-            ptr::null_mut()
-        }
+        let saved = exception::local_irq_mask_save();
+        let result = match self.allocate(layout.size()) {
+            Ok(alloc_start) => alloc_start,
+            Err(()) => ptr::null_mut(),
+        };
+        exception::local_irq_restore(saved);
+        result
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let saved = exception::local_irq_mask_save();
         self.deallocate(ptr);
+        exception::local_irq_restore(saved);
     }
 }
 
@@ -156,6 +218,29 @@ fn test_vector() {
     assert_eq!(xs.pop(), Some(42));
 }
 
+#[test_case]
+fn test_stats() {
+    let before = stats();
+    let boxed: Box<[u8; 64]> = Box::new([0; 64]);
+    let during = stats();
+    assert_eq!(during.used_bytes, before.used_bytes + 64);
+    drop(boxed);
+    assert_eq!(stats().used_bytes, before.used_bytes);
+}
+
+#[test_case]
+fn test_coalesce() {
+    // larger than any free block, so that the three are carved from the end of the heap in order
+    let size = stats().largest_free + 8;
+    let first: Vec<u8> = Vec::with_capacity(size);
+    let second: Vec<u8> = Vec::with_capacity(size);
+    let third: Vec<u8> = Vec::with_capacity(size);
+    drop(first);
+    drop(second); // merges with the free block before it
+    assert!(stats().largest_free >= 2 * size + 8);
+    drop(third);
+}
+
 #[test_case]
 fn test_box() {
     let boxed: Box<u8> = Box::new(5);
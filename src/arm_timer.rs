@@ -0,0 +1,302 @@
+/*
+ * Driver for the BCM2835 ARM timer, an ARM SP804 with a few changes. Unlike
+ * the system timer, whose compares are shared with the GPU, it belongs to
+ * the ARM side alone, which makes it the one to drive a scheduler tick.
+ *
+ * It has two counters, both fed by the core clock:
+ *  - the timer proper, which counts down at the core clock divided by the
+ *    pre-divider and the prescale. Reaching zero it raises the ARM timer
+ *    interrupt and starts again from the reload value;
+ *  - a free-running 32 bit counter with a prescaler of its own.
+ *
+ * Both slow down when the firmware lowers the core clock, e.g. when the SoC
+ * gets too hot, so telling the time is better left to the system timer.
+ *
+ * Based off of the BCM2835 ARM Peripherals data sheet, chapter 14, and the
+ * ARM Dual-Timer Module (SP804) Technical Reference Manual.
+ */
+
+use crate::timer::Duration;
+use crate::{cpu, exception, interrupts, mailbox};
+use alloc::boxed::Box;
+
+const ARM_TIMER_BASE: u32 = 0x2000B400;
+const LOAD: *mut u32 = ARM_TIMER_BASE as *mut u32;
+const VALUE: *mut u32 = (ARM_TIMER_BASE + 0x04) as *mut u32;
+const CONTROL: *mut u32 = (ARM_TIMER_BASE + 0x08) as *mut u32;
+const IRQ_CLEAR: *mut u32 = (ARM_TIMER_BASE + 0x0C) as *mut u32;
+const RELOAD: *mut u32 = (ARM_TIMER_BASE + 0x18) as *mut u32;
+const PRE_DIVIDER: *mut u32 = (ARM_TIMER_BASE + 0x1C) as *mut u32;
+const FREE_RUNNING: *mut u32 = (ARM_TIMER_BASE + 0x20) as *mut u32;
+
+const CONTROL_32_BIT: u32 = 1 << 1;
+const CONTROL_PRESCALE_SHIFT: u32 = 2;
+const CONTROL_PRESCALE_MASK: u32 = 0b11 << CONTROL_PRESCALE_SHIFT;
+const CONTROL_IRQ_ENABLE: u32 = 1 << 5;
+const CONTROL_ENABLE: u32 = 1 << 7;
+const CONTROL_FREE_RUNNING_ENABLE: u32 = 1 << 9;
+const CONTROL_FREE_RUNNING_SHIFT: u32 = 16;
+const CONTROL_FREE_RUNNING_MASK: u32 = 0xFF << CONTROL_FREE_RUNNING_SHIFT;
+
+/// The pre-divider is 10 bits wide.
+pub const MAX_PRE_DIVIDER: u32 = 0x3FF;
+
+// The rate init() sets the timer to count at, if the core clock divides down to it
+const DEFAULT_TICK_RATE: u32 = 1_000_000;
+
+/// Further division of the timer's clock after the pre-divider.
+// init() only divides by 1
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Prescale {
+    Div1 = 0b00,
+    Div16 = 0b01,
+    Div256 = 0b10,
+}
+
+impl Prescale {
+    pub fn divisor(self) -> u32 {
+        match self {
+            Prescale::Div1 => 1,
+            Prescale::Div16 => 16,
+            Prescale::Div256 => 256,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArmTimerError {
+    /// The firmware did not report the core clock through the mailbox.
+    ClockUnavailable,
+    /// The pre-divider is larger than MAX_PRE_DIVIDER.
+    PreDivider(u32),
+    /// The period is shorter than a tick or longer than the timer counts at the current rate.
+    Period(Duration),
+}
+
+static mut CORE_CLOCK: u32 = 0;
+// The rate the timer counts down at, in Hz
+static mut TICK_RATE: u32 = 0;
+static mut HANDLER: Option<Box<dyn FnMut()>> = None;
+// Counts the times the timer was stopped, so that the interrupt handler can tell whether the
+// handler it runs stopped or restarted the timer
+static mut STOPS: u32 = 0;
+static mut INITIALIZED: bool = false;
+
+/// Stop both counters, take the core clock from the firmware, and have the timer count at 1MHz
+/// if the clock divides down to it. Calling it again after the core clock changes picks the
+/// new rate up.
+///
+/// Must be called after `interrupts::init()`.
+pub unsafe fn init() -> Result<(), ArmTimerError> {
+    let core_clock =
+        mailbox::get_clock_rate(mailbox::CLOCK_CORE).ok_or(ArmTimerError::ClockUnavailable)?;
+    stop();
+    stop_free_running();
+    CORE_CLOCK = core_clock;
+    let pre_divider = (core_clock / DEFAULT_TICK_RATE).clamp(1, MAX_PRE_DIVIDER + 1) - 1;
+    set_prescaler(pre_divider, Prescale::Div1)?;
+
+    if !INITIALIZED {
+        interrupts::register_handler(interrupts::INTERRUPTS_BASIC_ARM_TIMER, || {
+            interrupt_handler()
+        });
+        interrupts::enable_source(interrupts::INTERRUPTS_BASIC_ARM_TIMER);
+        INITIALIZED = true;
+    }
+    Ok(())
+}
+
+/// Have the timer count at the core clock divided by `pre_divider + 1` and by `prescale`.
+/// Takes effect right away, also for a period under way. Must be called after `init()`.
+pub unsafe fn set_prescaler(pre_divider: u32, prescale: Prescale) -> Result<(), ArmTimerError> {
+    if pre_divider > MAX_PRE_DIVIDER {
+        return Err(ArmTimerError::PreDivider(pre_divider));
+    }
+    cpu::dev_barrier();
+    PRE_DIVIDER.write_volatile(pre_divider);
+    let control = CONTROL.read_volatile() & !CONTROL_PRESCALE_MASK;
+    CONTROL.write_volatile(control | (prescale as u32) << CONTROL_PRESCALE_SHIFT);
+    cpu::dev_barrier();
+    TICK_RATE = CORE_CLOCK / (pre_divider + 1) / prescale.divisor();
+    Ok(())
+}
+
+/// The rate the timer counts down at, in Hz.
+pub fn tick_rate() -> u32 {
+    unsafe { TICK_RATE }
+}
+
+// Ticks of `tick_rate` in `period`, rounded to the nearest one, or None if that is 0 or more
+// than the timer counts
+fn period_ticks(tick_rate: u32, period: Duration) -> Option<u32> {
+    let ticks = (period.as_nanos() * tick_rate as u128 + 500_000_000) / 1_000_000_000;
+    match ticks {
+        0 => None,
+        ticks if ticks > u32::MAX as u128 => None,
+        ticks => Some(ticks as u32),
+    }
+}
+
+#[test_case]
+fn test_period_ticks() {
+    // 250MHz divided down to 1MHz, and undivided
+    assert_eq!(
+        period_ticks(1_000_000, Duration::from_millis(10)),
+        Some(10_000)
+    );
+    assert_eq!(
+        period_ticks(250_000_000, Duration::from_micros(3)),
+        Some(750)
+    );
+    assert_eq!(period_ticks(1_000_000, Duration::from_nanos(1500)), Some(2));
+    assert_eq!(period_ticks(1_000_000, Duration::from_nanos(400)), None);
+    // 4295 seconds at 1MHz is just over 2^32 ticks
+    assert_eq!(
+        period_ticks(1_000_000, Duration::from_secs(4294)),
+        Some(4_294_000_000)
+    );
+    assert_eq!(period_ticks(1_000_000, Duration::from_secs(4295)), None);
+}
+
+/// Count down from `ticks` over and over, calling `handler` in IRQ mode each time the timer
+/// reaches zero. Replaces the handler of a timer already running, and restarts it.
+pub unsafe fn start_periodic<F>(ticks: u32, handler: F)
+where
+    F: FnMut() + 'static,
+{
+    stop();
+    let saved = exception::local_irq_mask_save();
+    HANDLER = Some(Box::new(handler));
+    exception::local_irq_restore(saved);
+
+    cpu::dev_barrier();
+    LOAD.write_volatile(ticks.max(1));
+    RELOAD.write_volatile(ticks.max(1));
+    IRQ_CLEAR.write_volatile(1);
+    let control = CONTROL.read_volatile();
+    CONTROL.write_volatile(control | CONTROL_32_BIT | CONTROL_IRQ_ENABLE | CONTROL_ENABLE);
+    cpu::dev_barrier();
+}
+
+/// `start_periodic()` with the ticks of `period` at the current tick rate.
+pub unsafe fn every<F>(period: Duration, handler: F) -> Result<(), ArmTimerError>
+where
+    F: FnMut() + 'static,
+{
+    let ticks = period_ticks(tick_rate(), period).ok_or(ArmTimerError::Period(period))?;
+    start_periodic(ticks, handler);
+    Ok(())
+}
+
+/// Count down from `ticks` once the current period is over, leaving it as it is.
+#[allow(dead_code)]
+pub unsafe fn set_reload(ticks: u32) {
+    cpu::dev_barrier();
+    RELOAD.write_volatile(ticks.max(1));
+    cpu::dev_barrier();
+}
+
+/// Stop the timer and drop its handler, which may call this. The free-running counter keeps
+/// going.
+pub unsafe fn stop() {
+    cpu::dev_barrier();
+    let control = CONTROL.read_volatile();
+    CONTROL.write_volatile(control & !(CONTROL_IRQ_ENABLE | CONTROL_ENABLE));
+    IRQ_CLEAR.write_volatile(1);
+    cpu::dev_barrier();
+
+    let saved = exception::local_irq_mask_save();
+    HANDLER = None;
+    STOPS = STOPS.wrapping_add(1);
+    exception::local_irq_restore(saved);
+}
+
+/// Ticks left until the timer reaches zero.
+#[allow(dead_code)]
+pub unsafe fn remaining() -> u32 {
+    cpu::dev_barrier();
+    VALUE.read_volatile()
+}
+
+/// Start the free-running counter at the core clock divided by `prescaler + 1`, from where it
+/// was stopped.
+pub unsafe fn start_free_running(prescaler: u8) {
+    cpu::dev_barrier();
+    let control = CONTROL.read_volatile() & !CONTROL_FREE_RUNNING_MASK;
+    CONTROL.write_volatile(
+        control | (prescaler as u32) << CONTROL_FREE_RUNNING_SHIFT | CONTROL_FREE_RUNNING_ENABLE,
+    );
+    cpu::dev_barrier();
+}
+
+pub unsafe fn stop_free_running() {
+    cpu::dev_barrier();
+    let control = CONTROL.read_volatile();
+    CONTROL.write_volatile(control & !CONTROL_FREE_RUNNING_ENABLE);
+    cpu::dev_barrier();
+}
+
+/// The rate the free-running counter counts up at, in Hz.
+pub unsafe fn free_running_rate() -> u32 {
+    cpu::dev_barrier();
+    let prescaler =
+        (CONTROL.read_volatile() & CONTROL_FREE_RUNNING_MASK) >> CONTROL_FREE_RUNNING_SHIFT;
+    CORE_CLOCK / (prescaler + 1)
+}
+
+/// The free-running counter, which wraps around.
+pub unsafe fn free_running_count() -> u32 {
+    cpu::dev_barrier();
+    FREE_RUNNING.read_volatile()
+}
+
+// Runs in IRQ mode when the timer reaches zero
+unsafe fn interrupt_handler() {
+    cpu::dev_barrier();
+    IRQ_CLEAR.write_volatile(1); // any value acknowledges
+    cpu::dev_barrier();
+    let stops = STOPS;
+    if let Some(mut handler) = HANDLER.take() {
+        handler();
+        // unless it stopped the timer, or started it with another handler
+        if STOPS == stops {
+            HANDLER = Some(handler);
+        }
+    }
+}
+
+#[test_case]
+fn test_periodic() {
+    use crate::timer;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static TICKS: AtomicU32 = AtomicU32::new(0);
+    let period = Duration::from_millis(2);
+    let elapsed = unsafe {
+        init().unwrap();
+        let start = timer::Instant::now();
+        every(period, || {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+        timer::sleep(Duration::from_millis(21));
+        stop();
+        start.elapsed()
+    };
+    // other interrupts may stretch the sleep past more periods, or hold off the last tick
+    // until the timer is stopped
+    let periods = (elapsed.as_micros() / period.as_micros()) as u32;
+    let ticks = TICKS.load(Ordering::Relaxed);
+    assert!(ticks == periods || ticks + 1 == periods);
+
+    unsafe {
+        start_free_running(0);
+        let start = free_running_count();
+        timer::sleep(Duration::from_micros(100));
+        let counted = free_running_count().wrapping_sub(start);
+        stop_free_running();
+        // 100us at the core clock, and the time it takes to read the counters
+        assert!(counted >= free_running_rate() / 10_000);
+    }
+}
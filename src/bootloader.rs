@@ -0,0 +1,166 @@
+/*
+ * Chain loading: receive a kernel over the console with XMODEM-CRC and run
+ * it in place of this one.
+ *
+ * Built with the `bootloader` feature, rustberry boots straight into
+ * receive-and-run, so that it can stand in for the C bootloader on the SD
+ * card and take images from bin/rpi-run.py. The shell's `boot` command does
+ * the same on demand.
+ *
+ * The image is received into the heap, which lies above this kernel. Once
+ * it is complete, a small trampoline is copied above the stacks, out of the
+ * way of 0x8000, and copies the image down to 0x8000 and jumps to it.
+ */
+
+use crate::serial::{self, Serial};
+use crate::{interrupts, timer};
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(target_arch = "arm")]
+#[path = "_arch/aarch32/bootloader.rs"]
+mod arch_bootloader;
+
+pub mod xmodem;
+
+use xmodem::{Receiver, Step};
+
+/// Where the firmware loads kernels, and where they are linked to run.
+pub const LOAD_ADDRESS: usize = 0x8000;
+
+/// Largest image accepted, well below what fits in the heap.
+pub const MAX_IMAGE_SIZE: usize = 0x800000;
+
+// Time the sender gets for each byte, and between asking for the first block
+const TIMEOUT_US: u32 = 1_000_000;
+// How long the line must stay quiet before a NAK, so that the rest of a bad block is not taken
+// for the start of the next one
+const PURGE_US: u32 = 100_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The transfer failed.
+    Xmodem(xmodem::Error),
+    /// The image is larger than MAX_IMAGE_SIZE.
+    TooLarge,
+    /// Nobody started a transfer in the time given.
+    NoSender,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Xmodem(xmodem::Error::TooManyErrors) => write!(f, "too many bad blocks"),
+            LoadError::Xmodem(xmodem::Error::Sequence { expected, received }) => write!(
+                f,
+                "expected block {} but received block {}",
+                expected, received
+            ),
+            LoadError::Xmodem(xmodem::Error::Cancelled) => write!(f, "cancelled by the sender"),
+            LoadError::TooLarge => write!(f, "image larger than {} bytes", MAX_IMAGE_SIZE),
+            LoadError::NoSender => write!(f, "no transfer started"),
+        }
+    }
+}
+
+/// Receive and run kernels from the console until one is received in full.
+pub fn run() -> ! {
+    loop {
+        match receive(serial::console(), None) {
+            Ok(image) => unsafe { chain_load(&image) },
+            Err(err) => crate::warn!("chain load failed: {}", err),
+        }
+    }
+}
+
+/// Receive an image with XMODEM-CRC (or XMODEM-1K) from `serial`.
+///
+/// Gives up if no transfer starts within `wait_s` seconds, or waits forever for `None`. Nothing
+/// else must read from or write to `serial` meanwhile, including logging to the console.
+pub fn receive(serial: &dyn Serial, wait_s: Option<u32>) -> Result<Vec<u8>, LoadError> {
+    let mut receiver = Receiver::new();
+    let mut image = Vec::new();
+    let mut waited_s = 0;
+    let mut next = receiver.timeout();
+    loop {
+        match next {
+            Step::Pending => {}
+            Step::Reply(reply) => {
+                if reply == xmodem::NAK {
+                    purge(serial);
+                }
+                serial.put_u8(reply);
+            }
+            Step::Block(data) => {
+                if image.len() + data.len() > MAX_IMAGE_SIZE {
+                    cancel(serial);
+                    return Err(LoadError::TooLarge);
+                }
+                image.extend_from_slice(data);
+                serial.put_u8(xmodem::ACK);
+            }
+            Step::Complete => {
+                serial.put_u8(xmodem::ACK);
+                serial.flush();
+                return Ok(image);
+            }
+            Step::Failed(err) => {
+                if err != xmodem::Error::Cancelled {
+                    cancel(serial);
+                }
+                return Err(LoadError::Xmodem(err));
+            }
+        }
+
+        next = match read_timeout(serial, TIMEOUT_US) {
+            Some(byte) => receiver.feed(byte),
+            None => {
+                if !receiver.started() {
+                    waited_s += 1;
+                    if wait_s.map_or(false, |wait_s| waited_s >= wait_s) {
+                        return Err(LoadError::NoSender);
+                    }
+                }
+                receiver.timeout()
+            }
+        };
+    }
+}
+
+/// Copy `image` over this kernel at LOAD_ADDRESS and jump to it.
+///
+/// # Safety
+///
+/// - `image` must be a kernel linked to run at LOAD_ADDRESS, and must lie above it.
+pub unsafe fn chain_load(image: &[u8]) -> ! {
+    crate::info!("starting the {} byte image", image.len());
+    serial::console().flush();
+    // the handlers and the vectors are about to be overwritten
+    interrupts::init();
+    arch_bootloader::jump(LOAD_ADDRESS, image)
+}
+
+// Returns the next byte from `serial`, or None if none arrives within `us` microseconds
+fn read_timeout(serial: &dyn Serial, us: u32) -> Option<u8> {
+    let start = unsafe { timer::get_ticks() };
+    loop {
+        if let Some(byte) = serial.try_read() {
+            return Some(byte);
+        }
+        if unsafe { timer::get_ticks() }.wrapping_sub(start) >= us {
+            return None;
+        }
+    }
+}
+
+// Drops what is received until the line is quiet
+fn purge(serial: &dyn Serial) {
+    while read_timeout(serial, PURGE_US).is_some() {}
+}
+
+fn cancel(serial: &dyn Serial) {
+    for &byte in xmodem::CANCEL.iter() {
+        serial.put_u8(byte);
+    }
+    serial.flush();
+}
@@ -0,0 +1,203 @@
+/*
+ * XMODEM-CRC receiver, as a state machine fed one byte at a time.
+ *
+ * The receiver asks for a CRC transfer by sending 'C' until the first block
+ * arrives. Blocks are SOH (128 data bytes, plain XMODEM) or STX (1024 data
+ * bytes, XMODEM-1K), followed by the block number, its complement, the data
+ * and a CRC-16 of the data, high byte first. Each block is answered with ACK,
+ * or NAK to have it sent again. EOT ends the transfer, CAN CAN aborts it.
+ *
+ * Tested on the host against a simulated sender.
+ */
+
+pub const SOH: u8 = 0x01;
+pub const STX: u8 = 0x02;
+pub const EOT: u8 = 0x04;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+pub const CAN: u8 = 0x18;
+/// Sent instead of NAK to start a transfer with CRCs rather than checksums.
+pub const CRC_REQUEST: u8 = b'C';
+
+/// What the receiver sends to abort a transfer.
+pub const CANCEL: [u8; 2] = [CAN, CAN];
+
+pub const BLOCK_SIZE: usize = 128;
+pub const BLOCK_1K_SIZE: usize = 1024;
+
+/// Bad or missing blocks in a row before the transfer is given up.
+pub const MAX_ERRORS: u32 = 10;
+
+// Block number, its complement, and the CRC
+const BLOCK_OVERHEAD: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The same block was bad or missing MAX_ERRORS times in a row.
+    TooManyErrors,
+    /// A block arrived out of order, so the data can't be trusted anymore.
+    Sequence { expected: u8, received: u8 },
+    /// The sender sent CAN CAN.
+    Cancelled,
+}
+
+/// What to do after handing a byte, or a timeout, to the receiver.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step<'a> {
+    /// Nothing, wait for more bytes.
+    Pending,
+    /// Send this byte to the sender.
+    Reply(u8),
+    /// The next block of data arrived. Store it, then send ACK.
+    Block(&'a [u8]),
+    /// The transfer is complete. Send ACK.
+    Complete,
+    /// The transfer failed. Send CANCEL unless the sender cancelled.
+    Failed(Error),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    // Between blocks, waiting for SOH, STX or EOT
+    Idle,
+    // Receiving a block of `size` data bytes, `received` bytes after SOH or STX so far
+    Block { size: usize, received: usize },
+    // A CAN arrived between blocks; another one cancels
+    Cancel,
+}
+
+pub struct Receiver {
+    state: State,
+    // Block number, complement, data and CRC of the block being received
+    buffer: [u8; BLOCK_1K_SIZE + BLOCK_OVERHEAD],
+    // Number of the next block, which starts at 1 and wraps around
+    next_block: u8,
+    started: bool,
+    errors: u32,
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Receiver {
+            state: State::Idle,
+            buffer: [0; BLOCK_1K_SIZE + BLOCK_OVERHEAD],
+            next_block: 1,
+            started: false,
+            errors: 0,
+        }
+    }
+
+    /// Whether a block arrived already.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Handle `byte` from the sender.
+    pub fn feed(&mut self, byte: u8) -> Step<'_> {
+        match self.state {
+            State::Idle | State::Cancel => {
+                let cancel = self.state == State::Cancel;
+                self.state = State::Idle;
+                match byte {
+                    SOH => self.begin_block(BLOCK_SIZE),
+                    STX => self.begin_block(BLOCK_1K_SIZE),
+                    EOT if self.started => Step::Complete,
+                    CAN if cancel => Step::Failed(Error::Cancelled),
+                    CAN => {
+                        self.state = State::Cancel;
+                        Step::Pending
+                    }
+                    // line noise, or whatever was printed before the transfer
+                    _ => Step::Pending,
+                }
+            }
+            State::Block { size, received } => {
+                self.buffer[received] = byte;
+                if received + 1 < size + BLOCK_OVERHEAD {
+                    self.state = State::Block {
+                        size,
+                        received: received + 1,
+                    };
+                    return Step::Pending;
+                }
+                self.state = State::Idle;
+                self.end_block(size)
+            }
+        }
+    }
+
+    /// Handle the sender going quiet for a while, and ask for the first block.
+    ///
+    /// Before the first block this keeps asking for a CRC transfer; how long to keep that up is
+    /// the caller's business. Afterwards, or if a block was cut short, the block expected is
+    /// requested again.
+    pub fn timeout(&mut self) -> Step<'_> {
+        let cut_short = matches!(self.state, State::Block { .. });
+        self.state = State::Idle;
+        if !self.started && !cut_short {
+            return Step::Reply(CRC_REQUEST);
+        }
+        self.error()
+    }
+
+    fn begin_block(&mut self, size: usize) -> Step<'_> {
+        self.state = State::Block { size, received: 0 };
+        Step::Pending
+    }
+
+    fn end_block(&mut self, size: usize) -> Step<'_> {
+        let number = self.buffer[0];
+        let complement = self.buffer[1];
+        let data_end = 2 + size;
+        let crc = u16::from_be_bytes([self.buffer[data_end], self.buffer[data_end + 1]]);
+        if number != !complement || crc != crc16(&self.buffer[2..data_end]) {
+            return self.error();
+        }
+
+        if number == self.next_block {
+            self.next_block = self.next_block.wrapping_add(1);
+            self.started = true;
+            self.errors = 0;
+            Step::Block(&self.buffer[2..data_end])
+        } else if self.started && number == self.next_block.wrapping_sub(1) {
+            // our ACK got lost and the sender tries again
+            Step::Reply(ACK)
+        } else {
+            Step::Failed(Error::Sequence {
+                expected: self.next_block,
+                received: number,
+            })
+        }
+    }
+
+    // Asks for the block again, unless that has been done too often already
+    fn error(&mut self) -> Step<'_> {
+        self.errors += 1;
+        if self.errors >= MAX_ERRORS {
+            return Step::Failed(Error::TooManyErrors);
+        }
+        Step::Reply(NAK)
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-16 as XMODEM computes it: polynomial 0x1021, starting from 0, no reflection.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -15,13 +15,23 @@ mod boot;
 //--------------------------------------------------------------------------------------------------
 // Architectural Public Reexports
 //--------------------------------------------------------------------------------------------------
-pub use arch_cpu::wait_forever;
+pub use arch_cpu::{
+    breakpoint, cycle_count, enable_cycle_counter, sync_instruction_cache, wait_forever,
+};
 
 extern "C" {
     pub fn dev_barrier();
 }
 
 /// Pause execution on the core by doing something small again and again.
+///
+/// How long that takes changes with the optimization level, the caches and the clock; use
+/// `timer::sleep()`, which waits at least as long as asked. Every build warns about calls to
+/// this function. That is the only spin loop checked for: a loop written out by hand elsewhere
+/// gets no warning.
+// Nothing calls it; it is kept for the deprecation warning
+#[allow(dead_code)]
+#[deprecated(note = "use `timer::sleep()` for a delay that is at least as long as asked")]
 pub fn sleep(value: u32) {
     for _ in 1..value {
         unsafe {
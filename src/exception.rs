@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2020-2021 Andre Richter <andre.o.richter@gmail.com>
+
+//! Synchronous and asynchronous exception handling.
+
+#[cfg(target_arch = "arm")]
+#[path = "_arch/aarch32/exception.rs"]
+mod arch_exception;
+
+//--------------------------------------------------------------------------------------------------
+// Architectural Public Reexports
+//--------------------------------------------------------------------------------------------------
+pub use arch_exception::{
+    handling_init, local_irq_is_masked, local_irq_mask, local_irq_mask_save, local_irq_restore,
+    local_irq_unmask, request_break, set_debug_handler, DebugEvent, ExceptionFrame,
+};
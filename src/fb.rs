@@ -7,9 +7,6 @@
  *
  */
 
-#[path = "mailbox.rs"]
-mod mailbox;
-
 #[repr(C, align(16))]
 struct FbConfigT {
     width: u32,
@@ -42,8 +39,9 @@ static mut FB: FbConfigT = FbConfigT {
 
 static mut BUFMODE: u32 = FB_SINGLEBUFFER;
 
-use mailbox::mailbox_request;
-use mailbox::MAILBOX_FRAMEBUFFER;
+use crate::mailbox::mailbox_request;
+use crate::mailbox::MAILBOX_FRAMEBUFFER;
+use crate::{error, info};
 
 pub unsafe fn fb_init(width: u32, height: u32, depth_in_bytes: u32, mode: u32) -> bool {
     BUFMODE = mode;
@@ -67,7 +65,19 @@ pub unsafe fn fb_init(width: u32, height: u32, depth_in_bytes: u32, mode: u32) -
     FB.total_bytes = 0;
 
     let config_addr: u32 = (&FB as *const _) as u32;
-    mailbox_request(MAILBOX_FRAMEBUFFER, config_addr)
+    let ok = mailbox_request(MAILBOX_FRAMEBUFFER, config_addr);
+    if ok {
+        info!(
+            "{}x{} framebuffer, {} bytes per pixel, pitch {}, at {:#010x}",
+            width, height, depth_in_bytes, FB.pitch, FB.framebuffer
+        );
+    } else {
+        error!(
+            "the GPU refused a {}x{} framebuffer with {} bytes per pixel",
+            width, height, depth_in_bytes
+        );
+    }
+    ok
 }
 
 pub unsafe fn fb_swap_buffer() -> bool {
@@ -0,0 +1,470 @@
+/*
+ * A GDB stub: lets gdb-multiarch debug the kernel over a serial port with
+ * the remote serial protocol.
+ *
+ *     (gdb) set architecture arm
+ *     (gdb) set serial baud 115200
+ *     (gdb) target remote /dev/ttyUSB0
+ *
+ * Once init() is called, exceptions stop in the stub instead of panicking:
+ * BKPT (cpu::breakpoint(), or breakpoints GDB plants), undefined
+ * instructions and aborts, and Ctrl-C from GDB, which the UART interrupt
+ * handlers turn into a break. The stub then answers GDB's packets with IRQs
+ * masked until GDB continues, steps or detaches.
+ *
+ * Registers, memory, software breakpoints and single steps are supported.
+ * Steps plant a breakpoint where step::next_pc() says the instruction
+ * continues. Memory accesses are not checked: reading an address nothing
+ * answers at hangs the board, like it would outside of the debugger.
+ */
+
+use crate::cpu;
+use crate::exception::{self, DebugEvent, ExceptionFrame};
+use crate::serial::Serial;
+
+pub mod packet;
+pub mod step;
+
+use packet::{Event, PacketReader, Response, PACKET_SIZE};
+
+// BKPT #0
+const BKPT: u32 = 0xe120_0070;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+// Signals reported to GDB
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// GDB's register numbers for ARM without a target description: r0-r15, the FPA registers f0-f7
+// of 12 bytes each and their status register, then the cpsr
+const REGISTER_FPS: usize = 24;
+const REGISTER_CPSR: usize = 25;
+const FPA_BYTES: usize = 8 * 12 + 4;
+
+// The cpsr bits GDB may change. The mode and the mask bits stay, as the stub returns through them.
+const CPSR_FLAGS: u32 = 0xf800_0000;
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    address: u32,
+    // the instruction the BKPT replaced
+    instruction: u32,
+}
+
+// A breakpoint the stub plants itself to step one instruction
+#[derive(Copy, Clone)]
+struct StepBreakpoint {
+    breakpoint: Breakpoint,
+    // GDB's breakpoint at the address stepped from, which is put back when the step is done
+    reinsert: Option<u32>,
+    // whether GDB asked for the step, rather than to continue past a breakpoint
+    report: bool,
+}
+
+static mut SERIAL: Option<&'static dyn Serial> = None;
+static mut BREAKPOINTS: [Option<Breakpoint>; MAX_BREAKPOINTS] = [None; MAX_BREAKPOINTS];
+static mut STEP: Option<StepBreakpoint> = None;
+// Whether GDB waits for a stop reply, because it continued or stepped
+static mut RUNNING: bool = false;
+static mut LAST_SIGNAL: u8 = SIGTRAP;
+
+static mut READER: PacketReader = PacketReader::new();
+static mut RESPONSE: Response = Response::new();
+
+/// Debug over `serial`, the console or another serial port in interrupt mode: from now on
+/// exceptions and Ctrl-C on `serial` stop in the stub.
+///
+/// Ctrl-C stops being data on `serial`, e.g. for the shell. Other ports pass it on.
+pub fn init(serial: &'static dyn Serial) {
+    unsafe {
+        if let Some(old) = SERIAL {
+            old.set_break_byte(None);
+        }
+        SERIAL = Some(serial);
+        exception::set_debug_handler(Some(handle));
+    }
+    serial.set_break_byte(Some(packet::INTERRUPT));
+}
+
+// Runs the stub for an exception, until GDB lets the interrupted code go on
+fn handle(event: DebugEvent, frame: &mut ExceptionFrame) {
+    let serial = match unsafe { SERIAL } {
+        Some(serial) => serial,
+        None => return,
+    };
+
+    let signal = match event {
+        DebugEvent::Breakpoint => match unsafe { end_step(frame) } {
+            StepEnd::Stop => SIGTRAP,
+            StepEnd::Continue => return,
+            StepEnd::NotStepping => {
+                // a BKPT compiled into the code would stop us again and again
+                if unsafe { !is_breakpoint(frame.pc) } {
+                    frame.pc = frame.pc.wrapping_add(4);
+                }
+                SIGTRAP
+            }
+        },
+        DebugEvent::Break => SIGINT,
+        DebugEvent::UndefinedInstruction => SIGILL,
+        DebugEvent::PrefetchAbort | DebugEvent::DataAbort => SIGSEGV,
+    };
+
+    unsafe {
+        LAST_SIGNAL = signal;
+        if RUNNING {
+            RUNNING = false;
+            RESPONSE.clear();
+            stop_reply(&mut RESPONSE);
+            send(serial, RESPONSE.as_bytes());
+        }
+        serve(serial, frame);
+    }
+}
+
+// Answers packets until one lets the code go on
+unsafe fn serve(serial: &dyn Serial, frame: &mut ExceptionFrame) {
+    loop {
+        let data = loop {
+            match READER.feed(serial.read()) {
+                Some(Event::Packet(data)) => break data,
+                Some(Event::BadPacket) => serial.put_u8(b'-'),
+                // stopped already
+                Some(Event::Interrupt) | None => {}
+            }
+        };
+        serial.put_u8(b'+');
+
+        let (&command, args) = match data.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let response = &mut RESPONSE;
+        response.clear();
+        match command {
+            b'?' => stop_reply(response),
+            b'g' => read_registers(frame, response),
+            b'G' => reply_ok(response, write_registers(frame, args)),
+            b'p' => read_register(frame, args, response),
+            b'P' => reply_ok(response, write_register(frame, args)),
+            b'm' => read_memory(args, response),
+            b'M' => reply_ok(response, write_memory(args)),
+            b'Z' => reply_ok(response, breakpoint_args(args).and_then(insert_breakpoint)),
+            b'z' => reply_ok(response, breakpoint_args(args).and_then(remove_breakpoint)),
+            b'c' | b's' => {
+                if let Some(address) = packet::parse_hex(args) {
+                    frame.pc = address;
+                }
+                resume(frame, command == b's');
+                return;
+            }
+            b'D' | b'k' => {
+                remove_all_breakpoints();
+                if command == b'D' {
+                    send(serial, b"OK");
+                }
+                return;
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                response.push(b"PacketSize=");
+                push_hex_number(response, PACKET_SIZE as u32);
+            }
+            b'q' if args == b"Attached" => response.push(b"1"),
+            b'H' => response.push(b"OK"),
+            // everything else is not supported, which an empty response says
+            _ => {}
+        }
+        send(serial, response.as_bytes());
+    }
+}
+
+// Sends a packet carrying `data` until GDB acknowledges it
+fn send(serial: &dyn Serial, data: &[u8]) {
+    loop {
+        packet::frame(data, &mut |byte| serial.put_u8(byte));
+        serial.flush();
+        loop {
+            match serial.read() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn stop_reply(response: &mut Response) {
+    response.push(b"S");
+    response.push_hex(&[unsafe { LAST_SIGNAL }]);
+}
+
+fn reply_ok(response: &mut Response, result: Option<()>) {
+    response.push(match result {
+        Some(()) => b"OK",
+        None => b"E01",
+    });
+}
+
+fn push_hex_number(response: &mut Response, value: u32) {
+    let digits = 8 - (value.leading_zeros() as usize / 4).min(7);
+    for i in (0..digits).rev() {
+        let digit = ((value >> (i * 4)) & 0xf) as u8;
+        response.push(&[if digit < 10 {
+            b'0' + digit
+        } else {
+            b'a' + digit - 10
+        }]);
+    }
+}
+
+fn read_registers(frame: &ExceptionFrame, response: &mut Response) {
+    for index in 0..16 {
+        response.push_word(frame.register(index));
+    }
+    response.push_hex(&[0; FPA_BYTES]);
+    response.push_word(frame.cpsr);
+}
+
+fn write_registers(frame: &mut ExceptionFrame, args: &[u8]) -> Option<()> {
+    let mut words = args.chunks(8);
+    let mut registers = [0; 16];
+    for register in registers.iter_mut() {
+        *register = packet::parse_word(words.next()?)?;
+    }
+    for (index, &value) in registers.iter().enumerate() {
+        frame.set_register(index, value);
+    }
+    // the cpsr, if GDB sent it, comes after the FPA registers
+    if let Some(cpsr) = args.get((16 * 4 + FPA_BYTES) * 2..) {
+        if !cpsr.is_empty() {
+            set_cpsr(frame, packet::parse_word(cpsr)?);
+        }
+    }
+    Some(())
+}
+
+fn register_number(args: &[u8]) -> Option<usize> {
+    packet::parse_hex(args).map(|number| number as usize)
+}
+
+fn read_register(frame: &ExceptionFrame, args: &[u8], response: &mut Response) {
+    match register_number(args) {
+        Some(index @ 0..=15) => response.push_word(frame.register(index)),
+        Some(16..=23) => response.push_hex(&[0; 12]),
+        Some(REGISTER_FPS) => response.push_word(0),
+        Some(REGISTER_CPSR) => response.push_word(frame.cpsr),
+        _ => response.push(b"E01"),
+    }
+}
+
+fn write_register(frame: &mut ExceptionFrame, args: &[u8]) -> Option<()> {
+    let equals = args.iter().position(|&byte| byte == b'=')?;
+    let value = packet::parse_word(&args[equals + 1..])?;
+    match register_number(&args[..equals])? {
+        index @ 0..=15 => frame.set_register(index, value),
+        REGISTER_CPSR => set_cpsr(frame, value),
+        // there is no FPA to write to
+        16..=REGISTER_FPS => {}
+        _ => return None,
+    }
+    Some(())
+}
+
+fn set_cpsr(frame: &mut ExceptionFrame, value: u32) {
+    frame.cpsr = frame.cpsr & !CPSR_FLAGS | value & CPSR_FLAGS;
+}
+
+// Parses "address,length" at the start of `args`, returning the rest after it
+fn address_length(args: &[u8]) -> Option<(u32, usize, &[u8])> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    let end = args
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or_else(|| args.len());
+    let address = packet::parse_hex(&args[..comma])?;
+    let length = packet::parse_hex(args.get(comma + 1..end)?)? as usize;
+    Some((address, length, args.get(end + 1..).unwrap_or(&[])))
+}
+
+// Whether memory is accessed a word at a time, which peripherals need
+fn word_access(address: u32, length: usize) -> bool {
+    address % 4 == 0 && length % 4 == 0
+}
+
+fn read_memory(args: &[u8], response: &mut Response) {
+    let (address, length) = match address_length(args) {
+        Some((address, length, _)) => (address, length.min(PACKET_SIZE / 2)),
+        None => return response.push(b"E01"),
+    };
+    unsafe {
+        cpu::dev_barrier();
+        if word_access(address, length) {
+            for offset in (0..length).step_by(4) {
+                let word = (address as usize + offset) as *const u32;
+                response.push_hex(&word.read_volatile().to_le_bytes());
+            }
+        } else {
+            for offset in 0..length {
+                let byte = (address as usize + offset) as *const u8;
+                response.push_hex(&[byte.read_volatile()]);
+            }
+        }
+    }
+}
+
+fn write_memory(args: &[u8]) -> Option<()> {
+    let (address, length, hex) = address_length(args)?;
+    let mut bytes = [0; PACKET_SIZE / 2];
+    if packet::decode_hex(hex, &mut bytes)? != length {
+        return None;
+    }
+    unsafe {
+        cpu::dev_barrier();
+        if word_access(address, length) {
+            for (offset, word) in bytes[..length].chunks(4).enumerate() {
+                let pointer = (address as usize + offset * 4) as *mut u32;
+                pointer.write_volatile(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        } else {
+            for (offset, &byte) in bytes[..length].iter().enumerate() {
+                ((address as usize + offset) as *mut u8).write_volatile(byte);
+            }
+        }
+        cpu::dev_barrier();
+    }
+    // GDB may have planted breakpoints this way
+    cpu::sync_instruction_cache();
+    Some(())
+}
+
+// Parses "type,address,kind" of Z and z, for software breakpoints in ARM code only
+fn breakpoint_args(args: &[u8]) -> Option<u32> {
+    let mut fields = args.split(|&byte| byte == b',');
+    let (kind, address, size) = (fields.next()?, fields.next()?, fields.next()?);
+    match (kind, packet::parse_hex(size)?) {
+        (b"0", 4) => packet::parse_hex(address),
+        _ => None,
+    }
+}
+
+unsafe fn read_instruction(address: u32) -> u32 {
+    (address as *const u32).read_volatile()
+}
+
+unsafe fn write_instruction(address: u32, instruction: u32) {
+    (address as *mut u32).write_volatile(instruction);
+    cpu::sync_instruction_cache();
+}
+
+// Whether GDB planted a breakpoint at `address`
+unsafe fn is_breakpoint(address: u32) -> bool {
+    BREAKPOINTS.iter().flatten().any(|b| b.address == address)
+}
+
+fn insert_breakpoint(address: u32) -> Option<()> {
+    unsafe {
+        if address % 4 != 0 {
+            return None;
+        }
+        if BREAKPOINTS.iter().flatten().any(|b| b.address == address) {
+            return Some(());
+        }
+        let slot = BREAKPOINTS.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(Breakpoint {
+            address,
+            instruction: read_instruction(address),
+        });
+        write_instruction(address, BKPT);
+    }
+    Some(())
+}
+
+fn remove_breakpoint(address: u32) -> Option<()> {
+    unsafe {
+        let slot = BREAKPOINTS
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |b| b.address == address))?;
+        if let Some(breakpoint) = slot.take() {
+            write_instruction(breakpoint.address, breakpoint.instruction);
+        }
+    }
+    Some(())
+}
+
+unsafe fn remove_all_breakpoints() {
+    for slot in BREAKPOINTS.iter_mut() {
+        if let Some(breakpoint) = slot.take() {
+            write_instruction(breakpoint.address, breakpoint.instruction);
+        }
+    }
+    if let Some(step) = STEP.take() {
+        write_instruction(step.breakpoint.address, step.breakpoint.instruction);
+    }
+}
+
+// Lets the interrupted code go on, for one instruction if `single_step`. Continuing from one of
+// GDB's breakpoints steps past it first, with the original instruction back in place.
+unsafe fn resume(frame: &ExceptionFrame, single_step: bool) {
+    RUNNING = true;
+    let on_breakpoint = BREAKPOINTS
+        .iter()
+        .flatten()
+        .find(|b| b.address == frame.pc)
+        .copied();
+    if !single_step && on_breakpoint.is_none() {
+        return;
+    }
+
+    let instruction = match on_breakpoint {
+        Some(breakpoint) => {
+            write_instruction(breakpoint.address, breakpoint.instruction);
+            breakpoint.instruction
+        }
+        None => read_instruction(frame.pc),
+    };
+    let mut registers = [0; 16];
+    for (index, register) in registers.iter_mut().enumerate() {
+        *register = frame.register(index);
+    }
+    let next = step::next_pc(instruction, &registers, frame.cpsr, &|address| {
+        read_instruction(address)
+    });
+    STEP = Some(StepBreakpoint {
+        breakpoint: Breakpoint {
+            address: next,
+            instruction: read_instruction(next),
+        },
+        reinsert: on_breakpoint.map(|breakpoint| breakpoint.address),
+        report: single_step,
+    });
+    write_instruction(next, BKPT);
+}
+
+enum StepEnd {
+    // the step is done, tell GDB
+    Stop,
+    // the step past a breakpoint is done, carry on
+    Continue,
+    NotStepping,
+}
+
+// Takes the step breakpoint out again if that is where the code stopped
+unsafe fn end_step(frame: &ExceptionFrame) -> StepEnd {
+    let step = match STEP {
+        Some(step) if step.breakpoint.address == frame.pc => step,
+        _ => return StepEnd::NotStepping,
+    };
+    STEP = None;
+    write_instruction(step.breakpoint.address, step.breakpoint.instruction);
+    if let Some(address) = step.reinsert {
+        write_instruction(address, BKPT);
+    }
+    if step.report {
+        StepEnd::Stop
+    } else {
+        StepEnd::Continue
+    }
+}
@@ -0,0 +1,222 @@
+/*
+ * Framing of the GDB remote serial protocol: packets are `$data#cc`, with cc
+ * the sum of the data bytes modulo 256 in two hex digits. The receiver
+ * answers each packet with `+`, or `-` to have it sent again. A lone 0x03
+ * (Ctrl-C) outside of a packet asks the target to stop.
+ *
+ * Also the hex encodings the packets carry numbers and memory in.
+ */
+
+/// Largest packet data received or sent, advertised to GDB as PacketSize.
+pub const PACKET_SIZE: usize = 1024;
+
+/// Sent by GDB outside of packets to stop the target.
+pub const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A packet with a good checksum arrived. Send `+`, then answer it.
+    Packet(&'a [u8]),
+    /// A packet arrived garbled or too long. Send `-`.
+    BadPacket,
+    /// GDB asks the target to stop.
+    Interrupt,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    // Outside of packets, where acknowledgements and Ctrl-C go
+    Idle,
+    Data,
+    // After '#', with the first checksum digit once received
+    Checksum(Option<u8>),
+}
+
+pub struct PacketReader {
+    state: State,
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+    overflow: bool,
+    sum: u8,
+}
+
+impl PacketReader {
+    pub const fn new() -> Self {
+        PacketReader {
+            state: State::Idle,
+            buffer: [0; PACKET_SIZE],
+            len: 0,
+            overflow: false,
+            sum: 0,
+        }
+    }
+
+    /// Handle `byte` from GDB.
+    pub fn feed(&mut self, byte: u8) -> Option<Event<'_>> {
+        match (self.state, byte) {
+            // a new packet starts over whatever came before
+            (_, b'$') => {
+                self.state = State::Data;
+                self.len = 0;
+                self.overflow = false;
+                self.sum = 0;
+                None
+            }
+            (State::Idle, INTERRUPT) => Some(Event::Interrupt),
+            // '+' and '-' for what we sent, or noise
+            (State::Idle, _) => None,
+            (State::Data, b'#') => {
+                self.state = State::Checksum(None);
+                None
+            }
+            (State::Data, _) => {
+                self.sum = self.sum.wrapping_add(byte);
+                if self.len < PACKET_SIZE {
+                    self.buffer[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+            (State::Checksum(None), _) => match hex_value(byte) {
+                Some(high) => {
+                    self.state = State::Checksum(Some(high));
+                    None
+                }
+                None => {
+                    self.state = State::Idle;
+                    Some(Event::BadPacket)
+                }
+            },
+            (State::Checksum(Some(high)), _) => {
+                self.state = State::Idle;
+                match hex_value(byte) {
+                    Some(low) if !self.overflow && high << 4 | low == self.sum => {
+                        Some(Event::Packet(&self.buffer[..self.len]))
+                    }
+                    _ => Some(Event::BadPacket),
+                }
+            }
+        }
+    }
+}
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Data of a packet being put together, for `frame()`.
+pub struct Response {
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub const fn new() -> Self {
+        Response {
+            buffer: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Append `bytes`, as far as they fit.
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == PACKET_SIZE {
+                return;
+            }
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Append `bytes` as two hex digits each.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[
+                HEX_DIGITS[(byte >> 4) as usize],
+                HEX_DIGITS[(byte & 0xf) as usize],
+            ]);
+        }
+    }
+
+    /// Append `value` the way registers are sent: its bytes in target order, little endian.
+    pub fn push_word(&mut self, value: u32) {
+        self.push_hex(&value.to_le_bytes());
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Calls `put` with the bytes of the packet carrying `data`.
+pub fn frame(data: &[u8], put: &mut dyn FnMut(u8)) {
+    put(b'$');
+    for &byte in data {
+        put(byte);
+    }
+    let sum = checksum(data);
+    put(b'#');
+    put(HEX_DIGITS[(sum >> 4) as usize]);
+    put(HEX_DIGITS[(sum & 0xf) as usize]);
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+pub fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a number written in hex, most significant digit first, like addresses and lengths.
+pub fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        Some(value << 4 | hex_value(digit)? as u32)
+    })
+}
+
+/// Decodes pairs of hex digits into `out`, returning how many bytes that was.
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    let pairs = digits.chunks_exact(2);
+    if !pairs.remainder().is_empty() || pairs.len() > out.len() {
+        return None;
+    }
+    for (pair, byte) in pairs.zip(out.iter_mut()) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+/// Parses a register value as `Response::push_word()` writes it.
+pub fn parse_word(digits: &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    match decode_hex(digits, &mut bytes)? {
+        4 => Some(u32::from_le_bytes(bytes)),
+        _ => None,
+    }
+}
@@ -0,0 +1,201 @@
+/*
+ * Where an ARM instruction continues, for single-stepping with a breakpoint
+ * on the next instruction rather than debug hardware.
+ *
+ * Covers the instructions that can write the pc in ARM state: B, BL, BX,
+ * BLX, data processing with rd = pc, LDR pc and LDM with pc in the list.
+ * Everything else, and anything whose condition fails, continues with the
+ * next word. Thumb is not supported.
+ */
+
+const PC: usize = 15;
+
+const CPSR_N: u32 = 1 << 31;
+const CPSR_Z: u32 = 1 << 30;
+const CPSR_C: u32 = 1 << 29;
+const CPSR_V: u32 = 1 << 28;
+
+/// Returns the address of the instruction executed after `instruction`.
+///
+/// `registers` are r0-r15 with r15 the address of `instruction`, `cpsr` supplies the condition
+/// flags, and `read_word` loads a word from memory, for LDR and LDM.
+pub fn next_pc(
+    instruction: u32,
+    registers: &[u32; 16],
+    cpsr: u32,
+    read_word: &dyn Fn(u32) -> u32,
+) -> u32 {
+    let pc = registers[PC];
+    let next = pc.wrapping_add(4);
+    let condition = instruction >> 28;
+
+    if condition == 0xf {
+        // the unconditional space, where only BLX <label> branches
+        if instruction & 0x0e00_0000 == 0x0a00_0000 {
+            let halfword = (instruction >> 23) & 2;
+            return branch_target(instruction, pc) | halfword;
+        }
+        return next;
+    }
+    if !condition_passed(condition, cpsr) {
+        return next;
+    }
+
+    // reading the pc as an operand gives the address of the instruction plus 8
+    let register = |index: u32| match index as usize & 0xf {
+        PC => pc.wrapping_add(8),
+        index => registers[index],
+    };
+    let rd = (instruction >> 12) & 0xf;
+    let rn = (instruction >> 16) & 0xf;
+    let load = instruction & (1 << 20) != 0;
+
+    // BX and BLX register
+    if instruction & 0x0fff_ffd0 == 0x012f_ff10 {
+        return register(instruction & 0xf) & !1;
+    }
+
+    match (instruction >> 25) & 0b111 {
+        // B, BL
+        0b101 => branch_target(instruction, pc),
+        // LDM with the pc in the register list
+        0b100 if load && instruction & (1 << PC) != 0 => {
+            let base = register(rn);
+            let count = (instruction & 0xffff).count_ones();
+            let before = instruction & (1 << 24) != 0;
+            let up = instruction & (1 << 23) != 0;
+            // the pc is the highest register, so it is loaded from the highest address
+            let address = match (before, up) {
+                (false, true) => base.wrapping_add(4 * (count - 1)),
+                (true, true) => base.wrapping_add(4 * count),
+                (false, false) => base,
+                (true, false) => base.wrapping_sub(4),
+            };
+            read_word(address)
+        }
+        // LDR of a word into the pc; 011 with bit 4 set are the media instructions
+        0b010 | 0b011
+            if load
+                && rd as usize == PC
+                && instruction & (1 << 22) == 0
+                && instruction & 0x0200_0010 != 0x0200_0010 =>
+        {
+            let offset = if instruction & (1 << 25) == 0 {
+                instruction & 0xfff
+            } else {
+                shifted_register(instruction, &register, cpsr)
+            };
+            let base = register(rn);
+            let address = match (instruction & (1 << 24) != 0, instruction & (1 << 23) != 0) {
+                (true, true) => base.wrapping_add(offset),
+                (true, false) => base.wrapping_sub(offset),
+                // post-indexed loads use the base as it is
+                (false, _) => base,
+            };
+            read_word(address)
+        }
+        0b000 | 0b001 if rd as usize == PC => {
+            data_processing(instruction, &register, cpsr).unwrap_or(next)
+        }
+        _ => next,
+    }
+}
+
+// Target of B, BL and BLX <label>: a signed word offset from the instruction plus 8
+fn branch_target(instruction: u32, pc: u32) -> u32 {
+    let offset = ((instruction << 8) as i32 >> 6) as u32;
+    pc.wrapping_add(8).wrapping_add(offset)
+}
+
+fn condition_passed(condition: u32, cpsr: u32) -> bool {
+    let n = cpsr & CPSR_N != 0;
+    let z = cpsr & CPSR_Z != 0;
+    let c = cpsr & CPSR_C != 0;
+    let v = cpsr & CPSR_V != 0;
+    match condition {
+        0x0 => z,
+        0x1 => !z,
+        0x2 => c,
+        0x3 => !c,
+        0x4 => n,
+        0x5 => !n,
+        0x6 => v,
+        0x7 => !v,
+        0x8 => c && !z,
+        0x9 => !c || z,
+        0xa => n == v,
+        0xb => n != v,
+        0xc => !z && n == v,
+        0xd => z || n != v,
+        _ => true,
+    }
+}
+
+// The result of a data processing instruction, or None if it is not one that writes rd
+fn data_processing(instruction: u32, register: &dyn Fn(u32) -> u32, cpsr: u32) -> Option<u32> {
+    let immediate = instruction & (1 << 25) != 0;
+    let opcode = (instruction >> 21) & 0xf;
+    let sets_flags = instruction & (1 << 20) != 0;
+    // multiplies and the extra loads and stores
+    if !immediate && instruction & 0x90 == 0x90 {
+        return None;
+    }
+    // TST, TEQ, CMP and CMN without S are the miscellaneous instructions, e.g. MRS and MSR
+    if (0x8..=0xb).contains(&opcode) && !sets_flags {
+        return None;
+    }
+
+    let operand = if immediate {
+        (instruction & 0xff).rotate_right(2 * ((instruction >> 8) & 0xf))
+    } else {
+        shifted_register(instruction, register, cpsr)
+    };
+    let rn = register((instruction >> 16) & 0xf);
+    let carry = (cpsr & CPSR_C != 0) as u32;
+    let borrow = 1 - carry;
+    Some(match opcode {
+        0x0 => rn & operand,
+        0x1 => rn ^ operand,
+        0x2 => rn.wrapping_sub(operand),
+        0x3 => operand.wrapping_sub(rn),
+        0x4 => rn.wrapping_add(operand),
+        0x5 => rn.wrapping_add(operand).wrapping_add(carry),
+        0x6 => rn.wrapping_sub(operand).wrapping_sub(borrow),
+        0x7 => operand.wrapping_sub(rn).wrapping_sub(borrow),
+        0xc => rn | operand,
+        0xd => operand,
+        0xe => rn & !operand,
+        0xf => !operand,
+        // the comparisons do not write rd
+        _ => return None,
+    })
+}
+
+// The shifted register operand of data processing instructions and register offset loads
+fn shifted_register(instruction: u32, register: &dyn Fn(u32) -> u32, cpsr: u32) -> u32 {
+    let value = register(instruction & 0xf);
+    let by_register = instruction & (1 << 4) != 0;
+    let amount = if by_register {
+        register((instruction >> 8) & 0xf) & 0xff
+    } else {
+        (instruction >> 7) & 0x1f
+    };
+    let carry = (cpsr & CPSR_C != 0) as u32;
+    match (instruction >> 5) & 0b11 {
+        // LSL
+        0b00 if amount >= 32 => 0,
+        0b00 => value << amount,
+        // LSR, where an immediate 0 means 32
+        0b01 if amount == 0 && by_register => value,
+        0b01 if amount == 0 || amount >= 32 => 0,
+        0b01 => value >> amount,
+        // ASR, likewise
+        0b10 if amount == 0 && by_register => value,
+        0b10 if amount == 0 || amount >= 32 => ((value as i32) >> 31) as u32,
+        0b10 => ((value as i32) >> amount) as u32,
+        // ROR, where an immediate 0 is RRX
+        _ if amount == 0 && by_register => value,
+        _ if amount == 0 => carry << 31 | value >> 1,
+        _ => value.rotate_right(amount & 0x1f),
+    }
+}
@@ -5,17 +5,20 @@
  * Author: Ashish Rao <aprao@stanford.edu>
  */
 
-use crate::{cpu, fb};
+use crate::log::{Record, Sink};
+use crate::timer::{self, Duration};
+use crate::{exception, fb, mouse, serial, watchdog};
+use core::cell::UnsafeCell;
 use core::convert::TryInto;
+use core::fmt::{self, Write};
 
 use embedded_graphics::{
     egtext,
-    fonts::Font24x32,
+    fonts::{Font24x32, Font6x8, Text},
     pixelcolor::Bgr888,
     prelude::*,
-    prelude::*,
     primitives::{Circle, Rectangle, Triangle},
-    style::PrimitiveStyle,
+    style::{PrimitiveStyle, TextStyleBuilder},
     text_style, DrawTarget,
 };
 
@@ -85,6 +88,239 @@ impl DrawTarget<Bgr888> for Display {
     }
 }
 
+// The mouse pointer, hotspot top left: 'X' is drawn black, '.' white, ' ' is transparent
+const CURSOR_SHAPE: [&str; 16] = [
+    "X          ",
+    "XX         ",
+    "X.X        ",
+    "X..X       ",
+    "X...X      ",
+    "X....X     ",
+    "X.....X    ",
+    "X......X   ",
+    "X.......X  ",
+    "X........X ",
+    "X.....XXXXX",
+    "X..X..X    ",
+    "X.X X..X   ",
+    "XX  X..X   ",
+    "X    X..X  ",
+    "     XXXX  ",
+];
+const CURSOR_WIDTH: usize = 11;
+const CURSOR_HEIGHT: usize = 16;
+
+// Returns the address of a pixel in the draw buffer, or None if it is off the screen.
+// Assumes 4 bytes per pixel.
+unsafe fn pixel_address(x: i32, y: i32) -> Option<*mut u32> {
+    let x: u32 = x.try_into().ok()?;
+    let y: u32 = y.try_into().ok()?;
+    if x >= fb::fb_get_width() || y >= fb::fb_get_height() {
+        return None;
+    }
+    let index: u32 = (fb::fb_get_depth() * x) + (y * fb::fb_get_pitch());
+    Some((fb::fb_get_draw_buffer() + index) as *mut u32)
+}
+
+/// A mouse pointer drawn over whatever is on the screen.
+///
+/// On a single buffered screen, erase() the cursor before moving it or drawing under it, then
+/// draw() it again; it restores the pixels it covered. A double buffered screen that is redrawn
+/// every frame only needs draw() before each fb_swap_buffer().
+pub struct Cursor {
+    x: i32,
+    y: i32,
+    // Pixels covered by the last draw(), and where they were
+    saved: [u32; CURSOR_WIDTH * CURSOR_HEIGHT],
+    saved_at: Option<(i32, i32)>,
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Cursor {
+            x: 0,
+            y: 0,
+            saved: [0; CURSOR_WIDTH * CURSOR_HEIGHT],
+            saved_at: None,
+        }
+    }
+
+    /// The screen position the cursor points at.
+    pub fn position(&self) -> Point {
+        Point::new(self.x, self.y)
+    }
+
+    /// Move the cursor to (x, y), keeping it on the screen.
+    pub unsafe fn move_to(&mut self, x: i32, y: i32) {
+        let max_x = fb::fb_get_width() as i32 - 1;
+        let max_y = fb::fb_get_height() as i32 - 1;
+        self.x = x.max(0).min(max_x);
+        self.y = y.max(0).min(max_y);
+    }
+
+    /// Move the cursor by a relative amount, e.g. that of a mouse::MouseEvent.
+    pub unsafe fn move_by(&mut self, dx: i32, dy: i32) {
+        self.move_to(self.x + dx, self.y + dy);
+    }
+
+    /// Draw the cursor at its position, saving the pixels it covers.
+    pub unsafe fn draw(&mut self) {
+        for (row, line) in CURSOR_SHAPE.iter().enumerate() {
+            for (col, shape) in line.bytes().enumerate() {
+                let address = pixel_address(self.x + col as i32, self.y + row as i32);
+                if let Some(address) = address {
+                    self.saved[row * CURSOR_WIDTH + col] = address.read_volatile();
+                    match shape {
+                        b'X' => address.write_volatile(0xff000000),
+                        b'.' => address.write_volatile(0xffffffff),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.saved_at = Some((self.x, self.y));
+    }
+
+    /// Restore the pixels covered by the last draw(), if the cursor is drawn.
+    pub unsafe fn erase(&mut self) {
+        let (x, y) = match self.saved_at.take() {
+            Some(at) => at,
+            None => return,
+        };
+        for row in 0..CURSOR_HEIGHT {
+            for col in 0..CURSOR_WIDTH {
+                if let Some(address) = pixel_address(x + col as i32, y + row as i32) {
+                    address.write_volatile(self.saved[row * CURSOR_WIDTH + col]);
+                }
+            }
+        }
+    }
+}
+
+// Size of a character cell of the text console
+const FONT_WIDTH: u32 = 6;
+const FONT_HEIGHT: u32 = 8;
+
+/// Lines of text on the screen that scroll up as new ones are added, e.g. as a `log::Sink`.
+///
+/// Text is drawn white on black with the 6x8 font into the buffer being drawn on, so this is
+/// meant for a single buffered screen. Call clear() once the framebuffer is set up.
+pub struct TextConsole {
+    // Column and row of the next character. Changed with IRQs masked.
+    position: UnsafeCell<(u32, u32)>,
+}
+
+// All access happens with IRQs masked on the one core.
+unsafe impl Sync for TextConsole {}
+
+/// The console on the screen.
+pub static SCREEN: TextConsole = TextConsole::new();
+
+impl TextConsole {
+    pub const fn new() -> Self {
+        TextConsole {
+            position: UnsafeCell::new((0, 0)),
+        }
+    }
+
+    // The size of the screen in characters
+    unsafe fn size() -> (u32, u32) {
+        (
+            fb::fb_get_width() / FONT_WIDTH,
+            fb::fb_get_height() / FONT_HEIGHT,
+        )
+    }
+
+    /// Blank the screen and start over at the top left.
+    pub fn clear(&self) {
+        let saved = exception::local_irq_mask_save();
+        unsafe {
+            fill_rows(0, fb::fb_get_height());
+            *self.position.get() = (0, 0);
+        }
+        exception::local_irq_restore(saved);
+    }
+
+    /// Print `string` at the current position, wrapping long lines.
+    pub fn write_str(&self, string: &str) {
+        let saved = exception::local_irq_mask_save();
+        unsafe {
+            let (columns, rows) = Self::size();
+            if columns > 0 && rows > 0 {
+                let (column, row) = &mut *self.position.get();
+                for c in string.chars() {
+                    match c {
+                        '\n' => newline(column, row, rows),
+                        '\r' => {}
+                        _ => {
+                            if *column == columns {
+                                newline(column, row, rows);
+                            }
+                            draw_char(c, *column, *row);
+                            *column += 1;
+                        }
+                    }
+                }
+            }
+        }
+        exception::local_irq_restore(saved);
+    }
+}
+
+// Moves to the start of the next line, scrolling the screen up a line at the bottom
+unsafe fn newline(column: &mut u32, row: &mut u32, rows: u32) {
+    *column = 0;
+    if *row + 1 < rows {
+        *row += 1;
+        return;
+    }
+    let line = (FONT_HEIGHT * fb::fb_get_pitch()) as usize;
+    let screen = fb::fb_get_draw_buffer() as *mut u8;
+    core::ptr::copy(screen.add(line), screen, (rows as usize - 1) * line);
+    fill_rows(*row * FONT_HEIGHT, FONT_HEIGHT);
+}
+
+// Blanks `height` pixel rows starting at `y`
+unsafe fn fill_rows(y: u32, height: u32) {
+    if height == 0 || fb::fb_get_width() == 0 {
+        return;
+    }
+    let top_left = Point::new(0, y as i32);
+    let bottom_right = Point::new(fb::fb_get_width() as i32 - 1, (y + height) as i32 - 1);
+    let _ = Rectangle::new(top_left, bottom_right)
+        .into_styled(PrimitiveStyle::with_fill(Bgr888::BLACK))
+        .draw(&mut Display {});
+}
+
+unsafe fn draw_char(c: char, column: u32, row: u32) {
+    let style = TextStyleBuilder::new(Font6x8)
+        .text_color(Bgr888::WHITE)
+        .background_color(Bgr888::BLACK)
+        .build();
+    let mut buffer = [0; 4];
+    let position = Point::new((column * FONT_WIDTH) as i32, (row * FONT_HEIGHT) as i32);
+    let _ = Text::new(c.encode_utf8(&mut buffer), position)
+        .into_styled(style)
+        .draw(&mut Display {});
+}
+
+impl Write for &TextConsole {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        TextConsole::write_str(self, string);
+        Ok(())
+    }
+}
+
+impl Sink for TextConsole {
+    fn log(&self, record: &Record) {
+        // keep records logged from interrupt handlers from landing in the middle of this one
+        let saved = exception::local_irq_mask_save();
+        let mut writer = self;
+        let _ = writeln!(writer, "{}", record);
+        exception::local_irq_restore(saved);
+    }
+}
+
 pub unsafe fn _gl_test() -> Result<(), core::convert::Infallible> {
     // TODO make non-public
     fb::fb_init(640, 512, 4, fb::FB_DOUBLEBUFFER);
@@ -132,8 +368,49 @@ pub unsafe fn _gl_test() -> Result<(), core::convert::Infallible> {
 
         fb::fb_swap_buffer();
 
-        cpu::sleep(15000);
+        timer::sleep(Duration::from_millis(20));
+        watchdog::pet();
+    }
+
+    Ok(())
+}
+
+/// Draw with the PS/2 mouse on a black screen while the left button is held, until the right
+/// button is clicked or a byte arrives on the console.
+pub unsafe fn paint() -> Result<(), core::convert::Infallible> {
+    fb::fb_init(640, 512, 4, fb::FB_SINGLEBUFFER);
+    let mut display = Display {};
+    Rectangle::new(Point::new(0, 0), Point::new(639, 511))
+        .into_styled(PrimitiveStyle::with_fill(Bgr888::BLACK))
+        .draw(&mut display)?;
+
+    mouse::init();
+    let brush = PrimitiveStyle::with_fill(Bgr888::YELLOW);
+    let mut cursor = Cursor::new();
+    cursor.move_to(320, 256);
+    cursor.draw();
+
+    while serial::console().try_read().is_none() {
+        watchdog::pet();
+        let event = match mouse::try_read_event() {
+            Some(event) => event,
+            None => continue,
+        };
+        if event.buttons.contains(mouse::Buttons::RIGHT) {
+            break;
+        }
+        cursor.erase();
+        cursor.move_by(event.dx, event.dy);
+        if event.buttons.contains(mouse::Buttons::LEFT) {
+            // below and right of the hotspot, as Display can't take negative coordinates
+            let at = cursor.position();
+            Rectangle::new(at, at + Point::new(2, 2))
+                .into_styled(brush)
+                .draw(&mut display)?;
+        }
+        cursor.draw();
     }
+    cursor.erase();
 
     Ok(())
 }
@@ -1,6 +1,6 @@
 // Author: Xiluo He <xiluohe@stanford.edu>
 
-use crate::cpu;
+use crate::{cpu, interrupts, timer};
 
 const GPIO_BASE: u32 = 0x20200000;
 const GPIO_FSEL0: *mut u32 = GPIO_BASE as *mut u32;
@@ -54,11 +54,12 @@ const GPPUDCLK: *mut u32 = (GPIO_BASE + 0x98) as *mut u32;
 pub unsafe fn set_pud(pin: isize, pud: u32) {
     let gppudclk: *mut u32 = GPPUDCLK.offset(pin / 32);
 
+    // the control signal needs 150 cycles to set up, and as long to be held
     GPPUD.write_volatile(pud);
-    for _x in 0..150 {}
+    timer::delay_us(1);
 
     gppudclk.write_volatile(1 << (pin % 32));
-    for _x in 0..150 {}
+    timer::delay_us(1);
 
     gppudclk.write_volatile(0);
 }
@@ -78,6 +79,43 @@ pub unsafe fn set_pullnone(pin: isize) {
     set_pud(pin, 0);
 }
 
+const GPEDS0: *mut u32 = (GPIO_BASE + 0x40) as *mut u32;
+const GPFEN0: *mut u32 = (GPIO_BASE + 0x58) as *mut u32;
+
+// Event detection: a falling edge on an enabled pin latches its bit in GPEDS, which raises the
+// bank's interrupt (see interrupt_source) until cleared.
+pub unsafe fn enable_falling_edge_event(pin: isize) {
+    cpu::dev_barrier();
+    let fen: *mut u32 = GPFEN0.offset(pin / 32);
+    fen.write_volatile(fen.read_volatile() | (1 << (pin % 32)));
+    cpu::dev_barrier();
+}
+
+pub unsafe fn disable_falling_edge_event(pin: isize) {
+    cpu::dev_barrier();
+    let fen: *mut u32 = GPFEN0.offset(pin / 32);
+    fen.write_volatile(fen.read_volatile() & !(1 << (pin % 32)));
+    cpu::dev_barrier();
+}
+
+pub unsafe fn check_event(pin: isize) -> bool {
+    cpu::dev_barrier();
+    let eds: *mut u32 = GPEDS0.offset(pin / 32);
+    (eds.read_volatile() >> (pin % 32)) & 0b1 == 1
+}
+
+pub unsafe fn clear_event(pin: isize) {
+    cpu::dev_barrier();
+    let eds: *mut u32 = GPEDS0.offset(pin / 32);
+    eds.write_volatile(1 << (pin % 32)); // write 1 to clear
+    cpu::dev_barrier();
+}
+
+// The interrupt controller source raised by events on `pin`
+pub fn interrupt_source(pin: isize) -> u32 {
+    interrupts::INTERRUPTS_GPIO0 + (pin / 32) as u32
+}
+
 #[test_case]
 pub fn test() {
     unsafe {
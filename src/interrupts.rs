@@ -0,0 +1,209 @@
+/*
+ * Driver for the BCM2835 interrupt controller. Handlers are registered
+ * per interrupt source and run in IRQ mode from the IRQ vector.
+ *
+ * Based off of the cs107e libpi interrupts module and the BCM2835 ARM
+ * Peripherals data sheet, chapter 7.
+ */
+
+use crate::{cpu, exception};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+const INTERRUPT_BASE: u32 = 0x2000B200;
+
+// Interrupt sources. 0-63 are the GPU peripheral interrupts reported in IRQ pending 1 and 2,
+// 64-71 the ARM specific interrupts reported in the low byte of IRQ basic pending.
+pub const INTERRUPTS_TIMER1: u32 = 1;
+pub const INTERRUPTS_TIMER3: u32 = 3;
+pub const INTERRUPTS_AUX: u32 = 29;
+pub const INTERRUPTS_GPIO0: u32 = 49;
+pub const INTERRUPTS_GPIO1: u32 = 50;
+pub const INTERRUPTS_GPIO2: u32 = 51;
+pub const INTERRUPTS_GPIO3: u32 = 52;
+pub const INTERRUPTS_UART: u32 = 57;
+pub const INTERRUPTS_BASIC_ARM_TIMER: u32 = 64;
+pub const INTERRUPTS_BASIC_ARM_MAILBOX: u32 = 65;
+pub const INTERRUPTS_BASIC_ARM_DOORBELL_0: u32 = 66;
+pub const INTERRUPTS_BASIC_ARM_DOORBELL_1: u32 = 67;
+pub const INTERRUPTS_BASIC_GPU_0_HALTED: u32 = 68;
+pub const INTERRUPTS_BASIC_GPU_1_HALTED: u32 = 69;
+pub const INTERRUPTS_BASIC_ACCESS_ERROR_1: u32 = 70;
+pub const INTERRUPTS_BASIC_ACCESS_ERROR_0: u32 = 71;
+
+const INTERRUPTS_COUNT: usize = 72;
+
+#[repr(C)]
+struct InterruptController {
+    irq_basic_pending: u32,
+    irq_pending: [u32; 2],
+    fiq_control: u32,
+    enable_irqs: [u32; 2],
+    enable_basic_irqs: u32,
+    disable_irqs: [u32; 2],
+    disable_basic_irqs: u32,
+}
+
+static mut CONTROLLER: *mut InterruptController = INTERRUPT_BASE as *mut InterruptController;
+
+type Handler = Box<dyn FnMut()>;
+
+// A source may be shared by several drivers (e.g. all GPIO pins of a bank raise the same
+// interrupt), so each one keeps a list of handlers that are all run when it fires.
+const NO_HANDLERS: Vec<Handler> = Vec::new();
+static mut HANDLERS: [Vec<Handler>; INTERRUPTS_COUNT] = [NO_HANDLERS; INTERRUPTS_COUNT];
+
+// Shadow copies of the enable registers: pending 1, pending 2 and basic.
+static mut ENABLED: [u32; 3] = [0; 3];
+
+fn check_source(source: u32) {
+    assert!(
+        (source as usize) < INTERRUPTS_COUNT,
+        "invalid interrupt source {}",
+        source
+    );
+}
+
+/// Disable every interrupt source and forget all registered handlers.
+///
+/// Must be called before `global_enable()`.
+pub unsafe fn init() {
+    exception::local_irq_mask();
+    cpu::dev_barrier();
+    core::ptr::write_volatile(&mut (*CONTROLLER).disable_irqs[0], !0_u32);
+    core::ptr::write_volatile(&mut (*CONTROLLER).disable_irqs[1], !0_u32);
+    core::ptr::write_volatile(&mut (*CONTROLLER).disable_basic_irqs, !0_u32);
+    core::ptr::write_volatile(&mut (*CONTROLLER).fiq_control, 0_u32);
+    cpu::dev_barrier();
+
+    ENABLED = [0; 3];
+    for handlers in HANDLERS.iter_mut() {
+        handlers.clear();
+    }
+}
+
+/// Unmask IRQs on the core. Sources still have to be enabled individually.
+pub fn global_enable() {
+    exception::local_irq_unmask();
+}
+
+/// Mask IRQs on the core without touching the enabled sources.
+pub fn global_disable() {
+    exception::local_irq_mask();
+}
+
+/// Register `handler` to be called in IRQ mode whenever `source` fires.
+///
+/// Handlers of a source are run in registration order. They must clear the condition that
+/// raised the interrupt in their peripheral, otherwise it fires again as soon as they return.
+/// Registering does not enable the source, see `enable_source()`.
+pub unsafe fn register_handler<F>(source: u32, handler: F)
+where
+    F: FnMut() + 'static,
+{
+    check_source(source);
+    let handler: Handler = Box::new(handler);
+
+    // dispatch() takes the handler list out and puts it back, so it must not run while the list
+    // changes. From a handler, this adds to the empty list left in its place.
+    let saved = exception::local_irq_mask_save();
+    HANDLERS[source as usize].push(handler);
+    exception::local_irq_restore(saved);
+}
+
+pub unsafe fn enable_source(source: u32) {
+    check_source(source);
+    let bank = (source / 32) as usize;
+    let bit = 1 << (source % 32);
+
+    cpu::dev_barrier();
+    match bank {
+        0 | 1 => core::ptr::write_volatile(&mut (*CONTROLLER).enable_irqs[bank], bit),
+        _ => core::ptr::write_volatile(&mut (*CONTROLLER).enable_basic_irqs, bit),
+    }
+    cpu::dev_barrier();
+
+    let saved = exception::local_irq_mask_save();
+    ENABLED[bank] |= bit;
+    exception::local_irq_restore(saved);
+}
+
+pub unsafe fn disable_source(source: u32) {
+    check_source(source);
+    let bank = (source / 32) as usize;
+    let bit = 1 << (source % 32);
+
+    cpu::dev_barrier();
+    match bank {
+        0 | 1 => core::ptr::write_volatile(&mut (*CONTROLLER).disable_irqs[bank], bit),
+        _ => core::ptr::write_volatile(&mut (*CONTROLLER).disable_basic_irqs, bit),
+    }
+    cpu::dev_barrier();
+
+    let saved = exception::local_irq_mask_save();
+    ENABLED[bank] &= !bit;
+    exception::local_irq_restore(saved);
+}
+
+unsafe fn handle(source: u32) {
+    let source = source as usize;
+    if HANDLERS[source].is_empty() {
+        // nobody can acknowledge it, so keep it from firing forever
+        disable_source(source as u32);
+        return;
+    }
+    // The list is taken out while the handlers run, so that one may register another handler
+    // for the same source. Those are added after the ones that ran.
+    let mut handlers = core::mem::take(&mut HANDLERS[source]);
+    for handler in handlers.iter_mut() {
+        handler();
+    }
+    handlers.append(&mut HANDLERS[source]);
+    HANDLERS[source] = handlers;
+}
+
+/// Run the handlers of every pending source. Called from the IRQ vector.
+pub unsafe fn dispatch() {
+    cpu::dev_barrier();
+    let pending: [u32; 3] = [
+        core::ptr::read_volatile(&(*CONTROLLER).irq_pending[0]),
+        core::ptr::read_volatile(&(*CONTROLLER).irq_pending[1]),
+        core::ptr::read_volatile(&(*CONTROLLER).irq_basic_pending) & 0xff,
+    ];
+    cpu::dev_barrier();
+
+    for (bank, bits) in pending.iter().enumerate() {
+        let mut bits = bits & ENABLED[bank];
+        while bits != 0 {
+            let bit = bits.trailing_zeros();
+            handle(bank as u32 * 32 + bit);
+            bits &= !(1 << bit);
+        }
+    }
+}
+
+#[test_case]
+fn test_register_from_handler() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static FIRST: AtomicU32 = AtomicU32::new(0);
+    static SECOND: AtomicU32 = AtomicU32::new(0);
+    // nothing else uses the doorbell, and handle() is called here rather than by an interrupt
+    let source = INTERRUPTS_BASIC_ARM_DOORBELL_0;
+    unsafe {
+        let saved = exception::local_irq_mask_save();
+        register_handler(source, move || {
+            if FIRST.fetch_add(1, Ordering::Relaxed) == 0 {
+                register_handler(source, || {
+                    SECOND.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        handle(source);
+        handle(source);
+        HANDLERS[source as usize].clear();
+        exception::local_irq_restore(saved);
+    }
+    assert_eq!(FIRST.load(Ordering::Relaxed), 2);
+    assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+}
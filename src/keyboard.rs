@@ -1,348 +1,368 @@
 // Author: Xiluo He <xiluohe@stanford.edu>
 
-use crate::gpio;
-use crate::timer;
+use crate::exception;
+use crate::ps2::{Ps2DeviceT, Ps2Error, PS2_SELF_TEST_PASSED};
+use crate::timer::{Duration, Instant};
 use crate::uart;
+use crate::{info, warn};
 
-pub struct Ps2DeviceT {
-    clock: u32,
-    data: u32,
+pub mod layout;
+mod scancode;
+
+use layout::Layout;
+use scancode::{extended_key, SequenceDecoder};
+pub use scancode::{Key, KeyAction, KeyEvent, Modifiers};
+
+static mut dev: Ps2DeviceT = Ps2DeviceT::new(3, 4);
+
+// Commands understood by the keyboard, see set_leds() etc. for their arguments
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+const KEYBOARD_SCANCODE_SET: u8 = 0xF0;
+const KEYBOARD_SET_TYPEMATIC: u8 = 0xF3;
+const KEYBOARD_RESET: u8 = 0xFF;
+
+// The self test after a reset takes 500-750ms
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long read_scancode(), read_sequence() and read_event() wait for a key
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Readers decode the queued scancodes with READER_DECODER. The interrupt handler runs its own
+// decoder over the same stream to keep KEY_STATE current whether or not anyone reads events.
+static mut READER_DECODER: SequenceDecoder = SequenceDecoder::new();
+static mut IRQ_DECODER: SequenceDecoder = SequenceDecoder::new();
+
+// One bit per keycode, set while the key is held down. Extended keycodes start at bit 256.
+static mut KEY_STATE: [u32; 16] = [0; 16];
+
+// Modifiers held and locks toggled as seen by readers, i.e. as of the last event read
+static mut MODIFIERS: Modifiers = Modifiers::NONE;
+
+// Lock keys currently held, so that typematic repeats do not toggle them again
+static mut LOCK_KEYS_DOWN: Modifiers = Modifiers::NONE;
+
+// Set when a lock changes. Lighting the LEDs is a round trip to the keyboard of up to tens of
+// ms, so try_read_event() does it once the line is idle rather than while keys come in.
+static mut LEDS_PENDING: bool = false;
+
+// The layout at boot is US unless a layout feature picks another one
+#[cfg(feature = "layout_azerty")]
+const DEFAULT_LAYOUT: &Layout = &layout::AZERTY;
+#[cfg(all(feature = "layout_dvorak", not(feature = "layout_azerty")))]
+const DEFAULT_LAYOUT: &Layout = &layout::DVORAK;
+#[cfg(not(any(feature = "layout_azerty", feature = "layout_dvorak")))]
+const DEFAULT_LAYOUT: &Layout = &layout::US;
+#[cfg(all(feature = "layout_azerty", feature = "layout_dvorak"))]
+compile_error!("pick one of the features layout_azerty and layout_dvorak");
+
+static mut LAYOUT: &Layout = DEFAULT_LAYOUT;
+
+// The accent of a dead key typed, waiting for the next character
+static mut DEAD_KEY: Option<char> = None;
+
+// An event held back by compose_event(), to be read next
+static mut PENDING_EVENT: Option<KeyEvent> = None;
+
+static mut INITIALIZED: bool = false;
+
+/// Start receiving from the keyboard and reset it.
+///
+/// A missing keyboard or one that fails its self test does not stop the boot; reset() can be
+/// called again later.
+pub unsafe fn init() {
+    if INITIALIZED {
+        return;
+    }
+    dev.init(Some(on_scancode));
+    INITIALIZED = true;
+    match reset() {
+        Ok(()) => info!("keyboard ready, {} layout", LAYOUT.name),
+        Err(error) => warn!("keyboard reset failed: {:?}", error),
+    }
+}
+
+// Called in IRQ mode with every scancode received
+fn on_scancode(scancode: u8) {
+    unsafe {
+        if let Some(action) = IRQ_DECODER.feed(scancode) {
+            update_key_state(action);
+        }
+    }
+}
+
+/// Reset the keyboard and wait for its self test to pass.
+///
+/// Afterwards no keys are held, all locks are off and the keyboard uses its default typematic
+/// rate and scancode set 2.
+pub unsafe fn reset() -> Result<(), Ps2Error> {
+    dev.command(&[KEYBOARD_RESET])?;
+    let result = match dev.read(SELF_TEST_TIMEOUT) {
+        Some(PS2_SELF_TEST_PASSED) => Ok(()),
+        Some(response) => Err(Ps2Error::Response(response)),
+        None => Err(Ps2Error::Timeout),
+    };
+
+    let saved = exception::local_irq_mask_save();
+    while dev.try_read().is_some() {}
+    READER_DECODER = SequenceDecoder::new();
+    IRQ_DECODER = SequenceDecoder::new();
+    KEY_STATE = [0; 16];
+    MODIFIERS = Modifiers::NONE;
+    LOCK_KEYS_DOWN = Modifiers::NONE;
+    LEDS_PENDING = false;
+    exception::local_irq_restore(saved);
+    result
 }
 
-enum Ps2Codes {
-    PS2_KEY_NONE = 0,
-    PS2_CODE_RELEASE = 0xF0,
-    PS2_CODE_EXTENDED = 0xE0,
-    PS2_KEY_SHIFT = 0x90,
-    PS2_KEY_ALT, // values assigned in increasing sequence from here
-    PS2_KEY_CTRL,
-    PS2_KEY_CAPS_LOCK,
-    PS2_KEY_ENTER,
-    PS2_KEY_ESC,
-    PS2_KEY_F1,
-    PS2_KEY_F2,
-    PS2_KEY_F3,
-    PS2_KEY_F4,
-    PS2_KEY_F5,
-    PS2_KEY_F6,
-    PS2_KEY_F7,
-    PS2_KEY_F8,
-    PS2_KEY_F9,
-    PS2_KEY_F10,
-    PS2_KEY_F11,
-    PS2_KEY_F12,
-    PS2_KEY_NUM_LOCK,
-    PS2_KEY_HOME,
-    PS2_KEY_PAGE_UP,
-    PS2_KEY_PAGE_DOWN,
-    PS2_KEY_INSERT,
-    PS2_KEY_DELETE,
-    PS2_KEY_END,
-    PS2_KEY_SCROLL_LOCK,
-    PS2_KEY_ARROW_UP,
-    PS2_KEY_ARROW_DOWN,
-    PS2_KEY_ARROW_LEFT,
-    PS2_KEY_ARROW_RIGHT,
+/// Light the caps, num and scroll lock LEDs of the locks set in `modifiers`.
+///
+/// Readers of key events do not need to call this: the LEDs follow the lock state as lock keys
+/// are read, once `try_read_event()` finds the keyboard idle.
+pub unsafe fn set_leds(modifiers: Modifiers) -> Result<(), Ps2Error> {
+    let mut leds = 0;
+    if modifiers.scroll_lock() {
+        leds |= 1 << 0;
+    }
+    if modifiers.num_lock() {
+        leds |= 1 << 1;
+    }
+    if modifiers.caps_lock() {
+        leds |= 1 << 2;
+    }
+    dev.command(&[KEYBOARD_SET_LEDS, leds])
 }
 
-const ps2_keys: &[char] = &[
-    /* scan code */
-    /* 00 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 01 */ { Ps2Codes::PS2_KEY_F9 as u8 as char },
-    /* 02 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 03 */ { Ps2Codes::PS2_KEY_F5 as u8 as char },
-    /* 04 */ { Ps2Codes::PS2_KEY_F3 as u8 as char },
-    /* 05 */ { Ps2Codes::PS2_KEY_F1 as u8 as char },
-    /* 06 */ { Ps2Codes::PS2_KEY_F2 as u8 as char },
-    /* 07 */ { Ps2Codes::PS2_KEY_F12 as u8 as char },
-    /* 08 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 09 */ { Ps2Codes::PS2_KEY_F10 as u8 as char },
-    /* 0A */ { Ps2Codes::PS2_KEY_F8 as u8 as char },
-    /* 0B */ { Ps2Codes::PS2_KEY_F6 as u8 as char },
-    /* 0C */ { Ps2Codes::PS2_KEY_F4 as u8 as char },
-    /* 0D */ { '\t' },
-    /* 0E */ { '`' },
-    /* 0F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 10 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 11 */ { Ps2Codes::PS2_KEY_ALT as u8 as char },
-    /* 12 */ { Ps2Codes::PS2_KEY_SHIFT as u8 as char },
-    /* 13 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 14 */ { Ps2Codes::PS2_KEY_CTRL as u8 as char },
-    /* 15 */ { 'q' },
-    /* 16 */ { '1' },
-    /* 17 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 18 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 19 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 1A */ { 'z' },
-    /* 1B */ { 's' },
-    /* 1C */ { 'a' },
-    /* 1D */ { 'w' },
-    /* 1E */ { '2' },
-    /* 1F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 20 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 21 */ { 'c' },
-    /* 22 */ { 'x' },
-    /* 23 */ { 'd' },
-    /* 24 */ { 'e' },
-    /* 25 */ { '4' },
-    /* 26 */ { '3' },
-    /* 27 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 28 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 29 */ { ' ' },
-    /* 2A */ { 'v' },
-    /* 2B */ { 'f' },
-    /* 2C */ { 't' },
-    /* 2D */ { 'r' },
-    /* 2E */ { '5' },
-    /* 2F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 30 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 31 */ { 'n' },
-    /* 32 */ { 'b' },
-    /* 33 */ { 'h' },
-    /* 34 */ { 'g' },
-    /* 35 */ { 'y' },
-    /* 36 */ { '6' },
-    /* 37 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 38 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 39 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 3A */ { 'm' },
-    /* 3B */ { 'j' },
-    /* 3C */ { 'u' },
-    /* 3D */ { '7' },
-    /* 3E */ { '8' },
-    /* 3F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 40 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 41 */ { ',' },
-    /* 42 */ { 'k' },
-    /* 43 */ { 'i' },
-    /* 44 */ { 'o' },
-    /* 45 */ { '0' },
-    /* 46 */ { '9' },
-    /* 47 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 48 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 49 */ { '.' },
-    /* 4A */ { '/' },
-    /* 4B */ { 'l' },
-    /* 4C */ { ';' },
-    /* 4D */ { 'p' },
-    /* 4E */ { '-' },
-    /* 4F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 50 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 51 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 52 */ { '\'' },
-    /* 53 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 54 */ { '[' },
-    /* 55 */ { '=' },
-    /* 56 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 57 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 58 */ { Ps2Codes::PS2_KEY_CAPS_LOCK as u8 as char },
-    /* 59 */ { Ps2Codes::PS2_KEY_SHIFT as u8 as char },
-    /* 5A */ { '\n' },
-    /* 5B */ { ']' },
-    /* 5C */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 5D */ { '\\' },
-    /* 5E */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 5F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 60 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 61 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 62 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 63 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 64 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 65 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 66 */ { '' },
-    /* 67 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 68 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 69 */ { Ps2Codes::PS2_KEY_END as u8 as char },
-    /* 6A */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 6B */ { Ps2Codes::PS2_KEY_ARROW_LEFT as u8 as char },
-    /* 6C */ { Ps2Codes::PS2_KEY_HOME as u8 as char },
-    /* 6D */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 6E */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 6F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 70 */ { Ps2Codes::PS2_KEY_INSERT as u8 as char },
-    /* 71 */ { Ps2Codes::PS2_KEY_DELETE as u8 as char },
-    /* 72 */ { Ps2Codes::PS2_KEY_ARROW_DOWN as u8 as char },
-    /* 73 */ { '5' },
-    /* 74 */ { Ps2Codes::PS2_KEY_ARROW_RIGHT as u8 as char },
-    /* 75 */ { Ps2Codes::PS2_KEY_ARROW_UP as u8 as char },
-    /* 76 */ { Ps2Codes::PS2_KEY_ESC as u8 as char },
-    /* 77 */ { Ps2Codes::PS2_KEY_NUM_LOCK as u8 as char },
-    /* 78 */ { Ps2Codes::PS2_KEY_F11 as u8 as char },
-    /* 79 */ { '+' },
-    /* 7A */ { Ps2Codes::PS2_KEY_PAGE_DOWN as u8 as char },
-    /* 7B */ { '-' },
-    /* 7C */ { '*' },
-    /* 7D */ { Ps2Codes::PS2_KEY_PAGE_UP as u8 as char },
-    /* 7E */ { Ps2Codes::PS2_KEY_SCROLL_LOCK as u8 as char },
-    /* 7F */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 80 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 81 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 82 */ { Ps2Codes::PS2_KEY_NONE as u8 as char },
-    /* 83 */ { Ps2Codes::PS2_KEY_F7 as u8 as char },
-];
-
-#[derive(Copy, Clone)]
-pub struct KeyActionT {
-    what: u32,
-    keycode: u32,
+// Returns the typematic byte for the repeat rate nearest to `per_second` (2-30) and the delay
+// nearest to `delay_ms` (250-1000). The keyboard repeats every (8 + A) * 2^B * 4.17ms, with A
+// in bits 0-2 and B in bits 3-4, after waiting (1 + D) * 250ms, with D in bits 5-6.
+fn typematic_byte(delay_ms: u32, per_second: u32) -> u8 {
+    let delay = (delay_ms.max(250).min(1000) - 250 + 125) / 250;
+    let per_second = per_second.max(2).min(30);
+    let distance = |rate: u32| {
+        let period = (8 + (rate & 0b111)) << (rate >> 3);
+        (period * per_second).max(240) - (period * per_second).min(240)
+    };
+    let rate = (0..32).min_by_key(|&rate| distance(rate)).unwrap();
+    ((delay << 5) | rate) as u8
 }
 
-pub struct KeyEventT {
-    action: KeyActionT,
-    key: char,
+/// Set how long a key has to be held before it repeats, and how often it then repeats.
+///
+/// The keyboard supports delays of 250, 500, 750 and 1000ms and rates from 2 to 30 repeats per
+/// second; the nearest supported values are used.
+pub unsafe fn set_typematic(delay_ms: u32, per_second: u32) -> Result<(), Ps2Error> {
+    dev.command(&[KEYBOARD_SET_TYPEMATIC, typematic_byte(delay_ms, per_second)])
 }
 
-static mut dev: Ps2DeviceT = Ps2DeviceT { clock: 3, data: 4 };
+/// Switch the keyboard to scancode set 1, 2 or 3.
+///
+/// Only set 2, the default, is decoded into key events; the others are for readers of raw
+/// scancodes.
+pub unsafe fn set_scancode_set(set: u8) -> Result<(), Ps2Error> {
+    assert!((1..=3).contains(&set), "invalid scancode set {}", set);
+    dev.command(&[KEYBOARD_SCANCODE_SET, set])
+}
 
-pub unsafe fn init() {
-    /*
-        dev = Ps2DeviceT {
-            clock: clock_gpio as u32,
-            data: data_gpio as u32,
-        };
-    */
-    gpio::set_input(dev.clock as isize);
-    gpio::set_pullup(dev.clock as isize);
+// Waits up to READ_TIMEOUT for the next scancode
+pub unsafe fn read_scancode() -> Option<u8> {
+    dev.read(READ_TIMEOUT)
+}
 
-    gpio::set_input(dev.data as isize);
-    gpio::set_pullup(dev.data as isize);
+fn key_state_bit(keycode: u8, extended: bool) -> (usize, u32) {
+    let index = keycode as usize + if extended { 256 } else { 0 };
+    (index / 32, 1 << (index % 32))
+}
+
+unsafe fn update_key_state(action: KeyAction) {
+    let (word, bit) = key_state_bit(action.keycode, action.extended);
+    if action.pressed {
+        KEY_STATE[word] |= bit;
+    } else {
+        KEY_STATE[word] &= !bit;
+    }
 }
 
-static mut timeout: u32 = 0;
+/// Returns whether `key` is currently held down. `Key::Char` matches any key that types the
+/// character in the current layout, with or without shift, AltGr or num lock, so
+/// is_pressed(Key::Char('A')) is true while the A key is down and is_pressed(Key::Char('5'))
+/// for both the main and the keypad 5.
+///
+/// The state is maintained by the interrupt handler, so it is current even if no events are
+/// being read.
+pub fn is_pressed(key: Key) -> bool {
+    let down = |keycode: u8, extended: bool| {
+        let (word, bit) = key_state_bit(keycode, extended);
+        unsafe { KEY_STATE[word] & bit != 0 }
+    };
+    let types = |keycode: u8| {
+        let base = KeyAction {
+            keycode,
+            extended: false,
+            pressed: true,
+        };
+        let layout = layout();
+        base.key(layout) == Some(key)
+            || base.key_with(layout, Modifiers::LEFT_SHIFT) == Some(key)
+            || base.key_with(layout, Modifiers::RIGHT_ALT) == Some(key)
+            || base.key_with(layout, Modifiers::NUM_LOCK) == Some(key)
+    };
+    (0..=0xff_u8).any(|keycode| {
+        (types(keycode) && down(keycode, false))
+            || (extended_key(keycode) == Some(key) && down(keycode, true))
+    })
+}
 
-unsafe fn wait_for_falling_clock_edge() {
-    let start: u32 = timer::get_ticks();
-    while gpio::read(dev.clock as u8 as isize) == 0 {
-        if timer::get_ticks() - start > 100000 {
-            timeout = 1;
-            break;
+// Returns the next complete make/break sequence without waiting, if one has been received
+pub unsafe fn try_read_sequence() -> Option<KeyAction> {
+    while let Some(scancode) = dev.try_read() {
+        if let Some(action) = READER_DECODER.feed(scancode) {
+            return Some(action);
         }
     }
-    while gpio::read(dev.clock as u8 as isize) == 1 {
-        if timer::get_ticks() - start > 100000 {
-            timeout = 1;
-            break;
-        } else if timeout == 1 {
-            break;
+    None
+}
+
+// Waits up to READ_TIMEOUT for the next make/break sequence
+pub unsafe fn read_sequence() -> Option<KeyAction> {
+    let deadline = Instant::now() + READ_TIMEOUT;
+    loop {
+        if let Some(action) = try_read_sequence() {
+            return Some(action);
+        }
+        if Instant::now() >= deadline {
+            return None;
         }
     }
 }
 
-unsafe fn read_bit() -> u32 {
-    wait_for_falling_clock_edge();
-    if timeout == 1 {
-        return 1;
+// Turns an action into an event, tracking modifiers and locks. None for keys we do not know, or
+// that type nothing with the modifiers held.
+unsafe fn to_event(action: KeyAction) -> Option<KeyEvent> {
+    if let Some(modifier) = action.modifier() {
+        MODIFIERS.set(modifier, action.pressed);
+    }
+    if let Some(lock) = action.lock() {
+        if action.pressed && !LOCK_KEYS_DOWN.contains(lock) {
+            MODIFIERS.toggle(lock);
+            LEDS_PENDING = true;
+        }
+        LOCK_KEYS_DOWN.set(lock, action.pressed);
     }
-    return gpio::read(dev.data as u8 as isize);
+    Some(KeyEvent {
+        key: action.key_with(LAYOUT, MODIFIERS)?,
+        pressed: action.pressed,
+        modifiers: MODIFIERS,
+    })
 }
 
-pub unsafe fn read_scancode() -> u32 {
-    let mut scancode: u32 = 0;
-    let mut paritycheck: u32 = 0;
-    loop {
-        while read_bit() == 1 {
-            if timeout == 1 {
-                return 0;
+// Applies a pending dead key to the next character typed. The dead key itself is reported as
+// Key::Dead. If the character does not compose with it, the accent is typed on its own first
+// and the character held back for the next read.
+unsafe fn compose_event(event: KeyEvent) -> KeyEvent {
+    if !event.pressed {
+        return event;
+    }
+    let accent = match DEAD_KEY {
+        Some(accent) => accent,
+        None => {
+            if let Key::Dead(accent) = event.key {
+                DEAD_KEY = Some(accent);
             }
+            return event;
         }
-
-        for i in 0..8 {
-            let bit: u32 = read_bit();
-            scancode |= bit << i;
-            paritycheck += bit;
+    };
+    let key = match event.key {
+        Key::Char(' ') => Key::Char(accent),
+        Key::Char(c) => match layout::compose(accent, c) {
+            Some(composed) => Key::Char(composed),
+            None => {
+                PENDING_EVENT = Some(event);
+                Key::Char(accent)
+            }
+        },
+        Key::Dead(second) if second == accent => Key::Char(accent),
+        Key::Dead(second) => {
+            DEAD_KEY = Some(second);
+            return KeyEvent {
+                key: Key::Char(accent),
+                ..event
+            };
         }
+        _ => return event, // e.g. shift, for a capital
+    };
+    DEAD_KEY = None;
+    KeyEvent { key, ..event }
+}
 
-        let paritybit: u32 = read_bit();
-        paritycheck += paritybit;
-        let stopbit: u32 = read_bit();
+/// The modifiers held and locks toggled as of the last event read.
+pub fn modifiers() -> Modifiers {
+    unsafe { MODIFIERS }
+}
 
-        if paritycheck % 2 != 1 || stopbit != 1 {
-            scancode = 0;
-            paritycheck = 0;
-            continue;
-        }
-        break;
+/// Select the layout the typing keys are decoded with, e.g. `layout::find("dvorak")`.
+pub fn set_layout(layout: &'static Layout) {
+    unsafe {
+        LAYOUT = layout;
+        DEAD_KEY = None;
     }
-    return scancode;
+    info!("keyboard layout {}", layout.name);
 }
 
-pub unsafe fn read_sequence() -> KeyActionT {
-    let mut action = KeyActionT {
-        what: 0,
-        keycode: 0,
-    };
+/// The layout the typing keys are decoded with.
+pub fn layout() -> &'static Layout {
+    unsafe { LAYOUT }
+}
 
-    let mut keycode: u32 = read_scancode();
-    if timeout == 1 {
-        return action;
+/// Returns the next key event without waiting, or None if no complete event has arrived.
+pub unsafe fn try_read_event() -> Option<KeyEvent> {
+    if let Some(event) = PENDING_EVENT.take() {
+        return Some(event);
     }
-
-    if keycode == 0xE0 {
-        keycode = read_scancode();
+    while let Some(action) = try_read_sequence() {
+        if let Some(event) = to_event(action) {
+            return Some(compose_event(event));
+        }
     }
-
-    if keycode == 0xF0 {
-        action.what = 1;
-        action.keycode = read_scancode();
-    } else {
-        action.what = 0;
-        action.keycode = keycode;
+    if LEDS_PENDING && dev.is_idle() {
+        LEDS_PENDING = false;
+        let _ = set_leds(MODIFIERS); // the LEDs are cosmetic, the lock state is ours
     }
-    return action;
+    None
 }
 
-pub unsafe fn read_event() -> KeyEventT {
-    let temp = KeyActionT {
-        what: 0,
-        keycode: 0,
-    };
-
-    let mut event = KeyEventT {
-        action: temp,
-        key: '\0',
-    };
-
+// Waits up to READ_TIMEOUT for the next key event
+pub unsafe fn read_event() -> Option<KeyEvent> {
+    let deadline = Instant::now() + READ_TIMEOUT;
     loop {
-        let mut action: KeyActionT = read_sequence();
-        if timeout == 1 {
-            return event;
+        if let Some(event) = try_read_event() {
+            return Some(event);
         }
-
-        if action.keycode != 0x12
-            && action.keycode != 0x59
-            && action.keycode != 0x11
-            && action.keycode != 0x14
-            && action.keycode != 0x58
-            && action.keycode != 0x7e
-            && action.keycode != 0x77
-        {
-            event.action = action;
-            event.key = ps2_keys[action.keycode as usize];
-            break;
+        if Instant::now() >= deadline {
+            return None;
         }
     }
-
-    return event;
 }
 
+// Returns the character of the next key press, skipping keys that do not type one.
+// Returns '\0' if no key is pressed for 100ms.
 pub unsafe fn read_next() -> char {
-    let mut keyevent: KeyEventT = read_event();
-    if timeout == 1 {
-        timeout = 0;
-    }
     loop {
-        if keyevent.action.what == 0 {
-            break;
+        match read_event() {
+            None => return '\0',
+            Some(event) if event.pressed => {
+                if let Some(c) = event.key.to_char() {
+                    return c;
+                }
+            }
+            Some(_) => {}
         }
-        keyevent = read_event();
     }
-
-    return keyevent.key;
 }
 
 #[test_case]
 pub fn test() {
     unsafe {
+        init();
         let mut inputchar: char = read_next();
         while inputchar != '`' {
             uart::put_u8(inputchar as u8);
@@ -350,3 +370,117 @@ pub fn test() {
         }
     }
 }
+
+#[test_case]
+fn test_key_with_modifiers() {
+    let a = KeyAction {
+        keycode: 0x1C,
+        extended: false,
+        pressed: true,
+    };
+    let one = KeyAction { keycode: 0x16, ..a };
+    let keypad_one = KeyAction { keycode: 0x69, ..a };
+
+    assert_eq!(
+        a.key_with(&layout::US, Modifiers::NONE),
+        Some(Key::Char('a'))
+    );
+    assert_eq!(
+        a.key_with(&layout::US, Modifiers::RIGHT_SHIFT),
+        Some(Key::Char('A'))
+    );
+    assert_eq!(
+        a.key_with(&layout::US, Modifiers::CAPS_LOCK),
+        Some(Key::Char('A'))
+    );
+    let shift_and_caps = Modifiers::LEFT_SHIFT | Modifiers::CAPS_LOCK;
+    assert_eq!(
+        a.key_with(&layout::US, shift_and_caps),
+        Some(Key::Char('a'))
+    );
+
+    assert_eq!(
+        one.key_with(&layout::US, Modifiers::CAPS_LOCK),
+        Some(Key::Char('1'))
+    );
+    assert_eq!(
+        one.key_with(&layout::US, Modifiers::LEFT_SHIFT),
+        Some(Key::Char('!'))
+    );
+
+    assert_eq!(
+        keypad_one.key_with(&layout::US, Modifiers::NONE),
+        Some(Key::End)
+    );
+    assert_eq!(
+        keypad_one.key_with(&layout::US, Modifiers::NUM_LOCK),
+        Some(Key::Char('1'))
+    );
+    let extended_end = KeyAction {
+        extended: true,
+        ..keypad_one
+    };
+    assert_eq!(
+        extended_end.key_with(&layout::US, Modifiers::NUM_LOCK),
+        Some(Key::End)
+    );
+}
+
+#[test_case]
+fn test_modifier_keys() {
+    let left_shift = KeyAction {
+        keycode: 0x12,
+        extended: false,
+        pressed: true,
+    };
+    let right_alt = KeyAction {
+        keycode: 0x11,
+        extended: true,
+        ..left_shift
+    };
+    let caps_lock = KeyAction {
+        keycode: 0x58,
+        ..left_shift
+    };
+    let a = KeyAction {
+        keycode: 0x1C,
+        ..left_shift
+    };
+
+    assert_eq!(left_shift.modifier(), Some(Modifiers::LEFT_SHIFT));
+    assert_eq!(right_alt.modifier(), Some(Modifiers::RIGHT_ALT));
+    assert_eq!(caps_lock.modifier(), None);
+    assert_eq!(caps_lock.lock(), Some(Modifiers::CAPS_LOCK));
+    assert_eq!(a.modifier(), None);
+    assert_eq!(a.lock(), None);
+}
+
+#[test_case]
+fn test_typematic_byte() {
+    assert_eq!(typematic_byte(250, 30), 0x00);
+    assert_eq!(typematic_byte(1000, 2), 0x7F);
+    assert_eq!(typematic_byte(500, 10), 0x2C);
+    assert_eq!(typematic_byte(0, 100), 0x00);
+    assert_eq!(typematic_byte(700, 0), 0x5F);
+}
+
+#[test_case]
+fn test_dead_keys() {
+    let press = |key| KeyEvent {
+        key,
+        pressed: true,
+        modifiers: Modifiers::NONE,
+    };
+    unsafe {
+        assert_eq!(compose_event(press(Key::Dead('^'))).key, Key::Dead('^'));
+        assert_eq!(compose_event(press(Key::Char('e'))).key, Key::Char('ê'));
+
+        compose_event(press(Key::Dead('¨')));
+        assert_eq!(compose_event(press(Key::Char('x'))).key, Key::Char('¨'));
+        assert_eq!(PENDING_EVENT.take(), Some(press(Key::Char('x'))));
+
+        compose_event(press(Key::Dead('^')));
+        assert_eq!(compose_event(press(Key::Char(' '))).key, Key::Char('^'));
+        assert_eq!(DEAD_KEY, None);
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ * Keyboard layouts: the characters typed by the keys of the main block.
+ *
+ * The typing keys are listed row by row in TYPING_KEYS, and a layout gives
+ * the characters of each row as a string, one character per key, for every
+ * layer: without modifiers, with shift and with AltGr. A space marks a key
+ * that types nothing in that layer. Dead keys are written as the Unicode
+ * combining mark of their accent (e.g. '\u{302}' for the circumflex) and
+ * compose with the next character typed, see DEAD_KEYS.
+ */
+
+use super::scancode::{Key, Modifiers};
+
+// Keycodes of the typing keys in scancode set 2, by row: the number row, the top, home and
+// bottom letter rows. 0x5D is the key above Enter on ANSI keyboards and left of it on ISO
+// ones; 0x61 is the extra key right of left shift that ISO keyboards have.
+const TYPING_KEYS: [&[u8]; 4] = [
+    &[
+        0x0E, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46, 0x45, 0x4E, 0x55,
+    ],
+    &[
+        0x15, 0x1D, 0x24, 0x2D, 0x2C, 0x35, 0x3C, 0x43, 0x44, 0x4D, 0x54, 0x5B, 0x5D,
+    ],
+    &[
+        0x1C, 0x1B, 0x23, 0x2B, 0x34, 0x33, 0x3B, 0x42, 0x4B, 0x4C, 0x52,
+    ],
+    &[
+        0x61, 0x1A, 0x22, 0x21, 0x2A, 0x32, 0x31, 0x3A, 0x41, 0x49, 0x4A,
+    ],
+];
+
+/// A dead key: typing it and then one of `bases` types the character at the same position in
+/// `composed`.
+pub struct DeadKey {
+    /// How the dead key is written in layout rows.
+    pub mark: char,
+    /// The accent on its own, typed by the dead key followed by space.
+    pub accent: char,
+    pub bases: &'static str,
+    pub composed: &'static str,
+}
+
+pub const DEAD_KEYS: [DeadKey; 5] = [
+    DeadKey {
+        mark: '\u{300}',
+        accent: '`',
+        bases: "aeiouAEIOU",
+        composed: "àèìòùÀÈÌÒÙ",
+    },
+    DeadKey {
+        mark: '\u{301}',
+        accent: '´',
+        bases: "aeiouyAEIOUY",
+        composed: "áéíóúýÁÉÍÓÚÝ",
+    },
+    DeadKey {
+        mark: '\u{302}',
+        accent: '^',
+        bases: "aeiouAEIOU",
+        composed: "âêîôûÂÊÎÔÛ",
+    },
+    DeadKey {
+        mark: '\u{303}',
+        accent: '~',
+        bases: "anoANO",
+        composed: "ãñõÃÑÕ",
+    },
+    DeadKey {
+        mark: '\u{308}',
+        accent: '¨',
+        bases: "aeiouyAEIOU",
+        composed: "äëïöüÿÄËÏÖÜ",
+    },
+];
+
+/// Returns the dead key whose accent is `accent`, as reported in `Key::Dead`.
+pub fn dead_key(accent: char) -> Option<&'static DeadKey> {
+    DEAD_KEYS.iter().find(|dead| dead.accent == accent)
+}
+
+/// Returns the character typed by dead key `accent` followed by `c`, if they compose.
+pub fn compose(accent: char, c: char) -> Option<char> {
+    let dead = dead_key(accent)?;
+    let index = dead.bases.chars().position(|base| base == c)?;
+    dead.composed.chars().nth(index)
+}
+
+pub struct Layout {
+    pub name: &'static str,
+    base: [&'static str; 4],
+    shifted: [&'static str; 4],
+    altgr: Option<[&'static str; 4]>,
+}
+
+pub const US: Layout = Layout {
+    name: "us",
+    base: [
+        "`1234567890-=",
+        "qwertyuiop[]\\",
+        "asdfghjkl;'",
+        "\\zxcvbnm,./",
+    ],
+    shifted: [
+        "~!@#$%^&*()_+",
+        "QWERTYUIOP{}|",
+        "ASDFGHJKL:\"",
+        "|ZXCVBNM<>?",
+    ],
+    altgr: None,
+};
+
+pub const DVORAK: Layout = Layout {
+    name: "dvorak",
+    base: [
+        "`1234567890[]",
+        "',.pyfgcrl/=\\",
+        "aoeuidhtns-",
+        "\\;qjkxbmwvz",
+    ],
+    shifted: [
+        "~!@#$%^&*(){}",
+        "\"<>PYFGCRL?+|",
+        "AOEUIDHTNS_",
+        "|:QJKXBMWVZ",
+    ],
+    altgr: None,
+};
+
+pub const AZERTY: Layout = Layout {
+    name: "azerty",
+    base: [
+        "²&é\"'(-è_çà)=",
+        "azertyuiop\u{302}$*",
+        "qsdfghjklmù",
+        "<wxcvbn,;:!",
+    ],
+    shifted: [
+        " 1234567890°+",
+        "AZERTYUIOP\u{308}£µ",
+        "QSDFGHJKLM%",
+        ">WXCVBN?./§",
+    ],
+    altgr: Some([
+        "  ~#{[|`\\^@]}",
+        "  €        ¤ ",
+        "           ",
+        "           ",
+    ]),
+};
+
+/// Every layout, for selecting one by name.
+pub const LAYOUTS: [&Layout; 3] = [&US, &DVORAK, &AZERTY];
+
+/// Returns the layout called `name`.
+pub fn find(name: &str) -> Option<&'static Layout> {
+    LAYOUTS.iter().copied().find(|layout| layout.name == name)
+}
+
+// Returns the row and column of a typing key
+fn position(keycode: u8) -> Option<(usize, usize)> {
+    TYPING_KEYS.iter().enumerate().find_map(|(row, keys)| {
+        let column = keys.iter().position(|&key| key == keycode)?;
+        Some((row, column))
+    })
+}
+
+// Returns the key typed at a position of a layer, None if it types nothing
+fn key_at(layer: &[&'static str; 4], (row, column): (usize, usize)) -> Option<Key> {
+    let c = layer[row].chars().nth(column)?;
+    if c == ' ' {
+        return None;
+    }
+    match DEAD_KEYS.iter().find(|dead| dead.mark == c) {
+        Some(dead) => Some(Key::Dead(dead.accent)),
+        None => Some(Key::Char(c)),
+    }
+}
+
+impl Layout {
+    /// Returns whether `keycode` is one of the typing keys this layout maps.
+    pub fn is_typing_key(keycode: u8) -> bool {
+        position(keycode).is_some()
+    }
+
+    /// The key typed by `keycode` with `modifiers` applied, None if it types nothing.
+    ///
+    /// AltGr (right alt) selects the AltGr layer if the layout has one. Otherwise shift
+    /// selects the shifted layer, and so does caps lock for letters, i.e. keys whose shifted
+    /// character is the upper case of their unshifted one.
+    pub fn key(&self, keycode: u8, modifiers: Modifiers) -> Option<Key> {
+        let position = position(keycode)?;
+        if let Some(altgr) = &self.altgr {
+            if modifiers.contains(Modifiers::RIGHT_ALT) {
+                return key_at(altgr, position);
+            }
+        }
+
+        let base = key_at(&self.base, position);
+        let shifted = key_at(&self.shifted, position);
+        let letter = match (base, shifted) {
+            (Some(Key::Char(lower)), Some(Key::Char(upper))) => {
+                lower.is_lowercase() && lower.to_uppercase().eq(core::iter::once(upper))
+            }
+            _ => false,
+        };
+        let shift = if letter {
+            modifiers.shift() != modifiers.caps_lock()
+        } else {
+            modifiers.shift()
+        };
+        if shift {
+            shifted
+        } else {
+            base
+        }
+    }
+}
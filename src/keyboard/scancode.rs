@@ -0,0 +1,469 @@
+/*
+ * Scancode set 2 decoding: make/break sequences to keys and characters.
+ * The characters of the typing keys come from a layout, see layout.rs.
+ */
+
+use super::layout::Layout;
+
+const PS2_CODE_RELEASE: u8 = 0xF0;
+const PS2_CODE_EXTENDED: u8 = 0xE0;
+const PS2_CODE_PAUSE: u8 = 0xE1; // Pause sends E1 14 77 E1 F0 14 F0 77 and nothing on release
+
+/// A key on the keyboard.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Key {
+    /// A key that types a printable character, including space and the keypad operators.
+    Char(char),
+    /// A dead key, by the accent it puts on the next character typed, see layout::compose().
+    Dead(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    PrintScreen,
+    Pause,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    LeftGui,
+    RightGui,
+    Menu,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+}
+
+impl Key {
+    /// The character typed by this key, if any. Enter, Tab and Backspace type their control
+    /// characters.
+    pub fn to_char(self) -> Option<char> {
+        match self {
+            Key::Char(c) => Some(c),
+            Key::Enter => Some('\n'),
+            Key::Tab => Some('\t'),
+            Key::Backspace => Some('\x08'),
+            _ => None,
+        }
+    }
+}
+
+/// The modifier keys held down, and the lock keys toggled on, when a key event was read.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const LEFT_SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const RIGHT_SHIFT: Modifiers = Modifiers(1 << 1);
+    pub const LEFT_CTRL: Modifiers = Modifiers(1 << 2);
+    pub const RIGHT_CTRL: Modifiers = Modifiers(1 << 3);
+    pub const LEFT_ALT: Modifiers = Modifiers(1 << 4);
+    pub const RIGHT_ALT: Modifiers = Modifiers(1 << 5);
+    pub const LEFT_GUI: Modifiers = Modifiers(1 << 6);
+    pub const RIGHT_GUI: Modifiers = Modifiers(1 << 7);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 8);
+    pub const NUM_LOCK: Modifiers = Modifiers(1 << 9);
+    pub const SCROLL_LOCK: Modifiers = Modifiers(1 << 10);
+
+    /// Returns whether all modifiers in `other` are set.
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn intersects(self, other: Modifiers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Either shift key is held.
+    pub fn shift(self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    /// Either ctrl key is held.
+    pub fn ctrl(self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    /// Either alt key is held.
+    pub fn alt(self) -> bool {
+        self.intersects(Self::LEFT_ALT | Self::RIGHT_ALT)
+    }
+
+    /// Either GUI ("Windows") key is held.
+    pub fn gui(self) -> bool {
+        self.intersects(Self::LEFT_GUI | Self::RIGHT_GUI)
+    }
+
+    pub fn caps_lock(self) -> bool {
+        self.contains(Self::CAPS_LOCK)
+    }
+
+    pub fn num_lock(self) -> bool {
+        self.contains(Self::NUM_LOCK)
+    }
+
+    pub fn scroll_lock(self) -> bool {
+        self.contains(Self::SCROLL_LOCK)
+    }
+
+    pub fn set(&mut self, other: Modifiers, on: bool) {
+        if on {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    pub fn toggle(&mut self, other: Modifiers) {
+        self.0 ^= other.0;
+    }
+
+    /// The modifier `key` sets while it is held.
+    pub fn held_by(key: Key) -> Option<Modifiers> {
+        match key {
+            Key::LeftShift => Some(Modifiers::LEFT_SHIFT),
+            Key::RightShift => Some(Modifiers::RIGHT_SHIFT),
+            Key::LeftCtrl => Some(Modifiers::LEFT_CTRL),
+            Key::RightCtrl => Some(Modifiers::RIGHT_CTRL),
+            Key::LeftAlt => Some(Modifiers::LEFT_ALT),
+            Key::RightAlt => Some(Modifiers::RIGHT_ALT),
+            Key::LeftGui => Some(Modifiers::LEFT_GUI),
+            Key::RightGui => Some(Modifiers::RIGHT_GUI),
+            _ => None,
+        }
+    }
+
+    /// The lock `key` toggles each time it goes down.
+    pub fn toggled_by(key: Key) -> Option<Modifiers> {
+        match key {
+            Key::CapsLock => Some(Modifiers::CAPS_LOCK),
+            Key::NumLock => Some(Modifiers::NUM_LOCK),
+            Key::ScrollLock => Some(Modifiers::SCROLL_LOCK),
+            _ => None,
+        }
+    }
+}
+
+impl core::ops::BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+}
+
+// Keys of scancode set 2 without the 0xE0 prefix, indexed by keycode. The typing keys of the
+// main block depend on the layout and are left to it. The keypad reports its navigation keys;
+// these share keycodes with their 0xE0 prefixed counterparts.
+const KEYS: [Option<Key>; 0x84] = [
+    /* 00 */ None,
+    /* 01 */ Some(Key::F9),
+    /* 02 */ None,
+    /* 03 */ Some(Key::F5),
+    /* 04 */ Some(Key::F3),
+    /* 05 */ Some(Key::F1),
+    /* 06 */ Some(Key::F2),
+    /* 07 */ Some(Key::F12),
+    /* 08 */ None,
+    /* 09 */ Some(Key::F10),
+    /* 0A */ Some(Key::F8),
+    /* 0B */ Some(Key::F6),
+    /* 0C */ Some(Key::F4),
+    /* 0D */ Some(Key::Tab),
+    /* 0E */ None,
+    /* 0F */ None,
+    /* 10 */ None,
+    /* 11 */ Some(Key::LeftAlt),
+    /* 12 */ Some(Key::LeftShift),
+    /* 13 */ None,
+    /* 14 */ Some(Key::LeftCtrl),
+    /* 15 */ None,
+    /* 16 */ None,
+    /* 17 */ None,
+    /* 18 */ None,
+    /* 19 */ None,
+    /* 1A */ None,
+    /* 1B */ None,
+    /* 1C */ None,
+    /* 1D */ None,
+    /* 1E */ None,
+    /* 1F */ None,
+    /* 20 */ None,
+    /* 21 */ None,
+    /* 22 */ None,
+    /* 23 */ None,
+    /* 24 */ None,
+    /* 25 */ None,
+    /* 26 */ None,
+    /* 27 */ None,
+    /* 28 */ None,
+    /* 29 */ Some(Key::Char(' ')),
+    /* 2A */ None,
+    /* 2B */ None,
+    /* 2C */ None,
+    /* 2D */ None,
+    /* 2E */ None,
+    /* 2F */ None,
+    /* 30 */ None,
+    /* 31 */ None,
+    /* 32 */ None,
+    /* 33 */ None,
+    /* 34 */ None,
+    /* 35 */ None,
+    /* 36 */ None,
+    /* 37 */ None,
+    /* 38 */ None,
+    /* 39 */ None,
+    /* 3A */ None,
+    /* 3B */ None,
+    /* 3C */ None,
+    /* 3D */ None,
+    /* 3E */ None,
+    /* 3F */ None,
+    /* 40 */ None,
+    /* 41 */ None,
+    /* 42 */ None,
+    /* 43 */ None,
+    /* 44 */ None,
+    /* 45 */ None,
+    /* 46 */ None,
+    /* 47 */ None,
+    /* 48 */ None,
+    /* 49 */ None,
+    /* 4A */ None,
+    /* 4B */ None,
+    /* 4C */ None,
+    /* 4D */ None,
+    /* 4E */ None,
+    /* 4F */ None,
+    /* 50 */ None,
+    /* 51 */ None,
+    /* 52 */ None,
+    /* 53 */ None,
+    /* 54 */ None,
+    /* 55 */ None,
+    /* 56 */ None,
+    /* 57 */ None,
+    /* 58 */ Some(Key::CapsLock),
+    /* 59 */ Some(Key::RightShift),
+    /* 5A */ Some(Key::Enter),
+    /* 5B */ None,
+    /* 5C */ None,
+    /* 5D */ None,
+    /* 5E */ None,
+    /* 5F */ None,
+    /* 60 */ None,
+    /* 61 */ None,
+    /* 62 */ None,
+    /* 63 */ None,
+    /* 64 */ None,
+    /* 65 */ None,
+    /* 66 */ Some(Key::Backspace),
+    /* 67 */ None,
+    /* 68 */ None,
+    /* 69 */ Some(Key::End),
+    /* 6A */ None,
+    /* 6B */ Some(Key::Left),
+    /* 6C */ Some(Key::Home),
+    /* 6D */ None,
+    /* 6E */ None,
+    /* 6F */ None,
+    /* 70 */ Some(Key::Insert),
+    /* 71 */ Some(Key::Delete),
+    /* 72 */ Some(Key::Down),
+    /* 73 */ Some(Key::Char('5')),
+    /* 74 */ Some(Key::Right),
+    /* 75 */ Some(Key::Up),
+    /* 76 */ Some(Key::Escape),
+    /* 77 */ Some(Key::NumLock),
+    /* 78 */ Some(Key::F11),
+    /* 79 */ Some(Key::Char('+')),
+    /* 7A */ Some(Key::PageDown),
+    /* 7B */ Some(Key::Char('-')),
+    /* 7C */ Some(Key::Char('*')),
+    /* 7D */ Some(Key::PageUp),
+    /* 7E */ Some(Key::ScrollLock),
+    /* 7F */ None,
+    /* 80 */ None,
+    /* 81 */ None,
+    /* 82 */ None,
+    /* 83 */ Some(Key::F7),
+];
+
+// Digits typed by the keypad keys while num lock is on, by keycode
+fn keypad_digit(keycode: u8) -> Option<char> {
+    match keycode {
+        0x70 => Some('0'),
+        0x69 => Some('1'),
+        0x72 => Some('2'),
+        0x7A => Some('3'),
+        0x6B => Some('4'),
+        0x73 => Some('5'),
+        0x74 => Some('6'),
+        0x6C => Some('7'),
+        0x75 => Some('8'),
+        0x7D => Some('9'),
+        0x71 => Some('.'),
+        _ => None,
+    }
+}
+
+/// Keys of scancode set 2 sent with the 0xE0 prefix.
+pub fn extended_key(keycode: u8) -> Option<Key> {
+    match keycode {
+        0x11 => Some(Key::RightAlt),
+        0x14 => Some(Key::RightCtrl),
+        0x1F => Some(Key::LeftGui),
+        0x27 => Some(Key::RightGui),
+        0x2F => Some(Key::Menu),
+        0x4A => Some(Key::Char('/')),
+        0x5A => Some(Key::Enter),
+        0x69 => Some(Key::End),
+        0x6B => Some(Key::Left),
+        0x6C => Some(Key::Home),
+        0x70 => Some(Key::Insert),
+        0x71 => Some(Key::Delete),
+        0x72 => Some(Key::Down),
+        0x74 => Some(Key::Right),
+        0x75 => Some(Key::Up),
+        0x77 => Some(Key::Pause), // decoded from the 0xE1 sequence, see SequenceDecoder
+        0x7A => Some(Key::PageDown),
+        0x7C => Some(Key::PrintScreen),
+        0x7D => Some(Key::PageUp),
+        // 0x12 and 0x59 are the fake shifts wrapped around Print Screen and the navigation keys
+        _ => None,
+    }
+}
+
+/// A single make (press) or break (release) code sequence.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyAction {
+    pub keycode: u8,
+    pub extended: bool, // sent with the 0xE0 prefix
+    pub pressed: bool,
+}
+
+impl KeyAction {
+    /// The key this sequence was sent for in `layout`, if it is one we know, ignoring
+    /// modifiers.
+    pub fn key(&self, layout: &Layout) -> Option<Key> {
+        self.key_with(layout, Modifiers::NONE)
+    }
+
+    /// The key this sequence was sent for in `layout` with `modifiers` applied: shift, caps
+    /// lock and AltGr select the layer of the typing keys, num lock turns the keypad
+    /// navigation keys into digits.
+    pub fn key_with(&self, layout: &Layout, modifiers: Modifiers) -> Option<Key> {
+        if self.extended {
+            return extended_key(self.keycode);
+        }
+        if Layout::is_typing_key(self.keycode) {
+            return layout.key(self.keycode, modifiers);
+        }
+        if modifiers.num_lock() {
+            if let Some(digit) = keypad_digit(self.keycode) {
+                return Some(Key::Char(digit));
+            }
+        }
+        KEYS.get(self.keycode as usize).copied().flatten()
+    }
+
+    /// The modifier this sequence's key sets while it is held. Modifier keys are not typing
+    /// keys, so this is the same in every layout and on every layer.
+    pub fn modifier(&self) -> Option<Modifiers> {
+        Modifiers::held_by(self.fixed_key()?)
+    }
+
+    /// The lock this sequence's key toggles, in every layout.
+    pub fn lock(&self) -> Option<Modifiers> {
+        Modifiers::toggled_by(self.fixed_key()?)
+    }
+
+    // The key of a sequence that is not one of the layout's typing keys, ignoring num lock
+    fn fixed_key(&self) -> Option<Key> {
+        if self.extended {
+            extended_key(self.keycode)
+        } else if Layout::is_typing_key(self.keycode) {
+            None
+        } else {
+            KEYS.get(self.keycode as usize).copied().flatten()
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+}
+
+// Assembles make/break sequences (optional 0xE0 extended prefix, optional 0xF0 release prefix,
+// then the keycode) one scancode at a time, so sequences may be split across polls
+pub struct SequenceDecoder {
+    extended: bool,
+    release: bool,
+    pause: bool,
+}
+
+impl SequenceDecoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            release: false,
+            pause: false,
+        }
+    }
+
+    /// Returns the action completed by `scancode`, if any.
+    pub fn feed(&mut self, scancode: u8) -> Option<KeyAction> {
+        match scancode {
+            PS2_CODE_EXTENDED => self.extended = true,
+            PS2_CODE_RELEASE => self.release = true,
+            PS2_CODE_PAUSE => self.pause = true,
+            0x14 if self.pause => self.release = false, // the Ctrl part of the Pause sequence
+            keycode => {
+                // Pause is reported as extended 0x77, which no real key uses
+                let action = KeyAction {
+                    keycode,
+                    extended: self.extended || self.pause,
+                    pressed: !self.release,
+                };
+                *self = SequenceDecoder::new();
+                return Some(action);
+            }
+        }
+        None
+    }
+}
+
+impl Default for SequenceDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
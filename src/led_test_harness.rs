@@ -2,7 +2,7 @@
 
 const GPIO_BASE: u32 = 0x20200000; // leave here to test GPIO module
 
-use crate::cpu;
+use crate::timer::{self, Duration};
 
 pub fn success() -> ! {
     let gpio = GPIO_BASE as *const u32; //
@@ -13,11 +13,11 @@ pub fn success() -> ! {
         unsafe {
             *(led_on) = 1 << 15;
         }
-        cpu::sleep(50000);
+        timer::sleep(Duration::from_millis(100));
         unsafe {
             *(led_off) = 1 << 15;
         }
-        cpu::sleep(50000);
+        timer::sleep(Duration::from_millis(100));
     }
 }
 
@@ -0,0 +1,436 @@
+/*
+ * Leveled logging. Code logs with error!, warn!, info!, debug! and trace!,
+ * and every record is handed to the sinks registered with add_sink(): the
+ * serial console, a memory buffer that can be dumped later, the screen, ...
+ *
+ * Records carry the module they were logged from as their target, or the
+ * one given with `target: "..."`. Levels above the one picked with a
+ * max_level_* cargo feature are compiled out; add_sink() and
+ * set_target_level() filter further at runtime.
+ */
+
+use crate::serial::Serial;
+use crate::{exception, timer};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// The most verbose level let through, if any.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LevelFilter {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LevelFilter {
+    pub const fn allows(&self, level: Level) -> bool {
+        level as usize <= *self as usize
+    }
+}
+
+/// The most verbose level compiled in, chosen with the max_level_* cargo features.
+pub const STATIC_MAX_LEVEL: LevelFilter = if cfg!(feature = "max_level_off") {
+    LevelFilter::Off
+} else if cfg!(feature = "max_level_error") {
+    LevelFilter::Error
+} else if cfg!(feature = "max_level_warn") {
+    LevelFilter::Warn
+} else if cfg!(feature = "max_level_info") {
+    LevelFilter::Info
+} else if cfg!(feature = "max_level_debug") {
+    LevelFilter::Debug
+} else {
+    LevelFilter::Trace
+};
+
+/// A message logged.
+pub struct Record<'a> {
+    pub level: Level,
+    /// The module path of the code that logged it, unless another target was given.
+    pub target: &'a str,
+    /// `timer::get_ticks()` when it was logged, wrapping around every 71 minutes.
+    pub timestamp_us: u32,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    /// One line without the line ending, e.g. `[    1.250000] INFO  rustberry::fb: message`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {:<5} {}: {}",
+            self.timestamp_us / 1_000_000,
+            self.timestamp_us % 1_000_000,
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+/// Somewhere records go.
+pub trait Sink: Sync {
+    /// Called with every record at or below the sink's level, possibly in IRQ mode.
+    fn log(&self, record: &Record);
+}
+
+// Registered sinks with the most verbose level each takes, and the most verbose of those, which
+// lets enabled() skip records nobody takes. Changed with IRQs masked, as records are logged
+// from interrupt handlers too.
+static mut SINKS: Vec<(&'static dyn Sink, LevelFilter)> = Vec::new();
+static mut SINK_MAX_LEVEL: LevelFilter = LevelFilter::Off;
+
+// Levels set for targets, see set_target_level()
+static mut TARGET_LEVELS: Vec<(&'static str, LevelFilter)> = Vec::new();
+
+/// Log to the serial console at `Info` and keep everything in `MEMORY`.
+pub fn init() {
+    add_sink(&SerialSink, LevelFilter::Info);
+    add_sink(&MEMORY, LevelFilter::Trace);
+}
+
+/// Send records up to `level` to `sink` from now on.
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) {
+    unsafe {
+        let saved = exception::local_irq_mask_save();
+        SINKS.push((sink, level));
+        SINK_MAX_LEVEL = SINK_MAX_LEVEL.max(level);
+        exception::local_irq_restore(saved);
+    }
+}
+
+/// Stop sending records to `sink`. Returns whether it was registered.
+pub fn remove_sink(sink: &'static dyn Sink) -> bool {
+    // sinks are told apart by address, as the vtable of one may be duplicated across codegen units
+    let address = sink as *const dyn Sink as *const u8;
+    unsafe {
+        let saved = exception::local_irq_mask_save();
+        let registered = SINKS.len();
+        SINKS.retain(|&(other, _)| other as *const dyn Sink as *const u8 != address);
+        SINK_MAX_LEVEL = SINKS
+            .iter()
+            .fold(LevelFilter::Off, |max, &(_, level)| max.max(level));
+        exception::local_irq_restore(saved);
+        SINKS.len() != registered
+    }
+}
+
+/// Only log records up to `level` from `target` and the modules below it, e.g.
+/// `set_target_level("rustberry::ps2", LevelFilter::Debug)`. The longest matching target wins.
+pub fn set_target_level(target: &'static str, level: LevelFilter) {
+    unsafe {
+        let saved = exception::local_irq_mask_save();
+        TARGET_LEVELS.retain(|&(other, _)| other != target);
+        TARGET_LEVELS.push((target, level));
+        exception::local_irq_restore(saved);
+    }
+}
+
+// Returns whether records from `target` are covered by the level set for `prefix`
+fn target_matches(prefix: &str, target: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+#[test_case]
+fn test_remove_sink() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    struct CountingSink(AtomicU32);
+    impl Sink for CountingSink {
+        fn log(&self, _record: &Record) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    static COUNTING: CountingSink = CountingSink(AtomicU32::new(0));
+
+    add_sink(&COUNTING, LevelFilter::Error);
+    crate::error!("counted");
+    assert!(remove_sink(&COUNTING));
+    crate::error!("not counted");
+    assert!(!remove_sink(&COUNTING));
+    assert_eq!(COUNTING.0.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn test_target_matches() {
+    assert!(target_matches("rustberry::ps2", "rustberry::ps2"));
+    assert!(target_matches("rustberry::ps2", "rustberry::ps2::decoder"));
+    assert!(!target_matches("rustberry::ps2", "rustberry::ps2x"));
+    assert!(!target_matches("rustberry::ps2", "rustberry"));
+}
+
+fn target_level(target: &str) -> LevelFilter {
+    unsafe {
+        TARGET_LEVELS
+            .iter()
+            .filter(|(prefix, _)| target_matches(prefix, target))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(LevelFilter::Trace, |&(_, level)| level)
+    }
+}
+
+/// Returns whether a record at `level` from `target` would go anywhere.
+#[inline]
+pub fn enabled(level: Level, target: &str) -> bool {
+    STATIC_MAX_LEVEL.allows(level)
+        && unsafe { SINK_MAX_LEVEL }.allows(level)
+        && target_level(target).allows(level)
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, target: &str, args: fmt::Arguments) {
+    let record = Record {
+        level,
+        target,
+        timestamp_us: unsafe { timer::get_ticks() },
+        args,
+    };
+    unsafe {
+        for &(sink, sink_level) in SINKS.iter() {
+            if sink_level.allows(level) {
+                sink.log(&record);
+            }
+        }
+    }
+}
+
+/// Logs a record at the given level, see `error!` and friends.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => ({
+        let level = $level;
+        if $crate::log::enabled(level, $target) {
+            $crate::log::_log(level, $target, format_args!($($arg)+));
+        }
+    });
+    ($level:expr, $($arg:tt)+) => ($crate::log!(target: module_path!(), $level, $($arg)+));
+}
+
+/// Logs something that went wrong.
+#[macro_export]
+macro_rules! error {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Error, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+/// Logs something that may be a problem.
+#[macro_export]
+macro_rules! warn {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Warn, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+/// Logs what is going on, e.g. a device being set up.
+#[macro_export]
+macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Info, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+/// Logs details for debugging.
+#[macro_export]
+macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Debug, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+/// Logs every little step.
+#[macro_export]
+macro_rules! trace {
+    (target: $target:expr, $($arg:tt)+) => (
+        $crate::log!(target: $target, $crate::log::Level::Trace, $($arg)+)
+    );
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+//--------------------------------------------------------------------------------------------------
+// Sinks
+//--------------------------------------------------------------------------------------------------
+
+/// Prints records on the serial console.
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn log(&self, record: &Record) {
+        crate::println!("{}", record);
+    }
+}
+
+/// Keeps the last `N` bytes of records logged, one line each, to be dumped later.
+pub struct MemorySink<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    // Index of the oldest byte kept, and how many are kept. Changed with IRQs masked.
+    start: UnsafeCell<usize>,
+    len: UnsafeCell<usize>,
+}
+
+// All access happens with IRQs masked on the one core.
+unsafe impl<const N: usize> Sync for MemorySink<N> {}
+
+/// The log kept in memory by `init()`.
+pub static MEMORY: MemorySink<8192> = MemorySink::new();
+
+impl<const N: usize> MemorySink<N> {
+    pub const fn new() -> Self {
+        MemorySink {
+            buffer: UnsafeCell::new([0; N]),
+            start: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+        }
+    }
+
+    // Appends `byte`, dropping the oldest one if full. IRQs must be masked.
+    unsafe fn push(&self, byte: u8) {
+        let start = &mut *self.start.get();
+        let len = &mut *self.len.get();
+        (*self.buffer.get())[(*start + *len) % N] = byte;
+        if *len == N {
+            *start = (*start + 1) % N;
+        } else {
+            *len += 1;
+        }
+    }
+
+    /// Calls `f` with the text kept, oldest first, in one or two pieces. Once the buffer has
+    /// wrapped around, the first line is cut off and is skipped.
+    pub fn read(&self, mut f: impl FnMut(&[u8])) {
+        let saved = exception::local_irq_mask_save();
+        unsafe {
+            let buffer = &*self.buffer.get();
+            let (start, len) = (*self.start.get(), *self.len.get());
+            let (first, second) = if start + len <= N {
+                (&buffer[start..start + len], &buffer[..0])
+            } else {
+                (&buffer[start..], &buffer[..start + len - N])
+            };
+            let skip = if len == N {
+                let mut bytes = first.iter().chain(second.iter());
+                bytes
+                    .position(|&byte| byte == b'\n')
+                    .map_or(len, |end| end + 1)
+            } else {
+                0
+            };
+            let first_skip = skip.min(first.len());
+            f(&first[first_skip..]);
+            f(&second[skip - first_skip..]);
+        }
+        exception::local_irq_restore(saved);
+    }
+
+    /// Send the text kept to `serial`, with CR-LF line endings.
+    pub fn dump(&self, serial: &dyn Serial) {
+        self.read(|piece| {
+            for &byte in piece {
+                if byte == b'\n' {
+                    serial.put_u8(b'\r');
+                }
+                serial.put_u8(byte);
+            }
+        });
+    }
+
+    pub fn clear(&self) {
+        let saved = exception::local_irq_mask_save();
+        unsafe {
+            *self.start.get() = 0;
+            *self.len.get() = 0;
+        }
+        exception::local_irq_restore(saved);
+    }
+}
+
+impl<const N: usize> Write for &MemorySink<N> {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for byte in string.bytes() {
+            unsafe { self.push(byte) };
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Sink for MemorySink<N> {
+    fn log(&self, record: &Record) {
+        // keep records logged from interrupt handlers from landing in the middle of this one
+        let saved = exception::local_irq_mask_save();
+        let mut writer = self;
+        let _ = writeln!(writer, "{}", record);
+        exception::local_irq_restore(saved);
+    }
+}
+
+#[test_case]
+fn test_memory_sink() {
+    let sink = MemorySink::<40>::new();
+    let log = |message: &str| {
+        sink.log(&Record {
+            level: Level::Info,
+            target: "test",
+            timestamp_us: 1_250_000,
+            args: format_args!("{}", message),
+        })
+    };
+    let contents = || {
+        let mut text = Vec::new();
+        sink.read(|piece| text.extend_from_slice(piece));
+        text
+    };
+
+    log("one");
+    assert_eq!(contents(), b"[    1.250000] INFO  test: one\n");
+    // the second line pushes most of the first one out, which is then skipped
+    log("two");
+    assert_eq!(contents(), b"[    1.250000] INFO  test: two\n");
+    sink.clear();
+    assert_eq!(contents(), b"");
+}
+
+#[test_case]
+fn test_level_filter() {
+    assert!(LevelFilter::Info.allows(Level::Error));
+    assert!(LevelFilter::Info.allows(Level::Info));
+    assert!(!LevelFilter::Info.allows(Level::Debug));
+    assert!(!LevelFilter::Off.allows(Level::Error));
+}
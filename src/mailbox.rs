@@ -1,6 +1,7 @@
 /*
  * Rust code to interact with the mailbox system on the Raspberry
- * Pi. Used to request a framebuffer on which images can be drawn.
+ * Pi. Used to request a framebuffer on which images can be drawn, and to
+ * ask the firmware for clock rates.
  *
  * Based off of the cs107e libpi module's mailbox implementation
  * authored by Pat Hanrahan and Philip Levis.
@@ -8,6 +9,9 @@
  * Author: Ashish Rao <aprao@stanford.edu>
  */
 
+use crate::{debug, error, warn};
+use core::sync::atomic::{compiler_fence, Ordering};
+
 const MAILBOX_BASE: u32 = 0x2000B880;
 const MAILBOX_FULL: u32 = 1 << 31;
 const MAILBOX_EMPTY: u32 = 1 << 30;
@@ -15,6 +19,20 @@ const MAILBOX_MAXCHANNEL: u32 = 16;
 const GPU_NOCACHE: u32 = 0x40000000;
 
 pub const MAILBOX_FRAMEBUFFER: u32 = 1;
+pub const MAILBOX_PROPERTY: u32 = 8;
+
+// Property channel messages: a buffer of tags, each with a request/response code
+const PROPERTY_REQUEST: u32 = 0;
+const PROPERTY_SUCCESS: u32 = 0x80000000;
+const PROPERTY_TAG_RESPONSE: u32 = 1 << 31;
+const PROPERTY_TAG_END: u32 = 0;
+
+const TAG_GET_CLOCK_RATE: u32 = 0x00030002;
+
+// Clock ids for get_clock_rate(): the PL011's reference clock, and the VideoCore clock, which
+// drives the other peripherals
+pub const CLOCK_UART: u32 = 2;
+pub const CLOCK_CORE: u32 = 4;
 
 #[repr(C)]
 struct MailboxT {
@@ -27,6 +45,20 @@ struct MailboxT {
     write: u32,
 }
 
+// A property message with the single tag TAG_GET_CLOCK_RATE. The firmware overwrites it with
+// the response, so like any buffer passed through the mailbox it must be 16 byte aligned.
+#[repr(C, align(16))]
+struct ClockRateMessage {
+    size: u32,
+    code: u32,
+    tag: u32,
+    value_size: u32,
+    tag_code: u32,
+    clock_id: u32,
+    rate: u32,
+    end: u32,
+}
+
 pub fn mailbox_request(channel: u32, addr: u32) -> bool {
     if !mailbox_write(channel, addr) {
         return false;
@@ -60,9 +92,11 @@ pub fn mailbox_read(channel: u32) -> u32 {
 
 pub fn mailbox_write(channel: u32, mut addr: u32) -> bool {
     if channel >= MAILBOX_MAXCHANNEL {
+        error!("no mailbox channel {}", channel);
         return false;
     };
     if (addr & 0xF) > 0 {
+        error!("mailbox message at {:#010x} is not 16 byte aligned", addr);
         return false;
     };
     let mailbox = unsafe { &mut *(MAILBOX_BASE as *mut MailboxT) };
@@ -84,3 +118,36 @@ pub fn mailbox_write(channel: u32, mut addr: u32) -> bool {
 
     true
 }
+
+/// Returns the rate of clock `clock_id` in Hz as reported by the firmware, or None if the
+/// request fails.
+pub fn get_clock_rate(clock_id: u32) -> Option<u32> {
+    let mut message = ClockRateMessage {
+        size: core::mem::size_of::<ClockRateMessage>() as u32,
+        code: PROPERTY_REQUEST,
+        tag: TAG_GET_CLOCK_RATE,
+        value_size: 8,
+        tag_code: PROPERTY_REQUEST,
+        clock_id,
+        rate: 0,
+        end: PROPERTY_TAG_END,
+    };
+    let addr = &mut message as *mut ClockRateMessage;
+
+    // the firmware reads and writes the message behind the compiler's back
+    compiler_fence(Ordering::SeqCst);
+    if !mailbox_write(MAILBOX_PROPERTY, addr as u32) {
+        return None;
+    }
+    // the property channel answers with the address of the message, which now holds the response
+    mailbox_read(MAILBOX_PROPERTY);
+    compiler_fence(Ordering::SeqCst);
+    let response = unsafe { core::ptr::read_volatile(addr) };
+
+    if response.code != PROPERTY_SUCCESS || response.tag_code & PROPERTY_TAG_RESPONSE == 0 {
+        warn!("the firmware did not report the rate of clock {}", clock_id);
+        return None;
+    }
+    debug!("clock {} runs at {} Hz", clock_id, response.rate);
+    Some(response.rate)
+}
@@ -19,20 +19,36 @@
 extern crate alloc;
 
 mod allocator;
+mod arm_timer;
+mod bootloader;
 mod bsp;
 mod cpu;
+mod exception;
 mod fb;
+mod gdb;
 mod gl;
 mod gpio;
+mod interrupts;
 mod keyboard;
 mod led_test_harness;
+mod log;
 mod mailbox;
 mod memory;
+mod mouse;
 mod panic_wait;
+mod pl011;
+mod print;
+mod ps2;
+mod ring_buffer;
+mod rpc;
 mod runtime_init;
+mod serial;
+mod shell;
+mod soft_uart;
 mod space_invaders;
 mod timer;
 mod uart;
+mod watchdog;
 
 /// Early init code.
 ///
@@ -41,10 +57,10 @@ mod uart;
 /// - Only a single core must be active and running this function.
 #[no_mangle]
 pub extern "C" fn main() -> ! {
-    unsafe {
-        space_invaders::run_game();
-    }
-    led_test_harness::success(); // flashes green led forever
+    #[cfg(feature = "bootloader")]
+    bootloader::run();
+    #[cfg(not(feature = "bootloader"))]
+    shell::run();
 }
 
 // -------------------------------------------------------------------------------------------------
@@ -0,0 +1,112 @@
+/*
+ * PS/2 mouse driver. Detects IntelliMouse scroll wheels and turns the
+ * movement packets of the mouse into pointer events.
+ *
+ * The mouse shares the GPIO interrupt with the keyboard; each PS/2 device
+ * only handles the edges on its own clock pin.
+ */
+
+use crate::ps2::{Ps2DeviceT, Ps2Error, PS2_SELF_TEST_PASSED};
+use crate::timer::{Duration, Instant};
+
+mod packet;
+
+use packet::PacketDecoder;
+pub use packet::{Buttons, MouseEvent};
+
+static mut DEV: Ps2DeviceT = Ps2DeviceT::new(23, 24);
+
+// Commands understood by the mouse
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_GET_DEVICE_ID: u8 = 0xF2;
+const MOUSE_ENABLE_DATA_REPORTING: u8 = 0xF4;
+const MOUSE_RESET: u8 = 0xFF;
+
+// Device IDs reported after a reset and by MOUSE_GET_DEVICE_ID
+const MOUSE_ID_STANDARD: u8 = 0x00;
+const MOUSE_ID_INTELLIMOUSE: u8 = 0x03;
+
+// The self test after a reset takes 300-500ms
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bytes the mouse sends after a command's ACK are queued, and arrive within a few ms
+const REPLY_TIMEOUT: Duration = Duration::from_millis(20);
+
+// How long read_event() waits for a pointer event
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+static mut DECODER: PacketDecoder = PacketDecoder::new(false);
+
+static mut INITIALIZED: bool = false;
+
+/// Start receiving from the mouse and reset it.
+///
+/// A missing mouse does not stop the boot; reset() can be called again later.
+pub unsafe fn init() {
+    if INITIALIZED {
+        return;
+    }
+    DEV.init(None);
+    INITIALIZED = true;
+    let _ = reset();
+}
+
+// Returns the next queued byte, which a command has told the mouse to send
+unsafe fn read_reply(timeout: Duration) -> Result<u8, Ps2Error> {
+    DEV.read(timeout).ok_or(Ps2Error::Timeout)
+}
+
+/// Reset the mouse, enable its scroll wheel if it has one, and turn on data reporting.
+pub unsafe fn reset() -> Result<(), Ps2Error> {
+    DECODER = PacketDecoder::new(false);
+    DEV.command(&[MOUSE_RESET])?;
+    match read_reply(SELF_TEST_TIMEOUT)? {
+        PS2_SELF_TEST_PASSED => {}
+        response => return Err(Ps2Error::Response(response)),
+    }
+    match read_reply(REPLY_TIMEOUT)? {
+        MOUSE_ID_STANDARD => {}
+        id => return Err(Ps2Error::Response(id)),
+    }
+
+    // The IntelliMouse knock: setting the sample rate to 200, 100, then 80 enables the wheel
+    // and changes the device ID. Mice without a wheel ignore it.
+    for &rate in [200, 100, 80].iter() {
+        DEV.command(&[MOUSE_SET_SAMPLE_RATE, rate])?;
+    }
+    DEV.command(&[MOUSE_GET_DEVICE_ID])?;
+    let has_wheel = read_reply(REPLY_TIMEOUT)? == MOUSE_ID_INTELLIMOUSE;
+    DEV.command(&[MOUSE_SET_SAMPLE_RATE, 100])?;
+
+    while DEV.try_read().is_some() {}
+    DECODER = PacketDecoder::new(has_wheel);
+    DEV.command(&[MOUSE_ENABLE_DATA_REPORTING])
+}
+
+/// Returns whether the mouse reports scroll wheel movement.
+pub fn has_wheel() -> bool {
+    unsafe { DECODER.has_wheel() }
+}
+
+/// Returns the next pointer event without waiting, or None if no complete packet has arrived.
+pub unsafe fn try_read_event() -> Option<MouseEvent> {
+    while let Some(byte) = DEV.try_read() {
+        if let Some(event) = DECODER.feed(byte) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+// Waits up to READ_TIMEOUT for the next pointer event
+pub unsafe fn read_event() -> Option<MouseEvent> {
+    let deadline = Instant::now() + READ_TIMEOUT;
+    loop {
+        if let Some(event) = try_read_event() {
+            return Some(event);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+    }
+}
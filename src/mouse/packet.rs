@@ -0,0 +1,148 @@
+/*
+ * PS/2 mouse packet decoding: movement packets to pointer events.
+ *
+ * A standard mouse sends 3 byte packets, an IntelliMouse with a scroll
+ * wheel 4 byte ones:
+ *
+ *   byte 0: Y overflow, X overflow, Y sign, X sign, 1, middle, right, left
+ *   byte 1: X movement, low 8 bits of a 9 bit two's complement value
+ *   byte 2: Y movement, likewise; positive is up
+ *   byte 3: wheel movement, 4 bit two's complement (IntelliMouse only)
+ */
+
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// A set of mouse buttons.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub const NONE: Buttons = Buttons(0);
+    pub const LEFT: Buttons = Buttons(1 << 0);
+    pub const RIGHT: Buttons = Buttons(1 << 1);
+    pub const MIDDLE: Buttons = Buttons(1 << 2);
+
+    /// Returns whether all buttons in `other` are in the set.
+    pub fn contains(self, other: Buttons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for Buttons {
+    type Output = Buttons;
+
+    fn bitor(self, other: Buttons) -> Buttons {
+        Buttons(self.0 | other.0)
+    }
+}
+
+/// Movement and buttons reported by one packet.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MouseEvent {
+    /// Horizontal movement, positive to the right.
+    pub dx: i32,
+    /// Vertical movement, positive down as on the screen.
+    pub dy: i32,
+    /// Wheel movement, positive towards the user. Always 0 without a scroll wheel.
+    pub wheel: i32,
+    /// The buttons held.
+    pub buttons: Buttons,
+    /// The buttons pressed or released since the previous packet.
+    pub changed: Buttons,
+}
+
+impl MouseEvent {
+    /// Returns whether `button` went down with this packet.
+    pub fn pressed(&self, button: Buttons) -> bool {
+        self.changed.contains(button) && self.buttons.contains(button)
+    }
+
+    /// Returns whether `button` went up with this packet.
+    pub fn released(&self, button: Buttons) -> bool {
+        self.changed.contains(button) && !self.buttons.contains(button)
+    }
+}
+
+// Returns the 9 bit movement value, saturated when the mouse reported an overflow
+fn movement(low: u8, sign: bool, overflow: bool) -> i32 {
+    match (overflow, sign) {
+        (true, true) => -256,
+        (true, false) => 255,
+        (false, true) => low as i32 - 256,
+        (false, false) => low as i32,
+    }
+}
+
+/// Assembles packets one byte at a time, so packets may be split across polls.
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    len: usize,
+    has_wheel: bool,
+    buttons: Buttons,
+}
+
+impl PacketDecoder {
+    /// A decoder for 3 byte packets, or 4 byte ones if `has_wheel`.
+    pub const fn new(has_wheel: bool) -> Self {
+        Self {
+            packet: [0; 4],
+            len: 0,
+            has_wheel,
+            buttons: Buttons::NONE,
+        }
+    }
+
+    /// Returns whether packets carry wheel movement.
+    pub fn has_wheel(&self) -> bool {
+        self.has_wheel
+    }
+
+    /// Returns the event completed by `byte`, if any.
+    ///
+    /// The first byte of a packet always has bit 3 set; bytes that cannot start a packet are
+    /// skipped, so a decoder that lost a byte resynchronizes within a few packets.
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < if self.has_wheel { 4 } else { 3 } {
+            return None;
+        }
+        self.len = 0;
+
+        let flags = self.packet[0];
+        let buttons = Buttons(flags & 0b111);
+        let wheel = if self.has_wheel {
+            ((self.packet[3] << 4) as i8 >> 4) as i32 // sign extend the low nibble
+        } else {
+            0
+        };
+        let event = MouseEvent {
+            dx: movement(
+                self.packet[1],
+                flags & PACKET_X_SIGN != 0,
+                flags & PACKET_X_OVERFLOW != 0,
+            ),
+            dy: -movement(
+                self.packet[2],
+                flags & PACKET_Y_SIGN != 0,
+                flags & PACKET_Y_OVERFLOW != 0,
+            ),
+            wheel,
+            buttons,
+            changed: Buttons(buttons.0 ^ self.buttons.0),
+        };
+        self.buttons = buttons;
+        Some(event)
+    }
+}
@@ -4,9 +4,10 @@
 //
 // Edited 2021 by Flynn Dreilinger <flynnd@stanford.edu> and Ashish Rao <aprao@stanford.edu>
 
-//! A panic handler that infinitely waits.
+//! A panic handler that prints the panic message and infinitely waits.
 
-use crate::cpu;
+use crate::timer::{self, Duration};
+use crate::{println, serial};
 use core::panic::PanicInfo;
 
 const GPIO_BASE: u32 = 0x20200000; // leave here to test GPIO module
@@ -18,6 +19,9 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    println!("\nKernel {}", info);
+    // the interrupt that would send it may never come
+    serial::console().flush();
 
     let gpio = GPIO_BASE as *const u32;
     let fsel_3 = unsafe { gpio.offset(3) as *mut u32 };
@@ -32,11 +36,11 @@ fn panic(info: &PanicInfo) -> ! {
         unsafe {
             *(set_1) = 1 << 3;
         }
-        cpu::sleep(1000000);
+        timer::sleep(Duration::from_millis(500));
         unsafe {
             *(clr_1) = 1 << 3;
         }
-        cpu::sleep(1000000);
+        timer::sleep(Duration::from_millis(500));
     }
 }
 
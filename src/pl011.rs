@@ -0,0 +1,551 @@
+/*
+ * Driver for the PL011 UART (UART0). Unlike the mini UART it has a baud
+ * rate generator of its own, fed by the UART reference clock rather than the
+ * core clock, and supports parity, two stop bits, break detection and
+ * RTS/CTS flow control. Its FIFOs hold 16 characters.
+ *
+ * Like the mini UART it is polled until enable_interrupts() is called. Both
+ * can be used at once, see serial::set_console().
+ *
+ * Based off of the ARM PrimeCell UART (PL011) Technical Reference Manual and
+ * the BCM2835 ARM Peripherals data sheet, chapter 13.
+ */
+
+use crate::ring_buffer::{ByteQueue, RingBuffer};
+use crate::serial::{BreakByte, Overruns, Serial};
+use crate::{cpu, exception, gpio, interrupts, mailbox};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const PL011_BASE: u32 = 0x20201000;
+
+// Receive status in the upper bits of a character read from DR
+const DR_FRAMING_ERROR: u32 = 1 << 8;
+const DR_PARITY_ERROR: u32 = 1 << 9;
+const DR_BREAK_ERROR: u32 = 1 << 10;
+const DR_OVERRUN_ERROR: u32 = 1 << 11;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RX_EMPTY: u32 = 1 << 4;
+const FR_TX_FULL: u32 = 1 << 5;
+
+const LCRH_PARITY_ENABLE: u32 = 1 << 1;
+const LCRH_EVEN_PARITY: u32 = 1 << 2;
+const LCRH_TWO_STOP_BITS: u32 = 1 << 3;
+const LCRH_FIFO_ENABLE: u32 = 1 << 4;
+const LCRH_WORD_LENGTH_SHIFT: u32 = 5;
+
+const CR_UART_ENABLE: u32 = 1 << 0;
+const CR_LOOPBACK: u32 = 1 << 7;
+const CR_TX_ENABLE: u32 = 1 << 8;
+const CR_RX_ENABLE: u32 = 1 << 9;
+const CR_RTS_FLOW: u32 = 1 << 14;
+const CR_CTS_FLOW: u32 = 1 << 15;
+
+// Interrupt FIFO levels: receive at 1/2 full, transmit at 1/8 full
+const IFLS_RX_HALF: u32 = 0b010 << 3;
+const IFLS_TX_EIGHTH: u32 = 0b000;
+
+// Interrupt bits, the same in IMSC, MIS and ICR. The receive timeout fires when characters sit
+// in the receive FIFO below its level for a while.
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+const INT_RX_TIMEOUT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7FF;
+
+const GPIO_FUNC_ALT0: u32 = 0b100;
+const GPIO_FUNC_ALT3: u32 = 0b111;
+
+// The integer part of the divisor is 16 bits and the fractional part 6 bits, so the divisor
+// is handled in 64ths: BAUD_DIVISOR_MAX is one more than the largest.
+const BAUD_DIVISOR_MIN: u64 = 1 << 6;
+const BAUD_DIVISOR_MAX: u64 = 1 << 22;
+// The rate both ends use may differ by about 5% before bits are sampled wrong, so allow half
+const BAUD_TOLERANCE_PERMILLE: u64 = 25;
+
+#[repr(C)]
+struct Registers {
+    dr: u32,
+    rsrecr: u32,
+    _reserved0: [u32; 4],
+    fr: u32,
+    _reserved1: u32,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcrh: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    ris: u32,
+    mis: u32,
+    icr: u32,
+}
+
+static mut REGS: *mut Registers = PL011_BASE as *mut Registers;
+
+static mut INITIALIZED: bool = false;
+
+// Queues between the interrupt handler and readers/writers in interrupt mode, see
+// enable_interrupts(). None while polling.
+static mut RX_QUEUE: Option<&'static dyn ByteQueue> = None;
+static mut TX_QUEUE: Option<&'static dyn ByteQueue> = None;
+
+// Characters lost or dropped on receive, see overruns() and line_errors()
+static FIFO_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static QUEUE_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static FRAMING_ERRORS: AtomicU32 = AtomicU32::new(0);
+static PARITY_ERRORS: AtomicU32 = AtomicU32::new(0);
+static BREAKS: AtomicU32 = AtomicU32::new(0);
+static BREAK_BYTE: BreakByte = BreakByte::new();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+}
+
+/// GPIO pins the UART is routed to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pins {
+    /// TXD0 and RXD0 on GPIO 14 and 15, CTS0 and RTS0 on 16 and 17. The mini UART uses the same
+    /// header pins, so only one of the two can be wired up there.
+    Gpio14,
+    /// TXD0 and RXD0 on GPIO 32 and 33, CTS0 and RTS0 on 30 and 31.
+    Gpio32,
+}
+
+/// Line settings for `init_with()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub pins: Pins,
+}
+
+impl Default for Config {
+    /// 115200 baud 8N1 without flow control on GPIO 14 and 15.
+    fn default() -> Self {
+        Config {
+            baud: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            pins: Pins::Gpio14,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pl011Error {
+    /// The firmware did not report the UART clock through the mailbox.
+    ClockUnavailable,
+    /// The UART clock can't be divided down to within BAUD_TOLERANCE_PERMILLE of the requested
+    /// rate; `closest` is the nearest rate it can.
+    BaudRate { requested: u32, closest: u32 },
+}
+
+/// Characters received with errors since boot. They are dropped rather than passed on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineErrors {
+    /// The stop bit was missing, usually because the baud rates of both ends differ.
+    pub framing: u32,
+    pub parity: u32,
+    /// The line was held low for longer than a character.
+    pub breaks: u32,
+}
+
+// Returns the integer and fractional baud rate divisors for the rate closest to `baud`, which
+// the PL011 derives from the UART clock as uart_clock / (16 * (integer + fractional / 64)).
+fn baud_divisors(uart_clock: u32, baud: u32) -> Result<(u32, u32), Pl011Error> {
+    // the divisor in 64ths is uart_clock * 64 / (16 * baud)
+    let divisor = if baud == 0 {
+        BAUD_DIVISOR_MAX - 1
+    } else {
+        ((4 * uart_clock as u64 + baud as u64 / 2) / baud as u64)
+            .clamp(BAUD_DIVISOR_MIN, BAUD_DIVISOR_MAX - 1)
+    };
+    let closest = 4 * uart_clock as u64 / divisor;
+
+    let error = if closest > baud as u64 {
+        closest - baud as u64
+    } else {
+        baud as u64 - closest
+    };
+    if error * 1000 > baud as u64 * BAUD_TOLERANCE_PERMILLE {
+        return Err(Pl011Error::BaudRate {
+            requested: baud,
+            closest: closest as u32,
+        });
+    }
+    Ok(((divisor >> 6) as u32, (divisor & 0x3F) as u32))
+}
+
+#[test_case]
+fn test_baud_divisors() {
+    // the 48 MHz UART clock of current firmware, and the 3 MHz of older firmware
+    assert_eq!(baud_divisors(48_000_000, 115200), Ok((26, 3)));
+    assert_eq!(baud_divisors(48_000_000, 3_000_000), Ok((1, 0)));
+    assert_eq!(baud_divisors(3_000_000, 115200), Ok((1, 40)));
+    // faster than uart_clock / 16
+    assert_eq!(
+        baud_divisors(3_000_000, 921600),
+        Err(Pl011Error::BaudRate {
+            requested: 921600,
+            closest: 187500
+        })
+    );
+    assert!(baud_divisors(48_000_000, 0).is_err());
+}
+
+/// Set up the UART for 115200 baud 8N1 on GPIO 14 and 15, see `init_with()`.
+pub unsafe fn init() -> Result<(), Pl011Error> {
+    init_with(Config::default())
+}
+
+/// Set up the UART with the line settings in `config`.
+///
+/// The baud rate divisor is computed from the UART clock the firmware reports. Fails without
+/// touching the UART if the rate can't be hit within 2.5%. Bytes still waiting to be sent are
+/// sent with the old settings first.
+pub unsafe fn init_with(config: Config) -> Result<(), Pl011Error> {
+    let uart_clock =
+        mailbox::get_clock_rate(mailbox::CLOCK_UART).ok_or(Pl011Error::ClockUnavailable)?;
+    let (integer, fractional) = baud_divisors(uart_clock, config.baud)?;
+
+    if INITIALIZED {
+        flush();
+    }
+    let (pins, function, flow_pins) = match config.pins {
+        Pins::Gpio14 => ([14, 15], GPIO_FUNC_ALT0, [16, 17]),
+        Pins::Gpio32 => ([32, 33], GPIO_FUNC_ALT3, [30, 31]),
+    };
+    for &pin in pins.iter() {
+        gpio::set_function(pin, function);
+    }
+    if config.flow_control == FlowControl::RtsCts {
+        for &pin in flow_pins.iter() {
+            gpio::set_function(pin, GPIO_FUNC_ALT3);
+        }
+    }
+
+    let mut lcrh = LCRH_FIFO_ENABLE;
+    lcrh |= match config.data_bits {
+        DataBits::Five => 0b00,
+        DataBits::Six => 0b01,
+        DataBits::Seven => 0b10,
+        DataBits::Eight => 0b11,
+    } << LCRH_WORD_LENGTH_SHIFT;
+    lcrh |= match config.parity {
+        Parity::None => 0,
+        Parity::Even => LCRH_PARITY_ENABLE | LCRH_EVEN_PARITY,
+        Parity::Odd => LCRH_PARITY_ENABLE,
+    };
+    if config.stop_bits == StopBits::Two {
+        lcrh |= LCRH_TWO_STOP_BITS;
+    }
+    let mut cr = CR_UART_ENABLE | CR_TX_ENABLE | CR_RX_ENABLE;
+    if config.flow_control == FlowControl::RtsCts {
+        cr |= CR_RTS_FLOW | CR_CTS_FLOW;
+    }
+
+    // The TRM's sequence: disable the UART, let it finish the character it is sending, and flush
+    // the FIFOs by disabling them. The divisors only take effect with the next write to LCRH.
+    cpu::dev_barrier();
+    core::ptr::write_volatile(&mut (*REGS).cr, 0);
+    while core::ptr::read_volatile(&(*REGS).fr) & FR_BUSY != 0 {}
+    core::ptr::write_volatile(&mut (*REGS).lcrh, 0);
+    core::ptr::write_volatile(&mut (*REGS).icr, INT_ALL);
+    core::ptr::write_volatile(&mut (*REGS).ibrd, integer);
+    core::ptr::write_volatile(&mut (*REGS).fbrd, fractional);
+    core::ptr::write_volatile(&mut (*REGS).lcrh, lcrh);
+    core::ptr::write_volatile(&mut (*REGS).ifls, IFLS_RX_HALF | IFLS_TX_EIGHTH);
+    if RX_QUEUE.is_some() {
+        // reconfigured in interrupt mode
+        core::ptr::write_volatile(&mut (*REGS).imsc, INT_RX | INT_RX_TIMEOUT);
+    }
+    core::ptr::write_volatile(&mut (*REGS).cr, cr);
+    INITIALIZED = true;
+    cpu::dev_barrier();
+    Ok(())
+}
+
+unsafe fn send(byte: u8) {
+    cpu::dev_barrier();
+    while core::ptr::read_volatile(&(*REGS).fr) & FR_TX_FULL != 0 {}
+    core::ptr::write_volatile(&mut (*REGS).dr, byte as u32);
+    cpu::dev_barrier();
+}
+
+// Takes the next character out of the receive FIFO, if there is one. Characters received with
+// errors are counted and dropped.
+unsafe fn receive() -> Option<u8> {
+    cpu::dev_barrier();
+    while core::ptr::read_volatile(&(*REGS).fr) & FR_RX_EMPTY == 0 {
+        let data = core::ptr::read_volatile(&(*REGS).dr);
+        // an overrun is flagged on the character before the ones lost, which itself is fine
+        if data & DR_OVERRUN_ERROR != 0 {
+            FIFO_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        if data & DR_BREAK_ERROR != 0 {
+            BREAKS.fetch_add(1, Ordering::Relaxed);
+        } else if data & DR_FRAMING_ERROR != 0 {
+            FRAMING_ERRORS.fetch_add(1, Ordering::Relaxed);
+        } else if data & DR_PARITY_ERROR != 0 {
+            PARITY_ERRORS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            return Some(data as u8);
+        }
+    }
+    None
+}
+
+/// Returns the next byte received without waiting, if there is one.
+pub unsafe fn try_read() -> Option<u8> {
+    match RX_QUEUE {
+        // with IRQs masked nobody fills the queue, so the FIFO is read once it is empty
+        Some(queue) => match queue.pop() {
+            None if exception::local_irq_is_masked() => receive(),
+            byte => byte,
+        },
+        None => receive(),
+    }
+}
+
+/// Send `byte`, waiting for room in the FIFO or transmit queue.
+pub unsafe fn put_u8(byte: u8) {
+    match TX_QUEUE {
+        Some(queue) => {
+            // With IRQs masked nobody else makes room, so the oldest byte is sent right away.
+            // Handlers print too, and the queue takes a single producer, so every push is made
+            // with IRQs masked; they are unmasked in between for the handler to make room.
+            let masked = exception::local_irq_is_masked();
+            loop {
+                let saved = exception::local_irq_mask_save();
+                let pushed = queue.push(byte);
+                if pushed {
+                    start_tx();
+                } else if masked {
+                    if let Some(oldest) = queue.pop() {
+                        send(oldest);
+                    }
+                }
+                exception::local_irq_restore(saved);
+                if pushed {
+                    return;
+                }
+            }
+        }
+        None => send(byte),
+    }
+}
+
+/// Queue as many of `bytes` as fit for sending and return how many that was.
+///
+/// Only interrupt mode has a queue; while polling every byte is sent before returning.
+pub unsafe fn write(bytes: &[u8]) -> usize {
+    let queue = match TX_QUEUE {
+        Some(queue) => queue,
+        None => {
+            for &byte in bytes {
+                send(byte);
+            }
+            return bytes.len();
+        }
+    };
+    // a single producer at a time, see put_u8()
+    let saved = exception::local_irq_mask_save();
+    let written = bytes.iter().take_while(|&&byte| queue.push(byte)).count();
+    start_tx();
+    exception::local_irq_restore(saved);
+    written
+}
+
+/// Wait until every byte written has been sent.
+pub unsafe fn flush() {
+    if let Some(queue) = TX_QUEUE {
+        // with IRQs masked we may take over the interrupt handler's side of the queue
+        let saved = exception::local_irq_mask_save();
+        while let Some(byte) = queue.pop() {
+            send(byte);
+        }
+        exception::local_irq_restore(saved);
+    }
+    while core::ptr::read_volatile(&(*REGS).fr) & FR_BUSY != 0 {}
+}
+
+/// Bytes lost on receive since boot.
+pub fn overruns() -> Overruns {
+    Overruns {
+        fifo: FIFO_OVERRUNS.load(Ordering::Relaxed),
+        queue: QUEUE_OVERRUNS.load(Ordering::Relaxed),
+    }
+}
+
+/// Characters dropped because of framing or parity errors, and breaks received, since boot.
+pub fn line_errors() -> LineErrors {
+    LineErrors {
+        framing: FRAMING_ERRORS.load(Ordering::Relaxed),
+        parity: PARITY_ERRORS.load(Ordering::Relaxed),
+        breaks: BREAKS.load(Ordering::Relaxed),
+    }
+}
+
+#[test_case]
+fn test_loopback() {
+    use crate::timer;
+
+    unsafe {
+        // GPIO 32 and 33 leave the header pins to the mini UART console
+        let config = Config {
+            pins: Pins::Gpio32,
+            ..Config::default()
+        };
+        assert_eq!(init_with(config), Ok(()));
+        let cr = core::ptr::read_volatile(&(*REGS).cr);
+        core::ptr::write_volatile(&mut (*REGS).cr, cr | CR_LOOPBACK);
+
+        assert_eq!(write(b"pl011"), 5);
+        let mut received = alloc::vec::Vec::new();
+        let start: u32 = timer::get_ticks();
+        while received.len() < 5 && timer::get_ticks().wrapping_sub(start) < 100000 {
+            received.extend(try_read());
+        }
+        flush();
+        core::ptr::write_volatile(&mut (*REGS).cr, cr);
+        assert_eq!(received, b"pl011");
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Interrupt mode
+//--------------------------------------------------------------------------------------------------
+
+/// Switch to interrupt driven operation, with a receive queue of `RX` - 1 bytes and a transmit
+/// queue of `TX` - 1 bytes.
+///
+/// Afterwards bytes are received in the background, and `write()` and `put_u8()` only wait when
+/// the transmit queue is full. Calling it again has no effect. Must be called after `init()` and
+/// `interrupts::init()`.
+pub unsafe fn enable_interrupts<const RX: usize, const TX: usize>() {
+    assert!(RX > 1 && TX > 1, "pl011 queues must hold at least one byte");
+    if TX_QUEUE.is_some() {
+        return;
+    }
+    flush();
+    RX_QUEUE = Some(Box::leak(Box::new(RingBuffer::<RX>::new())));
+    TX_QUEUE = Some(Box::leak(Box::new(RingBuffer::<TX>::new())));
+
+    interrupts::register_handler(interrupts::INTERRUPTS_UART, || interrupt_handler());
+    interrupts::enable_source(interrupts::INTERRUPTS_UART);
+    cpu::dev_barrier();
+    core::ptr::write_volatile(&mut (*REGS).imsc, INT_RX | INT_RX_TIMEOUT);
+    cpu::dev_barrier();
+}
+
+// Moves queued bytes into the transmit FIFO until it is full; the caller must be the only one
+// popping from the queue
+unsafe fn fill_tx_fifo(queue: &dyn ByteQueue) {
+    while core::ptr::read_volatile(&(*REGS).fr) & FR_TX_FULL == 0 {
+        match queue.pop() {
+            Some(byte) => core::ptr::write_volatile(&mut (*REGS).dr, byte as u32),
+            None => return,
+        }
+    }
+}
+
+// The transmit interrupt only fires when the FIFO drains past its level, so sending is started
+// by filling the FIFO by hand. The interrupt stays enabled while there is something queued.
+unsafe fn start_tx() {
+    if let Some(queue) = TX_QUEUE {
+        let saved = exception::local_irq_mask_save();
+        cpu::dev_barrier();
+        fill_tx_fifo(queue);
+        if !queue.is_empty() {
+            core::ptr::write_volatile(&mut (*REGS).imsc, INT_RX | INT_RX_TIMEOUT | INT_TX);
+        }
+        cpu::dev_barrier();
+        exception::local_irq_restore(saved);
+    }
+}
+
+unsafe fn interrupt_handler() {
+    let (rx_queue, tx_queue) = match (RX_QUEUE, TX_QUEUE) {
+        (Some(rx_queue), Some(tx_queue)) => (rx_queue, tx_queue),
+        _ => return,
+    };
+    cpu::dev_barrier();
+    let pending = core::ptr::read_volatile(&(*REGS).mis);
+
+    if pending & (INT_RX | INT_RX_TIMEOUT) != 0 {
+        while let Some(byte) = receive() {
+            if BREAK_BYTE.is_data(byte) && !rx_queue.push(byte) {
+                QUEUE_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        core::ptr::write_volatile(&mut (*REGS).icr, INT_RX | INT_RX_TIMEOUT);
+    }
+
+    if pending & INT_TX != 0 {
+        core::ptr::write_volatile(&mut (*REGS).icr, INT_TX);
+        fill_tx_fifo(tx_queue);
+        if tx_queue.is_empty() {
+            core::ptr::write_volatile(&mut (*REGS).imsc, INT_RX | INT_RX_TIMEOUT);
+        }
+    }
+    cpu::dev_barrier();
+}
+
+/// The PL011 behind the `Serial` interface, e.g. for `serial::set_console()`.
+pub struct Pl011;
+
+impl Serial for Pl011 {
+    fn try_read(&self) -> Option<u8> {
+        unsafe { try_read() }
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        unsafe { write(bytes) }
+    }
+
+    fn put_u8(&self, byte: u8) {
+        unsafe { put_u8(byte) }
+    }
+
+    fn flush(&self) {
+        unsafe { flush() }
+    }
+
+    fn overruns(&self) -> Overruns {
+        overruns()
+    }
+
+    fn set_break_byte(&self, byte: Option<u8>) {
+        BREAK_BYTE.set(byte);
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * PS/2 device driver: a keyboard or mouse on a clock/data pair of GPIO pins.
+ *
+ * The device drives the clock for both directions. Device-to-host frames are
+ * received one bit per falling clock edge from the GPIO interrupt and queued
+ * as bytes. Host-to-device frames are requested by holding the clock low and
+ * then clocked out by polling, with reception paused.
+ *
+ * Frame format, lsb first: start bit (0), 8 data bits, odd parity bit, stop
+ * bit (1). The device answers every byte it receives with ACK (0xFA).
+ */
+
+use crate::exception;
+use crate::gpio;
+use crate::interrupts;
+use crate::ring_buffer::RingBuffer;
+use crate::timer::{self, Duration, Instant};
+
+mod decoder;
+
+use decoder::{encode_frame, FrameDecoder};
+
+pub const PS2_ACK: u8 = 0xFA;
+pub const PS2_RESEND: u8 = 0xFE;
+pub const PS2_SELF_TEST_PASSED: u8 = 0xAA;
+
+// After a request to send the device has up to 15ms to start clocking, then 2ms for the frame
+const RTS_TIMEOUT: Duration = Duration::from_millis(15);
+const BIT_TIMEOUT: Duration = Duration::from_millis(2);
+
+// Time the device has to answer a command byte
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(20);
+
+// A resend request is answered by sending the byte again, this many times at most
+const MAX_RESENDS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The device did not clock the frame in, or did not answer, in time.
+    Timeout,
+    /// The device answered with something other than ACK, e.g. 0xFC (error).
+    Response(u8),
+}
+
+pub struct Ps2DeviceT {
+    clock: u32,
+    data: u32,
+    // Bytes received by the clock interrupt handler, waiting to be read
+    received: RingBuffer<64>,
+    // Called from the interrupt handler with every byte queued, see init()
+    on_receive: Option<fn(u8)>,
+    frame: FrameDecoder,
+    // While set, the handler leaves the clock edges to the polling transmit code
+    sending: bool,
+    // While set, the next byte received is the answer to a command and goes to `response`
+    awaiting_response: bool,
+    response: Option<u8>,
+}
+
+impl Ps2DeviceT {
+    pub const fn new(clock: u32, data: u32) -> Self {
+        Ps2DeviceT {
+            clock,
+            data,
+            received: RingBuffer::new(),
+            on_receive: None,
+            frame: FrameDecoder::new(),
+            sending: false,
+            awaiting_response: false,
+            response: None,
+        }
+    }
+
+    /// Configure the pins and start receiving from the GPIO interrupt.
+    ///
+    /// `on_receive` is called in IRQ mode with every byte received, in order, before it is
+    /// queued for `read()`. Responses to `write()` are not queued and not passed to it.
+    pub unsafe fn init(&'static mut self, on_receive: Option<fn(u8)>) {
+        self.on_receive = on_receive;
+
+        gpio::set_input(self.clock as isize);
+        gpio::set_pullup(self.clock as isize);
+
+        gpio::set_input(self.data as isize);
+        gpio::set_pullup(self.data as isize);
+
+        // the device drives the clock; we sample data on every falling edge
+        gpio::enable_falling_edge_event(self.clock as isize);
+        let source = gpio::interrupt_source(self.clock as isize);
+        let dev: *mut Ps2DeviceT = self;
+        interrupts::register_handler(source, move || (*dev).clock_edge_handler());
+        interrupts::enable_source(source);
+    }
+
+    unsafe fn clock_edge_handler(&mut self) {
+        let clock = self.clock as isize;
+        if !gpio::check_event(clock) {
+            return; // another pin of the bank
+        }
+        gpio::clear_event(clock);
+        if self.sending {
+            return;
+        }
+
+        let bit = gpio::read(self.data as isize);
+        // frames with parity or framing errors and partial frames are dropped
+        if let Some(Ok(byte)) = self.frame.falling_edge(bit != 0, timer::get_ticks()) {
+            if self.awaiting_response {
+                self.awaiting_response = false;
+                self.response = Some(byte);
+            } else {
+                if let Some(on_receive) = self.on_receive {
+                    on_receive(byte);
+                }
+                self.received.push(byte); // a full queue drops the newest byte
+            }
+        }
+    }
+
+    /// Returns whether the device is not sending a frame and all received bytes have been read,
+    /// so that a command sent now interrupts nothing.
+    pub unsafe fn is_idle(&self) -> bool {
+        let saved = exception::local_irq_mask_save();
+        let idle = self.received.is_empty() && !self.frame.is_receiving(timer::get_ticks());
+        exception::local_irq_restore(saved);
+        idle
+    }
+
+    /// Returns the next received byte without waiting.
+    pub fn try_read(&self) -> Option<u8> {
+        self.received.pop()
+    }
+
+    /// Waits up to `timeout` for the next received byte.
+    pub unsafe fn read(&self, timeout: Duration) -> Option<u8> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(byte) = self.received.pop() {
+                return Some(byte);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+        }
+    }
+
+    // Waits for the clock pin to read `level`, false on timeout
+    unsafe fn wait_clock(&self, level: u32, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while gpio::read(self.clock as isize) != level {
+            if Instant::now() >= deadline {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Clocks one frame out to the device. Returns whether the device acknowledged the frame
+    // with its ACK bit; this is the line level handshake, not the 0xFA response byte.
+    unsafe fn send_frame(&mut self, byte: u8) -> bool {
+        let clock = self.clock as isize;
+        let data = self.data as isize;
+        let frame = encode_frame(byte);
+
+        // Request to send: inhibit the device by holding the clock low for over 100us, pull data
+        // low as our start bit, then release the clock for the device to clock the frame in.
+        gpio::write(clock, 0);
+        gpio::set_output(clock);
+        timer::delay_us(120);
+        gpio::write(data, 0);
+        gpio::set_output(data);
+        gpio::set_input(clock);
+
+        // The device samples each bit on the rising edge; we change data while the clock is low
+        let mut acked = self.wait_clock(0, RTS_TIMEOUT);
+        for i in 1..10 {
+            if !acked {
+                break;
+            }
+            gpio::write(data, (frame >> i) & 1);
+            acked = self.wait_clock(1, BIT_TIMEOUT) && self.wait_clock(0, BIT_TIMEOUT);
+        }
+
+        // Stop bit: release data and let the pullup drive it high. The device then answers by
+        // pulling data low for one more clock.
+        gpio::set_input(data);
+        acked = acked && self.wait_clock(1, BIT_TIMEOUT) && self.wait_clock(0, BIT_TIMEOUT);
+        acked = acked && gpio::read(data) == 0;
+        acked && self.wait_clock(1, BIT_TIMEOUT)
+    }
+
+    /// Sends `byte` to the device and returns its response, normally `PS2_ACK`.
+    ///
+    /// The device stops sending while it processes a command, so the response is the next byte
+    /// received; it is returned here rather than queued for `read()`. Resend requests are
+    /// handled by sending the byte again.
+    pub unsafe fn write(&mut self, byte: u8) -> Result<u8, Ps2Error> {
+        for _ in 0..=MAX_RESENDS {
+            match self.write_once(byte)? {
+                PS2_RESEND => continue,
+                response => return Ok(response),
+            }
+        }
+        Ok(PS2_RESEND)
+    }
+
+    unsafe fn write_once(&mut self, byte: u8) -> Result<u8, Ps2Error> {
+        let clock = self.clock as isize;
+
+        // the interrupt handler reads these, so they are accessed with volatile operations
+        core::ptr::write_volatile(&mut self.response, None);
+        core::ptr::write_volatile(&mut self.awaiting_response, true);
+        core::ptr::write_volatile(&mut self.sending, true);
+        let acked = self.send_frame(byte);
+        // an inhibited device abandons the frame it was sending and repeats it later
+        self.frame.reset();
+        gpio::clear_event(clock);
+        core::ptr::write_volatile(&mut self.sending, false);
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        while acked && Instant::now() < deadline {
+            if let Some(response) = core::ptr::read_volatile(&self.response) {
+                return Ok(response);
+            }
+        }
+        core::ptr::write_volatile(&mut self.awaiting_response, false);
+        Err(Ps2Error::Timeout)
+    }
+
+    /// Sends a command byte followed by its arguments, checking that each is acknowledged.
+    pub unsafe fn command(&mut self, bytes: &[u8]) -> Result<(), Ps2Error> {
+        for &byte in bytes {
+            match self.write(byte)? {
+                PS2_ACK => {}
+                response => return Err(Ps2Error::Response(response)),
+            }
+        }
+        Ok(())
+    }
+}
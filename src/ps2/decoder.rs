@@ -0,0 +1,130 @@
+/*
+ * PS/2 frame decoding, fed with samples of the clock and data lines.
+ *
+ * Tested on the host against recorded traces, see host/tests/traces.rs.
+ */
+
+// A frame takes ~1ms; a longer gap between two clock edges means we lost sync mid-frame
+pub const FRAME_TIMEOUT_US: u32 = 2000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameError {
+    /// The parity bit does not make the number of set data bits odd.
+    Parity,
+    /// The start bit was not 0 or the stop bit was not 1.
+    Framing,
+    /// The clock stopped mid-frame; the bits received so far were dropped.
+    Timeout,
+}
+
+/// Returns the odd parity bit for `data`.
+pub fn parity(data: u8) -> u32 {
+    (data.count_ones() + 1) % 2
+}
+
+/// Returns the 11 bit frame carrying `data`, start bit first.
+pub fn encode_frame(data: u8) -> u32 {
+    (1 << 10) | (parity(data) << 9) | ((data as u32) << 1)
+}
+
+/// Returns the data byte of a complete 11 bit frame if start, parity and stop bits check out.
+pub fn decode_frame(frame: u32) -> Result<u8, FrameError> {
+    let start = frame & 1;
+    let data = ((frame >> 1) & 0xff) as u8;
+    let stop = (frame >> 10) & 1;
+
+    if start != 0 || stop != 1 {
+        return Err(FrameError::Framing);
+    }
+    if (frame >> 9) & 1 != parity(data) {
+        return Err(FrameError::Parity);
+    }
+    Ok(data)
+}
+
+/// Assembles device-to-host frames one bit per falling clock edge.
+pub struct FrameDecoder {
+    // Partially received frame, lsb first
+    bits: u32,
+    nbits: u32,
+    last_edge: u32,
+    // Clock level of the last sample, to find the edges in a stream of samples
+    clock: bool,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self {
+            bits: 0,
+            nbits: 0,
+            last_edge: 0,
+            clock: true, // idle
+        }
+    }
+
+    /// Drop the partially received frame, if any.
+    pub fn reset(&mut self) {
+        self.bits = 0;
+        self.nbits = 0;
+    }
+
+    /// Returns whether a frame is coming in at `time_us`: some of its bits have, the last less
+    /// than the frame timeout before.
+    pub fn is_receiving(&self, time_us: u32) -> bool {
+        self.nbits != 0 && time_us.wrapping_sub(self.last_edge) <= FRAME_TIMEOUT_US
+    }
+
+    /// Feed one sample of both lines taken at `time_us`, e.g. while polling or from a trace.
+    ///
+    /// Returns the byte or error of a frame completed by a falling clock edge, or a timeout
+    /// once the clock has been idle for too long mid-frame.
+    pub fn sample(
+        &mut self,
+        clock: bool,
+        data: bool,
+        time_us: u32,
+    ) -> Option<Result<u8, FrameError>> {
+        let falling = self.clock && !clock;
+        self.clock = clock;
+        if falling {
+            return self.falling_edge(data, time_us);
+        }
+        if self.nbits != 0 && time_us.wrapping_sub(self.last_edge) > FRAME_TIMEOUT_US {
+            self.reset();
+            return Some(Err(FrameError::Timeout));
+        }
+        None
+    }
+
+    /// Feed the data line as sampled on a falling clock edge at `time_us`.
+    ///
+    /// Returns the byte or error of the frame this edge completes. An edge after a long pause
+    /// mid-frame drops the partial frame and returns a timeout; the edge itself may then be
+    /// the start bit of the next frame.
+    pub fn falling_edge(&mut self, data: bool, time_us: u32) -> Option<Result<u8, FrameError>> {
+        let mut result = None;
+        if self.nbits != 0 && time_us.wrapping_sub(self.last_edge) > FRAME_TIMEOUT_US {
+            self.reset();
+            result = Some(Err(FrameError::Timeout));
+        }
+        self.last_edge = time_us;
+
+        if self.nbits == 0 && data {
+            return result; // not a start bit, keep waiting
+        }
+        self.bits |= (data as u32) << self.nbits;
+        self.nbits += 1;
+
+        if self.nbits == 11 {
+            result = Some(decode_frame(self.bits));
+            self.reset();
+        }
+        result
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
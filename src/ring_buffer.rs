@@ -0,0 +1,116 @@
+/*
+ * Fixed size byte queue that is safe to share between one producer and
+ * one consumer without locking, e.g. an interrupt handler pushing bytes
+ * received from a device and the main loop popping them.
+ */
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Single producer, single consumer queue of bytes holding up to `N - 1` entries.
+pub struct RingBuffer<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    head: AtomicUsize, // next slot to write, only advanced by the producer
+    tail: AtomicUsize, // next slot to read, only advanced by the consumer
+}
+
+// The producer and the consumer never touch the same slot, see push and pop.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append `byte`, returning false (and dropping it) if the queue is full.
+    ///
+    /// Must only be called from the producer side.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe {
+            (*self.buffer.get())[head] = byte;
+        }
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    /// Remove and return the oldest byte, if any.
+    ///
+    /// Must only be called from the consumer side.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.buffer.get())[tail] };
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(byte)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Acquire) == self.head.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + N - tail) % N
+    }
+}
+
+/// A RingBuffer of any size, for drivers that let their user choose the size of their queues.
+pub trait ByteQueue: Sync {
+    fn push(&self, byte: u8) -> bool;
+    fn pop(&self) -> Option<u8>;
+    fn is_empty(&self) -> bool;
+    fn len(&self) -> usize;
+    /// The number of entries the queue can hold.
+    fn capacity(&self) -> usize;
+}
+
+impl<const N: usize> ByteQueue for RingBuffer<N> {
+    fn push(&self, byte: u8) -> bool {
+        RingBuffer::push(self, byte)
+    }
+
+    fn pop(&self) -> Option<u8> {
+        RingBuffer::pop(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        RingBuffer::is_empty(self)
+    }
+
+    fn len(&self) -> usize {
+        RingBuffer::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        N - 1
+    }
+}
+
+#[test_case]
+fn test_ring_buffer() {
+    let queue: RingBuffer<4> = RingBuffer::new();
+    assert!(queue.is_empty());
+    assert!(queue.push(1));
+    assert!(queue.push(2));
+    assert!(queue.push(3));
+    assert!(!queue.push(4)); // holds N - 1 entries
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.pop(), Some(1));
+    assert!(queue.push(4));
+    assert_eq!(queue.pop(), Some(2));
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), Some(4));
+    assert_eq!(queue.pop(), None);
+}
@@ -0,0 +1,123 @@
+/*
+ * A framed binary request/response channel on a serial port, for tools on
+ * the host that query the board or push data to it. The shell's `rpc`
+ * command hands the console over to it until the host closes the session;
+ * host/src/rpc_client.rs is the other end.
+ *
+ * Frames are COBS encoded between zero bytes and carry a CRC-32 and a
+ * sequence number, see rpc/frame.rs. The host sends a request again if the
+ * response does not arrive, or if the Pi answers with a NAK. Logging to the
+ * console meanwhile is fine: the text never looks like a frame.
+ *
+ * frame.rs, message.rs and server.rs are also built and tested on the host,
+ * see host/src/lib.rs.
+ */
+
+use crate::serial::Serial;
+use crate::{allocator, cpu, gpio, timer};
+
+pub mod frame;
+// Also has the host's half, encoding requests and decoding responses, for host/src/rpc_client.rs
+#[allow(dead_code)]
+pub mod message;
+pub mod server;
+
+use message::{ErrorCode, Request, Response};
+use server::Server;
+
+// The BCM2835 has GPIO 0-53
+const GPIO_PINS: u8 = 54;
+
+static mut SERVER: Server = Server::new();
+
+/// Answer requests from `serial` until the host closes the session.
+pub fn serve(serial: &dyn Serial) {
+    let server = unsafe { &mut SERVER };
+    server.reset();
+    let mut closed = false;
+    while !closed {
+        let byte = serial.read();
+        server.feed(
+            byte,
+            &mut |request, scratch| {
+                closed = request == Request::Close;
+                dispatch(request, scratch)
+            },
+            &mut |byte| serial.put_u8(byte),
+        );
+    }
+    serial.flush();
+}
+
+// Carries out `request`, with `scratch` for the data of the response
+fn dispatch<'a>(request: Request<'_>, scratch: &'a mut [u8]) -> Response<'a> {
+    match request {
+        Request::Ping(value) => Response::Pong(value),
+        Request::Ticks => Response::Ticks(unsafe { timer::get_ticks() }),
+        Request::ReadMemory { address, len } => {
+            let data = &mut scratch[..len as usize];
+            unsafe { read_memory(address, data) };
+            Response::Memory(data)
+        }
+        Request::WriteMemory { address, data } => {
+            unsafe { write_memory(address, data) };
+            Response::Done
+        }
+        Request::ReadGpio { pin } if pin < GPIO_PINS => {
+            let pin = pin as isize;
+            let (function, level) = unsafe { (gpio::get_function(pin), gpio::read(pin)) };
+            Response::Gpio {
+                function: function as u8,
+                level: level != 0,
+            }
+        }
+        Request::WriteGpio { pin, level } if pin < GPIO_PINS => {
+            unsafe {
+                gpio::set_output(pin as isize);
+                gpio::write(pin as isize, level as u32);
+            }
+            Response::Done
+        }
+        Request::ReadGpio { .. } | Request::WriteGpio { .. } => Response::Error(ErrorCode::BadPin),
+        Request::Heap => {
+            let stats = allocator::stats();
+            Response::Heap {
+                used: stats.used_bytes as u32,
+                free: stats.free_bytes as u32,
+            }
+        }
+        Request::Close => Response::Done,
+    }
+}
+
+// Reads word by word if `address` and the length are word aligned, so that device registers
+// can be read, and byte by byte otherwise
+unsafe fn read_memory(address: u32, data: &mut [u8]) {
+    cpu::dev_barrier();
+    if address % 4 == 0 && data.len() % 4 == 0 {
+        for (i, word) in data.chunks_exact_mut(4).enumerate() {
+            let value = (address as *const u32).add(i).read_volatile();
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+    } else {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (address as *const u8).add(i).read_volatile();
+        }
+    }
+    cpu::dev_barrier();
+}
+
+unsafe fn write_memory(address: u32, data: &[u8]) {
+    cpu::dev_barrier();
+    if address % 4 == 0 && data.len() % 4 == 0 {
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            (address as *mut u32).add(i).write_volatile(value);
+        }
+    } else {
+        for (i, &byte) in data.iter().enumerate() {
+            (address as *mut u8).add(i).write_volatile(byte);
+        }
+    }
+    cpu::dev_barrier();
+}
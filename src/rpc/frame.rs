@@ -0,0 +1,211 @@
+/*
+ * Framing of the RPC channel: each frame is a kind byte, a sequence number,
+ * the message body and a CRC-32 of all of those, little endian. On the line
+ * it is COBS encoded, which leaves it without zero bytes, and sent between
+ * two zero bytes.
+ *
+ * Console text never contains a zero byte either, so text printed between
+ * frames ends up in a chunk of its own that fails to decode, and is handed
+ * back as rejected rather than mistaken for or mixed into a frame.
+ */
+
+/// A request from the host.
+pub const REQUEST: u8 = 1;
+/// The answer to the request with the same sequence number, which also acknowledges it.
+pub const RESPONSE: u8 = 2;
+/// Sent by the Pi for a frame that arrived garbled. The host sends its request again.
+pub const NAK: u8 = 3;
+
+/// Largest message body carried by a frame.
+pub const MAX_BODY: usize = 512;
+
+/// The kind and the sequence number in front of the body.
+pub const HEADER_SIZE: usize = 2;
+pub const CRC_SIZE: usize = 4;
+pub const MAX_FRAME: usize = HEADER_SIZE + MAX_BODY + CRC_SIZE;
+
+/// Largest frame once COBS encoded, without the delimiters.
+pub const MAX_ENCODED: usize = MAX_FRAME + MAX_FRAME / 254 + 1;
+
+const DELIMITER: u8 = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// More than MAX_ENCODED bytes arrived between two delimiters.
+    TooLong,
+    /// The bytes are not valid COBS.
+    Encoding,
+    /// Too short to hold the header and the CRC.
+    TooShort,
+    /// The CRC does not match.
+    Checksum,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: u8,
+    pub seq: u8,
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received<'a> {
+    Frame(Frame<'a>),
+    /// What arrived between two delimiters was not a frame: line noise, a garbled frame, or
+    /// text. `raw` holds it as received, cut off at MAX_ENCODED bytes.
+    Rejected {
+        raw: &'a [u8],
+        error: FrameError,
+    },
+}
+
+pub struct FrameReader {
+    raw: [u8; MAX_ENCODED],
+    len: usize,
+    overflow: bool,
+    decoded: [u8; MAX_FRAME],
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        FrameReader {
+            raw: [0; MAX_ENCODED],
+            len: 0,
+            overflow: false,
+            decoded: [0; MAX_FRAME],
+        }
+    }
+
+    /// Handle `byte` from the line. Returns what arrived once a delimiter ends it.
+    pub fn feed(&mut self, byte: u8) -> Option<Received<'_>> {
+        if byte != DELIMITER {
+            if self.len < MAX_ENCODED {
+                self.raw[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let (len, overflow) = (self.len, self.overflow);
+        self.len = 0;
+        self.overflow = false;
+        // back to back delimiters, e.g. the end of one frame and the start of the next
+        if len == 0 {
+            return None;
+        }
+        let raw = &self.raw[..len];
+        let reject = |error| Some(Received::Rejected { raw, error });
+        if overflow {
+            return reject(FrameError::TooLong);
+        }
+        let decoded_len = match cobs_decode(raw, &mut self.decoded) {
+            Some(decoded_len) => decoded_len,
+            None => return reject(FrameError::Encoding),
+        };
+        if decoded_len < HEADER_SIZE + CRC_SIZE {
+            return reject(FrameError::TooShort);
+        }
+        let (frame, crc) = self.decoded[..decoded_len].split_at(decoded_len - CRC_SIZE);
+        if crc32(frame).to_le_bytes() != crc {
+            return reject(FrameError::Checksum);
+        }
+        Some(Received::Frame(Frame {
+            kind: frame[0],
+            seq: frame[1],
+            body: &frame[HEADER_SIZE..],
+        }))
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fills in the header and the CRC of the frame in `frame`, whose body of `body_len` bytes is
+/// already in place after HEADER_SIZE bytes. Returns the length of the frame.
+pub fn seal(frame: &mut [u8], kind: u8, seq: u8, body_len: usize) -> usize {
+    frame[0] = kind;
+    frame[1] = seq;
+    let len = HEADER_SIZE + body_len;
+    let crc = crc32(&frame[..len]);
+    frame[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+    len + CRC_SIZE
+}
+
+/// Calls `put` with the bytes that send `frame`, as made by `seal()`.
+pub fn send(frame: &[u8], put: &mut dyn FnMut(u8)) {
+    put(DELIMITER);
+    cobs_encode(frame, put);
+    put(DELIMITER);
+}
+
+/// Calls `put` with `data` COBS encoded: without zero bytes, and one byte longer, plus one more
+/// for each 254 bytes without a zero.
+pub fn cobs_encode(data: &[u8], put: &mut dyn FnMut(u8)) {
+    let mut start = 0;
+    loop {
+        // each block is a length byte and up to 254 bytes up to the next zero, which it stands for
+        let run = data[start..]
+            .iter()
+            .take(254)
+            .take_while(|&&byte| byte != 0)
+            .count();
+        put(run as u8 + 1);
+        for &byte in &data[start..start + run] {
+            put(byte);
+        }
+        start += run;
+        if start == data.len() {
+            return;
+        }
+        // a full block stands for no zero
+        if run < 254 {
+            start += 1;
+        }
+    }
+}
+
+/// Decodes COBS `encoded` into `out`, returning how many bytes that was.
+pub fn cobs_decode(encoded: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut index = 0;
+    while index < encoded.len() {
+        let code = encoded[index] as usize;
+        let end = index + code;
+        if code == 0 || end > encoded.len() {
+            return None;
+        }
+        let run = &encoded[index + 1..end];
+        if run.contains(&0) {
+            return None;
+        }
+        out.get_mut(len..len + run.len())?.copy_from_slice(run);
+        len += run.len();
+        index = end;
+        if code < 0xff && index < encoded.len() {
+            *out.get_mut(len)? = 0;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// The CRC-32 of zlib and Ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,255 @@
+/*
+ * The requests the host can make over the RPC channel and their responses,
+ * and how they are laid out in a frame's body: an opcode byte followed by
+ * the fields, little endian.
+ */
+
+use super::frame::MAX_BODY;
+
+/// Most bytes of memory read or written by one request, leaving room in the body for the
+/// fields around them.
+pub const MAX_DATA: usize = MAX_BODY - 16;
+
+const PING: u8 = 0x01;
+const TICKS: u8 = 0x02;
+const READ_MEMORY: u8 = 0x03;
+const WRITE_MEMORY: u8 = 0x04;
+const READ_GPIO: u8 = 0x05;
+const WRITE_GPIO: u8 = 0x06;
+const HEAP: u8 = 0x07;
+const CLOSE: u8 = 0x08;
+
+const PONG: u8 = 0x81;
+const TICKS_ARE: u8 = 0x82;
+const MEMORY: u8 = 0x83;
+const DONE: u8 = 0x84;
+const GPIO: u8 = 0x85;
+const HEAP_IS: u8 = 0x86;
+const ERROR: u8 = 0xff;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// Answered with `Pong` and the same value.
+    Ping(u32),
+    /// The system timer, in microseconds since boot.
+    Ticks,
+    /// Up to MAX_DATA bytes of memory. Whole words are read as words.
+    ReadMemory { address: u32, len: u16 },
+    /// Store up to MAX_DATA bytes. Whole words are written as words.
+    WriteMemory { address: u32, data: &'a [u8] },
+    /// The function and level of a GPIO pin.
+    ReadGpio { pin: u8 },
+    /// Make a GPIO pin an output and drive it to `level`.
+    WriteGpio { pin: u8, level: bool },
+    /// Bytes used and free on the heap.
+    Heap,
+    /// End the session, returning the Pi to the shell.
+    Close,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    Pong(u32),
+    Ticks(u32),
+    Memory(&'a [u8]),
+    /// The request was carried out.
+    Done,
+    Gpio {
+        function: u8,
+        level: bool,
+    },
+    Heap {
+        used: u32,
+        free: u32,
+    },
+    Error(ErrorCode),
+}
+
+/// Why a request failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The body is cut short, too long, or has a bad field.
+    Malformed = 1,
+    /// The opcode is not known.
+    Unknown = 2,
+    /// More than MAX_DATA bytes were asked for.
+    TooLong = 3,
+    /// There is no such GPIO pin.
+    BadPin = 4,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Result<Self, ErrorCode> {
+        match code {
+            1 => Ok(ErrorCode::Malformed),
+            2 => Ok(ErrorCode::Unknown),
+            3 => Ok(ErrorCode::TooLong),
+            4 => Ok(ErrorCode::BadPin),
+            _ => Err(ErrorCode::Malformed),
+        }
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Writes the request into `out`, returning its length, or None if it does not fit.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut writer = Writer { out, len: 0 };
+        match *self {
+            Request::Ping(value) => writer.put(&[PING])?.put(&value.to_le_bytes())?,
+            Request::Ticks => writer.put(&[TICKS])?,
+            Request::ReadMemory { address, len } => writer
+                .put(&[READ_MEMORY])?
+                .put(&address.to_le_bytes())?
+                .put(&len.to_le_bytes())?,
+            Request::WriteMemory { address, data } => writer
+                .put(&[WRITE_MEMORY])?
+                .put(&address.to_le_bytes())?
+                .put(data)?,
+            Request::ReadGpio { pin } => writer.put(&[READ_GPIO, pin])?,
+            Request::WriteGpio { pin, level } => writer.put(&[WRITE_GPIO, pin, level as u8])?,
+            Request::Heap => writer.put(&[HEAP])?,
+            Request::Close => writer.put(&[CLOSE])?,
+        };
+        Some(writer.len)
+    }
+
+    pub fn decode(body: &'a [u8]) -> Result<Self, ErrorCode> {
+        let mut reader = Reader { bytes: body };
+        let request = match reader.u8()? {
+            PING => Request::Ping(reader.u32()?),
+            TICKS => Request::Ticks,
+            READ_MEMORY => {
+                let address = reader.u32()?;
+                let len = reader.u16()?;
+                if len as usize > MAX_DATA {
+                    return Err(ErrorCode::TooLong);
+                }
+                Request::ReadMemory { address, len }
+            }
+            WRITE_MEMORY => {
+                let address = reader.u32()?;
+                let data = reader.rest();
+                if data.len() > MAX_DATA {
+                    return Err(ErrorCode::TooLong);
+                }
+                Request::WriteMemory { address, data }
+            }
+            READ_GPIO => Request::ReadGpio { pin: reader.u8()? },
+            WRITE_GPIO => Request::WriteGpio {
+                pin: reader.u8()?,
+                level: reader.bool()?,
+            },
+            HEAP => Request::Heap,
+            CLOSE => Request::Close,
+            _ => return Err(ErrorCode::Unknown),
+        };
+        reader.end()?;
+        Ok(request)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Writes the response into `out`, returning its length, or None if it does not fit.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut writer = Writer { out, len: 0 };
+        match *self {
+            Response::Pong(value) => writer.put(&[PONG])?.put(&value.to_le_bytes())?,
+            Response::Ticks(ticks) => writer.put(&[TICKS_ARE])?.put(&ticks.to_le_bytes())?,
+            Response::Memory(data) => writer.put(&[MEMORY])?.put(data)?,
+            Response::Done => writer.put(&[DONE])?,
+            Response::Gpio { function, level } => writer.put(&[GPIO, function, level as u8])?,
+            Response::Heap { used, free } => writer
+                .put(&[HEAP_IS])?
+                .put(&used.to_le_bytes())?
+                .put(&free.to_le_bytes())?,
+            Response::Error(code) => writer.put(&[ERROR, code as u8])?,
+        };
+        Some(writer.len)
+    }
+
+    pub fn decode(body: &'a [u8]) -> Result<Self, ErrorCode> {
+        let mut reader = Reader { bytes: body };
+        let response = match reader.u8()? {
+            PONG => Response::Pong(reader.u32()?),
+            TICKS_ARE => Response::Ticks(reader.u32()?),
+            MEMORY => Response::Memory(reader.rest()),
+            DONE => Response::Done,
+            GPIO => Response::Gpio {
+                function: reader.u8()?,
+                level: reader.bool()?,
+            },
+            HEAP_IS => Response::Heap {
+                used: reader.u32()?,
+                free: reader.u32()?,
+            },
+            ERROR => Response::Error(ErrorCode::from_u8(reader.u8()?)?),
+            _ => return Err(ErrorCode::Unknown),
+        };
+        reader.end()?;
+        Ok(response)
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn put(&mut self, bytes: &[u8]) -> Option<&mut Self> {
+        let end = self.len + bytes.len();
+        self.out.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(self)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ErrorCode> {
+        if self.bytes.len() < len {
+            return Err(ErrorCode::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ErrorCode> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ErrorCode> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ErrorCode::Malformed),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, ErrorCode> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ErrorCode> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.bytes)
+    }
+
+    // Fails for bytes left over after the last field
+    fn end(&self) -> Result<(), ErrorCode> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorCode::Malformed)
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * The Pi's end of the RPC channel: takes requests from the line, has them
+ * carried out, and sends the responses.
+ *
+ * The host waits for the response to each request before it sends the
+ * next, and sends the request again if the response does not arrive. So
+ * that a request whose response got lost is not carried out twice, the
+ * last response is kept and sent again for a request with the same
+ * sequence number.
+ */
+
+use super::frame::{self, FrameReader, Received, CRC_SIZE, HEADER_SIZE, MAX_FRAME};
+use super::message::{ErrorCode, Request, Response, MAX_DATA};
+
+pub struct Server {
+    reader: FrameReader,
+    // The sequence number of the request `response` answers
+    last_seq: Option<u8>,
+    response: [u8; MAX_FRAME],
+    response_len: usize,
+    scratch: [u8; MAX_DATA],
+}
+
+impl Server {
+    pub const fn new() -> Self {
+        Server {
+            reader: FrameReader::new(),
+            last_seq: None,
+            response: [0; MAX_FRAME],
+            response_len: 0,
+            scratch: [0; MAX_DATA],
+        }
+    }
+
+    /// Forget the last request, for a new session.
+    pub fn reset(&mut self) {
+        self.reader = FrameReader::new();
+        self.last_seq = None;
+    }
+
+    /// Handle `byte` from the line. Once a request is complete, `dispatch` carries it out, with
+    /// room for the data of its response, and `put` is called with the bytes to send back.
+    pub fn feed(
+        &mut self,
+        byte: u8,
+        dispatch: &mut dyn for<'a> FnMut(Request<'_>, &'a mut [u8]) -> Response<'a>,
+        put: &mut dyn FnMut(u8),
+    ) {
+        let frame = match self.reader.feed(byte) {
+            None => return,
+            Some(Received::Frame(frame)) => frame,
+            Some(Received::Rejected { .. }) => {
+                let mut nak = [0; HEADER_SIZE + CRC_SIZE];
+                let len = frame::seal(&mut nak, frame::NAK, 0, 0);
+                frame::send(&nak[..len], put);
+                return;
+            }
+        };
+        if frame.kind != frame::REQUEST {
+            return;
+        }
+
+        if self.last_seq != Some(frame.seq) {
+            let response = match Request::decode(frame.body) {
+                Ok(request) => dispatch(request, &mut self.scratch),
+                Err(code) => Response::Error(code),
+            };
+            let body = &mut self.response[HEADER_SIZE..MAX_FRAME - CRC_SIZE];
+            let body_len = match response.encode(body) {
+                Some(body_len) => body_len,
+                None => Response::Error(ErrorCode::TooLong)
+                    .encode(body)
+                    .unwrap_or(0),
+            };
+            self.response_len =
+                frame::seal(&mut self.response, frame::RESPONSE, frame.seq, body_len);
+            self.last_seq = Some(frame.seq);
+        }
+        frame::send(&self.response[..self.response_len], put);
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -6,7 +6,7 @@
 
 //! Rust runtime initialization code.
 
-use crate::{allocator, bsp, cpu, memory, uart};
+use crate::{allocator, bsp, cpu, exception, interrupts, log, memory, timer, uart};
 
 //--------------------------------------------------------------------------------------------------
 // Private Code
@@ -22,6 +22,15 @@ unsafe fn zero_bss() {
     memory::zero_volatile(bsp::memory::bss_range_inclusive());
 }
 
+/// Move the console from the mini UART to the PL011, unless the PL011 can't be set up.
+#[cfg(feature = "console_pl011")]
+unsafe fn console_pl011() {
+    if crate::pl011::init().is_ok() {
+        crate::pl011::enable_interrupts::<256, 4096>();
+        crate::serial::set_console(&crate::pl011::Pl011);
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Public Code
 //--------------------------------------------------------------------------------------------------
@@ -35,8 +44,20 @@ unsafe fn zero_bss() {
 #[no_mangle]
 pub unsafe fn runtime_init() -> ! {
     zero_bss();
+    // before anything waits with timer::sleep()
+    cpu::enable_cycle_counter();
+    exception::handling_init();
     uart::init();
     allocator::init();
+    interrupts::init();
+    uart::enable_interrupts::<256, 4096>();
+    timer::init();
+    #[cfg(feature = "console_pl011")]
+    console_pl011();
+    log::init();
+    #[cfg(feature = "gdb")]
+    crate::gdb::init(crate::serial::console());
+    interrupts::global_enable();
 
     #[cfg(test)]
     crate::test_main();
@@ -0,0 +1,147 @@
+/*
+ * The interface the UART drivers share, and the console: the serial port
+ * that print! and the exception handlers write to.
+ *
+ * The console is the mini UART unless another port is selected with
+ * set_console(), e.g. the PL011 while the mini UART talks to a device.
+ */
+
+use crate::exception;
+use crate::uart::MiniUart;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Bytes lost on receive in interrupt mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Overruns {
+    /// Lost because the UART's receive FIFO filled up before the interrupt handler ran.
+    pub fifo: u32,
+    /// Lost because the receive queue was full, i.e. try_read() is not called often enough.
+    pub queue: u32,
+}
+
+/// A serial port, set up by its driver's `init()`.
+pub trait Serial: Sync {
+    /// Returns the next byte received without waiting, if there is one. Also works with IRQs
+    /// masked, e.g. in a debugger.
+    fn try_read(&self) -> Option<u8>;
+
+    /// Queue as many of `bytes` as fit for sending and return how many that was.
+    fn write(&self, bytes: &[u8]) -> usize;
+
+    /// Send `byte`, waiting for room if need be. Also works with IRQs masked, e.g. while
+    /// panicking.
+    fn put_u8(&self, byte: u8);
+
+    /// Wait until every byte written has been sent.
+    fn flush(&self);
+
+    /// Bytes lost on receive since boot.
+    fn overruns(&self) -> Overruns;
+
+    /// Have the port take `byte` received in interrupt mode for a request to stop the running
+    /// code rather than data, see `exception::request_break()`. None turns this off. Other
+    /// ports keep passing the byte on.
+    fn set_break_byte(&self, byte: Option<u8>);
+
+    /// Waits for the next byte received.
+    fn read(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read() {
+                return byte;
+            }
+        }
+    }
+
+    /// Sends the bytes of `string` as they are, without translating line endings.
+    fn put_string(&self, string: &str) {
+        for byte in string.bytes() {
+            self.put_u8(byte);
+        }
+    }
+
+    /// Sends `value` as 0x followed by eight hex digits, without going through core::fmt.
+    fn put_hex(&self, value: u32) {
+        self.put_string("0x");
+        for i in (0..8).rev() {
+            let digit = ((value >> (i * 4)) & 0xf) as u8;
+            self.put_u8(if digit < 10 {
+                b'0' + digit
+            } else {
+                b'a' + digit - 10
+            });
+        }
+    }
+}
+
+static mut CONSOLE: &dyn Serial = &MiniUart;
+
+// Outside of the u8 range while there is no break byte
+const NO_BREAK_BYTE: u32 = 0x100;
+
+/// The break byte of a port, for its driver's `Serial::set_break_byte()`.
+pub struct BreakByte(AtomicU32);
+
+impl BreakByte {
+    pub const fn new() -> Self {
+        BreakByte(AtomicU32::new(NO_BREAK_BYTE))
+    }
+
+    pub fn set(&self, byte: Option<u8>) {
+        self.0
+            .store(byte.map_or(NO_BREAK_BYTE, u32::from), Ordering::Relaxed);
+    }
+
+    /// Called by the port's receive interrupt handler with every byte received. Returns whether
+    /// it is data, and requests a break if it is the break byte instead.
+    pub fn is_data(&self, byte: u8) -> bool {
+        if u32::from(byte) == self.0.load(Ordering::Relaxed) {
+            exception::request_break();
+            return false;
+        }
+        true
+    }
+}
+
+/// Make `serial` the console. What was written to the old console is sent first.
+pub fn set_console(serial: &'static dyn Serial) {
+    unsafe {
+        CONSOLE.flush();
+        CONSOLE = serial;
+    }
+}
+
+/// The serial port printing goes to.
+pub fn console() -> &'static dyn Serial {
+    unsafe { CONSOLE }
+}
+
+// Calls `put` with the bytes of `string`, sending a CR before every LF
+fn for_each_crlf_byte(string: &str, mut put: impl FnMut(u8)) {
+    for byte in string.bytes() {
+        if byte == b'\n' {
+            put(b'\r');
+        }
+        put(byte);
+    }
+}
+
+/// The console as a `core::fmt::Write` sink, for `print!` and friends.
+///
+/// Strings are sent as UTF-8, with each '\n' turned into the CR-LF terminals expect.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let console = console();
+        for_each_crlf_byte(string, |byte| console.put_u8(byte));
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_crlf_translation() {
+    let mut sent = alloc::vec::Vec::new();
+    for_each_crlf_byte("a\nb\n", |byte| sent.push(byte));
+    assert_eq!(sent, b"a\r\nb\r\n");
+}
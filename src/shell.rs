@@ -0,0 +1,557 @@
+/*
+ * A monitor shell on the serial console, for poking at the board without
+ * editing main and reflashing. Type `help` for the commands.
+ *
+ * Addresses are physical; there is no MMU setup. Numbers are decimal or
+ * hex with a 0x prefix.
+ */
+
+use crate::serial::Serial;
+use crate::soft_uart::{SoftUart, SoftUartError};
+use crate::timer::Duration;
+use crate::{
+    allocator, arm_timer, bootloader, cpu, fb, gdb, gl, gpio, log, print, println, rpc, serial,
+    soft_uart, space_invaders, timer, watchdog,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+mod line;
+
+use line::LineEditor;
+
+// How long `boot` waits for the transfer to start
+const BOOT_WAIT_S: u32 = 60;
+
+// The BCM2835 has GPIO 0-53
+const GPIO_PINS: u32 = 54;
+
+// Size of the screen the log is shown on
+const LOG_SCREEN_WIDTH: u32 = 640;
+const LOG_SCREEN_HEIGHT: u32 = 480;
+
+// Period `tick` runs the ARM timer at, unless given
+const TICK_DEFAULT_PERIOD_MS: u32 = 10;
+
+// The byte that ends `term`: Ctrl-], as in telnet
+const TERM_ESCAPE: u8 = 0x1D;
+const TERM_DEFAULT_BAUD: u32 = 9600;
+
+// The software UART `term` opened. There is one compare for it, so it stays on its pins.
+static mut TERM_UART: Option<&'static SoftUart> = None;
+
+// How long an app may go without petting the watchdog before the board is reset
+const APP_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(2);
+
+type CommandResult = Result<(), &'static str>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str]) -> CommandResult,
+}
+
+const COMMANDS: [Command; 16] = [
+    Command {
+        name: "help",
+        usage: "",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "peek",
+        usage: "<address> [words]",
+        help: "print 32 bit words from memory",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<address> <value>",
+        help: "write a 32 bit word to memory",
+        run: poke,
+    },
+    Command {
+        name: "gpio",
+        usage: "[pin]",
+        help: "print the function and level of a pin, or of all of them",
+        run: gpio_info,
+    },
+    Command {
+        name: "heap",
+        usage: "",
+        help: "print heap usage",
+        run: heap,
+    },
+    Command {
+        name: "fb",
+        usage: "",
+        help: "print the framebuffer settings",
+        run: fb_info,
+    },
+    Command {
+        name: "time",
+        usage: "",
+        help: "print the time since boot",
+        run: time,
+    },
+    Command {
+        name: "tick",
+        usage: "[period ms]",
+        help: "run the ARM timer for a second and count its ticks",
+        run: tick,
+    },
+    Command {
+        name: "log",
+        usage: "[screen on|off]",
+        help: "print the log kept in memory, or also show it on the screen",
+        run: log_command,
+    },
+    Command {
+        name: "run",
+        usage: "[app]",
+        help: "start an app, or list them",
+        run: run_app,
+    },
+    Command {
+        name: "gdb",
+        usage: "",
+        help: "stop here and wait for GDB on the console",
+        run: gdb_command,
+    },
+    Command {
+        name: "rpc",
+        usage: "",
+        help: "answer RPC requests from a host tool until it closes",
+        run: rpc_command,
+    },
+    Command {
+        name: "term",
+        usage: "<tx pin> <rx pin> [baud]",
+        help: "talk to a serial device through the software UART, Ctrl-] to leave",
+        run: term,
+    },
+    Command {
+        name: "boot",
+        usage: "",
+        help: "receive a kernel with XMODEM and run it",
+        run: boot,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "restart the board",
+        run: reboot_command,
+    },
+    Command {
+        name: "halt",
+        usage: "",
+        help: "stop the board until the power is cycled",
+        run: halt_command,
+    },
+];
+
+struct App {
+    name: &'static str,
+    help: &'static str,
+    run: unsafe fn(),
+}
+
+const APPS: [App; 3] = [
+    App {
+        name: "invaders",
+        help: "space invaders, played on the PS/2 keyboard",
+        run: run_space_invaders,
+    },
+    App {
+        name: "gl",
+        help: "draw some triangles",
+        run: run_gl_test,
+    },
+    App {
+        name: "paint",
+        help: "draw with the PS/2 mouse, right click to quit",
+        run: run_paint,
+    },
+];
+
+/// Read commands from the console and run them, forever.
+pub fn run() -> ! {
+    let mut editor = LineEditor::new();
+    println!("rustberry monitor, type help for the commands");
+    if watchdog::reset_reason() == watchdog::ResetReason::Watchdog {
+        println!("(the watchdog reset the board last)");
+    }
+    print!("> ");
+    loop {
+        let console = serial::console();
+        let byte = console.read();
+        if let Some(line) = editor.feed(byte, &mut |echo| {
+            echo.iter().for_each(|&b| console.put_u8(b))
+        }) {
+            execute(line);
+            print!("> ");
+        }
+    }
+}
+
+/// Run one command line, printing what went wrong if anything did.
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return,
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            if let Err(message) = (command.run)(args) {
+                println!("{}: {}", name, message);
+                println!("usage: {} {}", command.name, command.usage);
+            }
+        }
+        None => println!("{}: no such command, try help", name),
+    }
+}
+
+// Parses a decimal number, or a hex one with a 0x prefix
+fn parse_number(word: &str) -> Option<u32> {
+    match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("42"), Some(42));
+    assert_eq!(parse_number("0x2020001C"), Some(0x2020001C));
+    assert_eq!(parse_number("0XfF"), Some(0xFF));
+    assert_eq!(parse_number("0x"), None);
+    assert_eq!(parse_number("12ab"), None);
+    assert_eq!(parse_number("4294967296"), None);
+}
+
+// Parses the argument at `index`, or returns `default` if there are fewer arguments
+fn number_arg(args: &[&str], index: usize, default: Option<u32>) -> Result<u32, &'static str> {
+    match args.get(index) {
+        Some(word) => parse_number(word).ok_or("not a number"),
+        None => default.ok_or("missing argument"),
+    }
+}
+
+fn word_address(args: &[&str]) -> Result<*mut u32, &'static str> {
+    let address = number_arg(args, 0, None)?;
+    if address % 4 != 0 {
+        return Err("address is not word aligned");
+    }
+    Ok(address as *mut u32)
+}
+
+#[test_case]
+fn test_execute() {
+    // nothing to check but that none of these hang or panic
+    execute("");
+    execute("nonsense");
+    execute("help");
+    execute("peek");
+    execute("peek 0x3");
+    execute("time");
+    execute("heap");
+    execute("run");
+    execute("term 20");
+    execute("log screen off");
+    execute("log screen");
+}
+
+fn help(_args: &[&str]) -> CommandResult {
+    for command in COMMANDS.iter() {
+        println!("  {:6} {:18} {}", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> CommandResult {
+    let address = word_address(args)?;
+    let words = number_arg(args, 1, Some(1))?;
+    for i in 0..words as usize {
+        let address = address.wrapping_add(i);
+        if i % 4 == 0 {
+            print!("{:#010x}:", address as u32);
+        }
+        let word = unsafe {
+            cpu::dev_barrier();
+            address.read_volatile()
+        };
+        print!(" {:08x}", word);
+        if i % 4 == 3 || i + 1 == words as usize {
+            println!();
+        }
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> CommandResult {
+    let address = word_address(args)?;
+    let value = number_arg(args, 1, None)?;
+    unsafe {
+        cpu::dev_barrier();
+        address.write_volatile(value);
+        cpu::dev_barrier();
+    }
+    Ok(())
+}
+
+fn function_name(function: u32) -> &'static str {
+    match function {
+        0 => "input",
+        1 => "output",
+        2 => "alt5",
+        3 => "alt4",
+        4 => "alt0",
+        5 => "alt1",
+        6 => "alt2",
+        _ => "alt3",
+    }
+}
+
+fn gpio_info(args: &[&str]) -> CommandResult {
+    let pins = match args.get(0) {
+        Some(_) => {
+            let pin = number_arg(args, 0, None)?;
+            if pin >= GPIO_PINS {
+                return Err("no such pin");
+            }
+            pin..pin + 1
+        }
+        None => 0..GPIO_PINS,
+    };
+    for pin in pins {
+        let (function, level) =
+            unsafe { (gpio::get_function(pin as isize), gpio::read(pin as isize)) };
+        println!("  gpio {:2}  {:6}  {}", pin, function_name(function), level);
+    }
+    Ok(())
+}
+
+fn heap(_args: &[&str]) -> CommandResult {
+    let stats = allocator::stats();
+    println!("  heap {:#010x}-{:#010x}", stats.start, stats.end);
+    println!(
+        "  used {} bytes in {} blocks",
+        stats.used_bytes, stats.used_blocks
+    );
+    println!(
+        "  free {} bytes in {} blocks, largest {}",
+        stats.free_bytes, stats.free_blocks, stats.largest_free
+    );
+    Ok(())
+}
+
+fn fb_info(_args: &[&str]) -> CommandResult {
+    unsafe {
+        if fb::fb_get_pitch() == 0 {
+            println!("  no framebuffer");
+            return Ok(());
+        }
+        println!(
+            "  {}x{}, {} bytes per pixel, pitch {}",
+            fb::fb_get_width(),
+            fb::fb_get_height(),
+            fb::fb_get_depth(),
+            fb::fb_get_pitch()
+        );
+        println!("  drawing at {:#010x}", fb::fb_get_draw_buffer());
+    }
+    Ok(())
+}
+
+fn time(_args: &[&str]) -> CommandResult {
+    let ticks = timer::Instant::now().as_micros();
+    println!(
+        "  {}.{:06} s ({} ticks)",
+        ticks / 1_000_000,
+        ticks % 1_000_000,
+        ticks
+    );
+    Ok(())
+}
+
+fn tick(args: &[&str]) -> CommandResult {
+    static TICKS: AtomicU32 = AtomicU32::new(0);
+
+    let period_ms = number_arg(args, 0, Some(TICK_DEFAULT_PERIOD_MS))?;
+    let period = Duration::from_millis(period_ms as u64);
+    let counted = unsafe {
+        arm_timer::init().map_err(|_| "the firmware did not report the core clock")?;
+        TICKS.store(0, Ordering::Relaxed);
+        arm_timer::every(period, || {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        })
+        .map_err(|_| "period out of range")?;
+        arm_timer::start_free_running(0);
+        let start = arm_timer::free_running_count();
+        timer::sleep(Duration::from_secs(1));
+        let counted = arm_timer::free_running_count().wrapping_sub(start);
+        arm_timer::stop();
+        arm_timer::stop_free_running();
+        counted
+    };
+    println!(
+        "  {} ticks of {} ms in 1 s, counting at {} Hz",
+        TICKS.load(Ordering::Relaxed),
+        period_ms,
+        arm_timer::tick_rate()
+    );
+    println!(
+        "  free-running counter: {} in 1 s, at {} Hz",
+        counted,
+        unsafe { arm_timer::free_running_rate() }
+    );
+    Ok(())
+}
+
+fn log_command(args: &[&str]) -> CommandResult {
+    match args {
+        [] => log::MEMORY.dump(serial::console()),
+        ["screen", "on"] => show_log_on_screen()?,
+        ["screen", "off"] => {
+            log::remove_sink(&gl::SCREEN);
+        }
+        _ => return Err("expected screen on or screen off"),
+    }
+    Ok(())
+}
+
+// Sets up a single buffered screen and logs to it from now on
+fn show_log_on_screen() -> CommandResult {
+    let ready = unsafe { fb::fb_init(LOG_SCREEN_WIDTH, LOG_SCREEN_HEIGHT, 4, fb::FB_SINGLEBUFFER) };
+    if !ready {
+        return Err("can't set up the framebuffer");
+    }
+    gl::SCREEN.clear();
+    log::remove_sink(&gl::SCREEN); // it may be on already
+    log::add_sink(&gl::SCREEN, log::LevelFilter::Info);
+    Ok(())
+}
+
+fn run_app(args: &[&str]) -> CommandResult {
+    let name = match args.get(0) {
+        Some(name) => name,
+        None => {
+            for app in APPS.iter() {
+                println!("  {:10} {}", app.name, app.help);
+            }
+            return Ok(());
+        }
+    };
+    let app = APPS
+        .iter()
+        .find(|app| app.name == *name)
+        .ok_or("no such app")?;
+    // the apps set up framebuffers of their own, which the log would be drawn over
+    let screen_log = log::remove_sink(&gl::SCREEN);
+    // the apps pet it from their loops, so a hung one resets the board
+    unsafe {
+        watchdog::start(APP_WATCHDOG_TIMEOUT).map_err(|_| "can't start the watchdog")?;
+        (app.run)();
+        watchdog::stop();
+    }
+    if screen_log {
+        show_log_on_screen()?;
+    }
+    Ok(())
+}
+
+unsafe fn run_space_invaders() {
+    let _ = space_invaders::run_game();
+}
+
+unsafe fn run_gl_test() {
+    let _ = gl::_gl_test();
+}
+
+unsafe fn run_paint() {
+    let _ = gl::paint();
+}
+
+fn gdb_command(_args: &[&str]) -> CommandResult {
+    println!("waiting for GDB; continue in GDB to get back here");
+    serial::console().flush();
+    gdb::init(serial::console());
+    cpu::breakpoint();
+    Ok(())
+}
+
+fn rpc_command(_args: &[&str]) -> CommandResult {
+    println!("serving RPC requests on the console");
+    rpc::serve(serial::console());
+    println!("RPC session closed");
+    Ok(())
+}
+
+fn term(args: &[&str]) -> CommandResult {
+    let tx = number_arg(args, 0, None)?;
+    let rx = number_arg(args, 1, None)?;
+    let baud = number_arg(args, 2, Some(TERM_DEFAULT_BAUD))?;
+    let uart = unsafe {
+        let uart = match TERM_UART {
+            Some(uart) if uart.pins() != (tx, rx) => {
+                let (tx, rx) = uart.pins();
+                println!("  the software UART stays on pins {} and {}", tx, rx);
+                return Err("pins in use");
+            }
+            Some(uart) => uart,
+            None => &*Box::leak(Box::new(SoftUart::new(tx, rx))),
+        };
+        match uart.init(baud) {
+            Ok(()) => {}
+            Err(SoftUartError::BaudRate(_)) => {
+                println!("  {} to {} baud", soft_uart::MIN_BAUD, soft_uart::MAX_BAUD);
+                return Err("unsupported baud rate");
+            }
+            Err(SoftUartError::Pins) => return Err("no such pins"),
+            Err(SoftUartError::Busy) => return Err("the software UART is busy"),
+        }
+        TERM_UART = Some(uart);
+        uart
+    };
+
+    println!("connected at {} baud, Ctrl-] to leave", baud);
+    let console = serial::console();
+    loop {
+        if let Some(byte) = console.try_read() {
+            if byte == TERM_ESCAPE {
+                break;
+            }
+            uart.put_u8(byte);
+        }
+        if let Some(byte) = uart.try_read() {
+            console.put_u8(byte);
+        }
+    }
+    println!();
+    println!("  {} framing errors", uart.framing_errors());
+    Ok(())
+}
+
+fn boot(_args: &[&str]) -> CommandResult {
+    println!("send the kernel with XMODEM within a minute");
+    match bootloader::receive(serial::console(), Some(BOOT_WAIT_S)) {
+        Ok(image) => unsafe { bootloader::chain_load(&image) },
+        Err(err) => println!("boot: {}", err),
+    }
+    Ok(())
+}
+
+fn reboot_command(_args: &[&str]) -> CommandResult {
+    println!("rebooting");
+    serial::console().flush();
+    watchdog::reboot()
+}
+
+fn halt_command(_args: &[&str]) -> CommandResult {
+    println!("halting");
+    serial::console().flush();
+    watchdog::halt()
+}
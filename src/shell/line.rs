@@ -0,0 +1,204 @@
+/*
+ * Line editing for the shell: turns the bytes a terminal sends into lines,
+ * echoing what is typed.
+ *
+ * Printable ASCII is appended to the line, backspace (BS or DEL) erases the
+ * last character, Ctrl-U the whole line and Ctrl-C abandons it. The up and
+ * down arrows (ESC [ A and ESC [ B) step through the lines entered before.
+ * Enter is CR, LF or CR LF.
+ */
+
+/// Longest line kept; further characters are ignored.
+pub const LINE_MAX: usize = 120;
+
+/// Number of lines kept for the up arrow.
+pub const HISTORY_LEN: usize = 16;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+// Erases the character left of the terminal's cursor
+const ERASE: &[u8] = b"\x08 \x08";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Escape {
+    None,
+    // ESC received
+    Started,
+    // ESC [ received, waiting for the final byte of a control sequence
+    ControlSequence,
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    bytes: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        bytes: [0; LINE_MAX],
+        len: 0,
+    };
+
+    fn as_str(&self) -> &str {
+        // only printable ASCII is ever added
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+pub struct LineEditor {
+    line: Line,
+    // Set once the line is entered; the next byte starts a new one
+    entered: bool,
+    // Whether the last byte was CR, so that a LF right after it is not a second Enter
+    after_cr: bool,
+    escape: Escape,
+    history: [Line; HISTORY_LEN],
+    // Number of lines in history, and the slot the next one goes in
+    history_count: usize,
+    history_next: usize,
+    // How far back the line shown is while stepping through history, 0 for a new line
+    history_offset: usize,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        LineEditor {
+            line: Line::EMPTY,
+            entered: false,
+            after_cr: false,
+            escape: Escape::None,
+            history: [Line::EMPTY; HISTORY_LEN],
+            history_count: 0,
+            history_next: 0,
+            history_offset: 0,
+        }
+    }
+
+    /// Handle `byte` from the terminal, passing what to send back to `echo`.
+    ///
+    /// Returns the line when `byte` enters it. It stays available until the next call.
+    pub fn feed(&mut self, byte: u8, echo: &mut dyn FnMut(&[u8])) -> Option<&str> {
+        if self.entered {
+            self.entered = false;
+            self.line.len = 0;
+            self.history_offset = 0;
+        }
+        let after_cr = self.after_cr;
+        self.after_cr = byte == b'\r';
+
+        match self.escape {
+            Escape::Started => {
+                self.escape = if byte == b'[' {
+                    Escape::ControlSequence
+                } else {
+                    Escape::None
+                };
+                return None;
+            }
+            Escape::ControlSequence => {
+                // parameter and intermediate bytes come before the final byte
+                if (0x40..=0x7E).contains(&byte) {
+                    self.escape = Escape::None;
+                    match byte {
+                        b'A' => self.recall(self.history_offset + 1, echo),
+                        b'B' if self.history_offset > 0 => {
+                            self.recall(self.history_offset - 1, echo)
+                        }
+                        _ => {}
+                    }
+                }
+                return None;
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                echo(b"\r\n");
+                self.enter()
+            }
+            BACKSPACE | DELETE => {
+                if self.line.len > 0 {
+                    self.line.len -= 1;
+                    echo(ERASE);
+                }
+                None
+            }
+            CTRL_U => {
+                self.erase(echo);
+                None
+            }
+            CTRL_C => {
+                echo(b"^C\r\n");
+                self.line.len = 0;
+                self.entered = true;
+                Some("")
+            }
+            ESCAPE => {
+                self.escape = Escape::Started;
+                None
+            }
+            0x20..=0x7E if self.line.len < LINE_MAX => {
+                self.line.bytes[self.line.len] = byte;
+                self.line.len += 1;
+                echo(&[byte]);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Enters the line, keeping it in history unless it is empty or the same as the last one
+    fn enter(&mut self) -> Option<&str> {
+        self.entered = true;
+        let newest = self.history_entry(1).map(|line| line.as_str());
+        if self.line.len > 0 && newest != Some(self.line.as_str()) {
+            self.history[self.history_next] = self.line;
+            self.history_next = (self.history_next + 1) % HISTORY_LEN;
+            self.history_count = (self.history_count + 1).min(HISTORY_LEN);
+        }
+        Some(self.line.as_str())
+    }
+
+    // Returns the line entered `offset` lines ago, 1 being the last one
+    fn history_entry(&self, offset: usize) -> Option<&Line> {
+        if offset == 0 || offset > self.history_count {
+            return None;
+        }
+        Some(&self.history[(self.history_next + HISTORY_LEN - offset) % HISTORY_LEN])
+    }
+
+    // Replaces the line with the one entered `offset` lines ago, or an empty one for 0
+    fn recall(&mut self, offset: usize, echo: &mut dyn FnMut(&[u8])) {
+        let line = match offset {
+            0 => Line::EMPTY,
+            _ => match self.history_entry(offset) {
+                Some(line) => *line,
+                None => return,
+            },
+        };
+        self.erase(echo);
+        self.line = line;
+        self.history_offset = offset;
+        echo(&self.line.bytes[..self.line.len]);
+    }
+
+    fn erase(&mut self, echo: &mut dyn FnMut(&[u8])) {
+        for _ in 0..self.line.len {
+            echo(ERASE);
+        }
+        self.line.len = 0;
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,432 @@
+/*
+ * Software UART: 8N1 on any pair of GPIO pins, timed with the system timer,
+ * for serial devices such as GPS modules beyond what the two hardware UARTs
+ * can serve.
+ *
+ * Every bit is sampled or sent from the interrupt of the system timer's C3
+ * compare, which is set to the next bit edge or middle in turn. A character
+ * thus takes twenty short interrupts rather than holding up the CPU for its
+ * whole length, and other interrupts, e.g. those of the console, are served
+ * in between. Receiving starts from the falling edge of the start bit: the
+ * GPIO interrupt only sets the compare to the middle of the start bit.
+ * Sending and receiving go on at the same time.
+ *
+ * Bits are sampled in their middle, so an interrupt may be up to about half
+ * a bit time late, 13us at 38400 baud. There is a single C3 compare, so only
+ * one SoftUart can run.
+ *
+ * With IRQs masked, e.g. in a debugger, nobody takes the interrupts, so
+ * reading and writing wait for each bit themselves.
+ */
+
+use crate::ring_buffer::RingBuffer;
+use crate::serial::{BreakByte, Overruns, Serial};
+use crate::timer::{self, Channel};
+use crate::{exception, gpio, interrupts};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Fastest rate supported. Above it the timer's 1us resolution and the interrupt latency take
+/// up too much of a bit.
+pub const MAX_BAUD: u32 = 38400;
+/// Slowest rate supported.
+pub const MIN_BAUD: u32 = 1200;
+
+// From the falling edge of the start bit to the handler reading the timer
+const IRQ_LATENCY_US: u32 = 2;
+
+// How far ahead of the counter the compare is set at least, so that it is not passed before
+// the write lands; closer bits are waited for in the handler
+const MIN_LEAD_US: u32 = 4;
+
+// The BCM2835 has GPIO 0-53
+const GPIO_PINS: u32 = 54;
+
+// The SoftUart the compare interrupt is for
+static mut ACTIVE: Option<&'static SoftUart> = None;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SoftUartError {
+    /// The baud rate is outside MIN_BAUD..=MAX_BAUD.
+    BaudRate(u32),
+    /// A pin is not a GPIO pin, or both pins are the same.
+    Pins,
+    /// Another SoftUart has the C3 compare.
+    Busy,
+}
+
+// A character being received
+#[derive(Copy, Clone)]
+struct Receiving {
+    // When its start bit fell
+    start: u32,
+    // 0 for the start bit, 1-8 for the data bits, 9 for the stop bit
+    bit: u32,
+    byte: u8,
+}
+
+// A character being sent
+#[derive(Copy, Clone)]
+struct Sending {
+    // When its start bit began
+    start: u32,
+    // The start bit, the data lsb first and the stop bit
+    frame: u32,
+    // The next bit to send, 10 for the end of the stop bit
+    bit: u32,
+}
+
+impl Sending {
+    fn new(start: u32, byte: u8) -> Self {
+        Sending {
+            start,
+            frame: (byte as u32) << 1 | 1 << 9,
+            bit: 0,
+        }
+    }
+}
+
+// Owned by the compare and edge interrupt handlers, and by the caller while IRQs are masked
+struct State {
+    receiving: Option<Receiving>,
+    sending: Option<Sending>,
+}
+
+pub struct SoftUart {
+    tx: u32,
+    rx: u32,
+    baud: AtomicU32,
+    initialized: AtomicBool,
+    // Bytes received by the interrupt handlers, waiting to be read
+    received: RingBuffer<256>,
+    // Bytes written, waiting for the interrupt handler to send them
+    to_send: RingBuffer<256>,
+    state: UnsafeCell<State>,
+    queue_overruns: AtomicU32,
+    framing_errors: AtomicU32,
+    break_byte: BreakByte,
+}
+
+// `state` is only touched with IRQs masked or from IRQ mode
+unsafe impl Sync for SoftUart {}
+
+impl SoftUart {
+    /// A UART sending on GPIO `tx` and receiving on GPIO `rx`, see `init()`.
+    pub const fn new(tx: u32, rx: u32) -> Self {
+        SoftUart {
+            tx,
+            rx,
+            baud: AtomicU32::new(MAX_BAUD),
+            initialized: AtomicBool::new(false),
+            received: RingBuffer::new(),
+            to_send: RingBuffer::new(),
+            state: UnsafeCell::new(State {
+                receiving: None,
+                sending: None,
+            }),
+            queue_overruns: AtomicU32::new(0),
+            framing_errors: AtomicU32::new(0),
+            break_byte: BreakByte::new(),
+        }
+    }
+
+    /// Configure the pins for `baud` 8N1 and start receiving from the GPIO interrupt, with the
+    /// C3 compare of the system timer for the bit times. Calling it again changes the rate.
+    ///
+    /// Must be called after `interrupts::init()`. Nothing is sent or received before.
+    pub unsafe fn init(&'static self, baud: u32) -> Result<(), SoftUartError> {
+        if !(MIN_BAUD..=MAX_BAUD).contains(&baud) {
+            return Err(SoftUartError::BaudRate(baud));
+        }
+        if self.tx == self.rx || self.tx >= GPIO_PINS || self.rx >= GPIO_PINS {
+            return Err(SoftUartError::Pins);
+        }
+        match ACTIVE {
+            Some(active) if !core::ptr::eq(active, self) => return Err(SoftUartError::Busy),
+            _ => {}
+        }
+        self.baud.store(baud, Ordering::Relaxed);
+        if self.initialized.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // the line idles high
+        let (tx, rx) = (self.tx as isize, self.rx as isize);
+        gpio::write(tx, 1);
+        gpio::set_output(tx);
+        gpio::set_input(rx);
+        gpio::set_pullup(rx);
+
+        ACTIVE = Some(self);
+        let compare = Channel::C3.interrupt_source();
+        interrupts::register_handler(compare, move || self.compare_handler());
+        interrupts::enable_source(compare);
+
+        let edge = gpio::interrupt_source(rx);
+        interrupts::register_handler(edge, move || self.edge_handler());
+        interrupts::enable_source(edge);
+        self.listen();
+        self.initialized.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// The pins it sends and receives on.
+    pub fn pins(&self) -> (u32, u32) {
+        (self.tx, self.rx)
+    }
+
+    /// Characters received without a stop bit since boot, usually because the baud rates of
+    /// both ends differ. They are dropped rather than passed on.
+    pub fn framing_errors(&self) -> u32 {
+        self.framing_errors.load(Ordering::Relaxed)
+    }
+
+    // Have the next start bit raise the GPIO interrupt
+    unsafe fn listen(&self) {
+        let rx = self.rx as isize;
+        gpio::clear_event(rx);
+        gpio::enable_falling_edge_event(rx);
+    }
+
+    unsafe fn edge_handler(&self) {
+        let rx = self.rx as isize;
+        if !gpio::check_event(rx) {
+            return; // another pin of the bank
+        }
+        let start = timer::get_ticks().wrapping_sub(IRQ_LATENCY_US);
+        self.start_receiving(start);
+    }
+
+    // Takes the character whose start bit fell at `start`
+    unsafe fn start_receiving(&self, start: u32) {
+        let rx = self.rx as isize;
+        // the edges within the character are of no interest
+        gpio::disable_falling_edge_event(rx);
+        gpio::clear_event(rx);
+        let state = &mut *self.state.get();
+        state.receiving = Some(Receiving {
+            start,
+            bit: 0,
+            byte: 0,
+        });
+        self.service();
+    }
+
+    unsafe fn compare_handler(&self) {
+        if timer::has_matched(Channel::C3) {
+            self.service();
+        }
+    }
+
+    // Samples and sends the bits that are due, and sets the compare to the next one
+    unsafe fn service(&self) {
+        timer::clear_match(Channel::C3);
+        let state = &mut *self.state.get();
+        let baud = self.baud.load(Ordering::Relaxed);
+        loop {
+            let sample_at = state
+                .receiving
+                .map(|r| r.start.wrapping_add(offset_us(baud, 1 + 2 * r.bit)));
+            let edge_at = state
+                .sending
+                .map(|s| s.start.wrapping_add(offset_us(baud, 2 * s.bit)));
+            let now = timer::get_ticks();
+            let (at, is_sample) = match (sample_at, edge_at) {
+                (None, None) => return,
+                (Some(sample), Some(edge)) if until(now, edge) < until(now, sample) => {
+                    (edge, false)
+                }
+                (Some(sample), _) => (sample, true),
+                (None, Some(edge)) => (edge, false),
+            };
+
+            if until(now, at) > MIN_LEAD_US as i32 {
+                timer::set_compare(Channel::C3, at);
+                // in case the counter got there first after all
+                if until(timer::get_ticks(), at) > 0 {
+                    return;
+                }
+            }
+            while until(timer::get_ticks(), at) > 0 {}
+            if is_sample {
+                self.sample(state);
+            } else {
+                self.send_bit(state, at);
+            }
+        }
+    }
+
+    unsafe fn sample(&self, state: &mut State) {
+        let receiving = match state.receiving.as_mut() {
+            Some(receiving) => receiving,
+            None => return,
+        };
+        let level = gpio::read(self.rx as isize);
+        match receiving.bit {
+            // a glitch rather than a start bit
+            0 if level != 0 => {}
+            0..=8 => {
+                if receiving.bit > 0 {
+                    receiving.byte |= (level as u8) << (receiving.bit - 1);
+                }
+                receiving.bit += 1;
+                return;
+            }
+            _ if level == 0 => {
+                self.framing_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                let byte = receiving.byte;
+                if self.break_byte.is_data(byte) && !self.received.push(byte) {
+                    self.queue_overruns.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        // done in the middle of the stop bit, in time for the next start bit
+        state.receiving = None;
+        self.listen();
+    }
+
+    // Sends the next bit of the character, due `at`, or starts on the next character
+    unsafe fn send_bit(&self, state: &mut State, at: u32) {
+        let sending = match state.sending.as_mut() {
+            Some(sending) => sending,
+            None => return,
+        };
+        if sending.bit < 10 {
+            gpio::write(self.tx as isize, (sending.frame >> sending.bit) & 1);
+            sending.bit += 1;
+        } else {
+            // back to back, so that the bit times don't drift
+            state.sending = self.to_send.pop().map(|byte| Sending::new(at, byte));
+        }
+    }
+
+    // Starts sending from the queue if the handler is not at it already. IRQs must be masked.
+    unsafe fn start_sending(&self) {
+        let state = &mut *self.state.get();
+        if state.sending.is_none() {
+            if let Some(byte) = self.to_send.pop() {
+                state.sending = Some(Sending::new(timer::get_ticks(), byte));
+                self.service();
+            }
+        }
+    }
+
+    fn is_sending(&self) -> bool {
+        let saved = exception::local_irq_mask_save();
+        let sending = unsafe { (*self.state.get()).sending.is_some() } || !self.to_send.is_empty();
+        exception::local_irq_restore(saved);
+        sending
+    }
+}
+
+impl Serial for SoftUart {
+    fn try_read(&self) -> Option<u8> {
+        if let Some(byte) = self.received.pop() {
+            return Some(byte);
+        }
+        if !exception::local_irq_is_masked() || !self.initialized.load(Ordering::Acquire) {
+            return None;
+        }
+        // with IRQs masked the handlers are run from here, and a start bit is looked for on the
+        // pin; it is caught in time as long as this is called often enough
+        unsafe {
+            let receiving = (*self.state.get()).receiving.is_some();
+            if !receiving && gpio::read(self.rx as isize) == 0 {
+                self.start_receiving(timer::get_ticks());
+            }
+            while (*self.state.get()).receiving.is_some() {
+                self.service();
+            }
+        }
+        self.received.pop()
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        if !self.initialized.load(Ordering::Acquire) {
+            return bytes.len();
+        }
+        // a single producer at a time, see put_u8()
+        let saved = exception::local_irq_mask_save();
+        let written = bytes
+            .iter()
+            .take_while(|&&byte| self.to_send.push(byte))
+            .count();
+        unsafe { self.start_sending() };
+        exception::local_irq_restore(saved);
+        if exception::local_irq_is_masked() {
+            self.flush();
+        }
+        written
+    }
+
+    /// With IRQs masked, nobody else sends the byte, so it is sent before returning.
+    fn put_u8(&self, byte: u8) {
+        if !self.initialized.load(Ordering::Acquire) {
+            return;
+        }
+        // Handlers may write too, and the queue takes a single producer, so every push is made
+        // with IRQs masked. They are unmasked in between, for the handler to make room.
+        let masked = exception::local_irq_is_masked();
+        loop {
+            let saved = exception::local_irq_mask_save();
+            let pushed = self.to_send.push(byte);
+            unsafe {
+                if pushed {
+                    self.start_sending();
+                } else if masked {
+                    self.service();
+                }
+            }
+            exception::local_irq_restore(saved);
+            if pushed {
+                break;
+            }
+        }
+        if masked {
+            self.flush();
+        }
+    }
+
+    fn flush(&self) {
+        while self.is_sending() {
+            if exception::local_irq_is_masked() {
+                unsafe { self.service() };
+            }
+        }
+    }
+
+    /// Characters lost because an interrupt came too late are not noticed, so `fifo` stays 0.
+    fn overruns(&self) -> Overruns {
+        Overruns {
+            fifo: 0,
+            queue: self.queue_overruns.load(Ordering::Relaxed),
+        }
+    }
+
+    fn set_break_byte(&self, byte: Option<u8>) {
+        self.break_byte.set(byte);
+    }
+}
+
+// Microseconds from the start of a character to `half_bits` half bit times into it
+fn offset_us(baud: u32, half_bits: u32) -> u32 {
+    half_bits * 500_000 / baud
+}
+
+// Microseconds from `now` until `at`, negative once it has passed
+fn until(now: u32, at: u32) -> i32 {
+    at.wrapping_sub(now) as i32
+}
+
+#[test_case]
+fn test_bit_times() {
+    // the middle of the first data bit and the end of the stop bit
+    assert_eq!(offset_us(38400, 3), 39);
+    assert_eq!(offset_us(38400, 20), 260);
+    assert_eq!(offset_us(9600, 20), 1041);
+    // across the counter wrapping around
+    assert_eq!(until(u32::MAX - 4, 5), 10);
+    assert_eq!(until(5, u32::MAX - 4), -10);
+}
@@ -9,7 +9,9 @@
  */
 
 use crate::gl::Display;
-use crate::{cpu, fb, gl, keyboard};
+use crate::keyboard::Key;
+use crate::timer::{self, Duration, Instant};
+use crate::{fb, gl, keyboard, watchdog};
 use core::convert::TryInto;
 
 use core::cell::UnsafeCell;
@@ -25,6 +27,9 @@ use embedded_graphics::{
     text_style, DrawTarget,
 };
 
+// How often the game moves on, whatever the drawing takes
+const FRAME_TIME: Duration = Duration::from_millis(50);
+
 struct Spaceship {
     pos_x: i32,
     pos_y: i32,
@@ -315,6 +320,7 @@ impl Beam {
 
 pub unsafe fn run_game() -> Result<(), core::convert::Infallible> {
     fb::fb_init(640, 512, 4, fb::FB_DOUBLEBUFFER);
+    keyboard::init();
     let w = 640;
     let h = 512;
 
@@ -435,6 +441,7 @@ pub unsafe fn run_game() -> Result<(), core::convert::Infallible> {
 
     let beam_arr: [Beam; 5] = [beam1, beam2, beam3, beam4, beam5];
 
+    let mut next_frame = Instant::now();
     loop {
         row1.clear();
         row1.move_by(20);
@@ -452,29 +459,41 @@ pub unsafe fn run_game() -> Result<(), core::convert::Infallible> {
             .into_styled(black)
             .draw(&mut display)?;
 
-        if keyboard::read_next() == 'h' && ship.pos_x - 5 > 60 {
-            //keyboard::PS2_KEY_ARROW_LEFT {
+        // movement follows held keys, firing and pausing happen once per key press
+        if (keyboard::is_pressed(Key::Left) || keyboard::is_pressed(Key::Char('h')))
+            && ship.pos_x - 5 > 60
+        {
             ship.move_by(-20, 0);
-        } else if keyboard::read_next() == 'l' && ship.pos_x + 5 < w - 60 {
-            // keyboard::PS2_KEY_ARROW_RIGHT {
+        } else if (keyboard::is_pressed(Key::Right) || keyboard::is_pressed(Key::Char('l')))
+            && ship.pos_x + 5 < w - 60
+        {
             ship.move_by(20, 0);
-        } else if keyboard::read_next() == 'k' {
-            // try to find a beam that is not active
-            for beam in &beam_arr {
-                if *(beam.available.get()) {
-                    beam.clear();
-                    *(beam.curr_x.get()) = ship.pos_x;
-                    *(beam.curr_y.get()) = ship.pos_y - ship.size;
-                    *(beam.player.get()) = 1;
-                    *(beam.prev_dx.get()) = 0;
-                    *(beam.prev_dy.get()) = 0;
-                    *(beam.active.get()) = true;
-                    *(beam.available.get()) = false;
-                    break;
+        }
+
+        while let Some(event) = keyboard::try_read_event() {
+            if !event.pressed {
+                continue;
+            }
+            if event.key == Key::Up || event.key == Key::Char('k') {
+                // try to find a beam that is not active
+                for beam in &beam_arr {
+                    if *(beam.available.get()) {
+                        beam.clear();
+                        *(beam.curr_x.get()) = ship.pos_x;
+                        *(beam.curr_y.get()) = ship.pos_y - ship.size;
+                        *(beam.player.get()) = 1;
+                        *(beam.prev_dx.get()) = 0;
+                        *(beam.prev_dy.get()) = 0;
+                        *(beam.active.get()) = true;
+                        *(beam.available.get()) = false;
+                        break;
+                    }
+                }
+            } else if event.key == Key::Char('p') {
+                while keyboard::read_next() != 'r' {
+                    watchdog::pet();
                 }
             }
-        } else if keyboard::read_next() == 'p' {
-            while keyboard::read_next() != 'r' {}
         }
 
         ship.draw();
@@ -527,7 +546,13 @@ pub unsafe fn run_game() -> Result<(), core::convert::Infallible> {
         }
 
         fb::fb_swap_buffer();
-        cpu::sleep(500000);
+        // keep a steady pace; after a slow frame, start counting again from now
+        next_frame += FRAME_TIME;
+        if next_frame < Instant::now() {
+            next_frame = Instant::now();
+        }
+        timer::sleep_until(next_frame);
+        watchdog::pet();
         /*if (ship.pos_x + 30 > w - 30 && dx > 0) || (ship.pos_x - 30 < 30 && dx < 0) {
             dx *= -1;
         }*/
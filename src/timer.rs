@@ -1,20 +1,482 @@
 // Author: Xiluo He <xiluohe@stanford.edu>
+//
+// The BCM2835 system timer: a free-running 64 bit counter of microseconds,
+// and four compare registers that raise an interrupt when the low word of
+// the counter reaches them. The GPU uses C0 and C2. C1 drives the software
+// timers started with after() and every(); C3 clocks the bits of the
+// software UART.
+//
+// sleep() and the delay_*() functions never return early. They return
+// within a microsecond of the deadline, plus the time interrupt handlers
+// take meanwhile. Should the counter stand still, as on emulators that
+// don't model it, they go by the cycle counter of the ARM1176 instead,
+// assuming the fastest clock it is run at, so that they are still not
+// short: at the default 700MHz they then take 1.4 times as long.
+//
+// See the BCM2835 ARM Peripherals data sheet, chapter 12.
 
+use crate::{cpu, exception, interrupts};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+pub use core::time::Duration;
+
+const CS: *mut u32 = 0x20003000 as *mut u32;
 const TIME: *mut u32 = 0x20003004 as *mut u32;
+const TIME_HI: *mut u32 = 0x20003008 as *mut u32;
+// C0-C3
+const COMPARE: *mut u32 = 0x2000300C as *mut u32;
+
+// How far ahead of the counter a compare is set at least, so that it is not passed before the
+// write lands
+const MIN_LEAD_US: u32 = 10;
+// Compares are set at most this far ahead, so that the counter can't be mistaken for having
+// passed them; later deadlines are reached in several steps
+const MAX_LEAD_US: u64 = 1 << 30;
 
+// The fastest the ARM1176 of a Pi 1 is clocked, with arm_freq in config.txt
+const MAX_ARM_MHZ: u64 = 1000;
+
+/// The low 32 bits of the counter, in microseconds, which wrap around every 71 minutes. Compare
+/// them with `wrapping_sub()`, or use `Instant` instead.
 pub unsafe fn get_ticks() -> u32 {
     return TIME.read_volatile();
 }
 
 pub unsafe fn delay_us(usecs: u32) {
-    let start: u32 = get_ticks();
-    while get_ticks() - start < usecs {}
+    sleep(Duration::from_micros(usecs as u64));
 }
 
 pub unsafe fn delay_ms(msecs: u32) {
-    delay_us(1000 * msecs);
+    sleep(Duration::from_millis(msecs as u64));
 }
 
 pub unsafe fn delay(secs: u32) {
-    delay_us(1000000 * secs);
+    sleep(Duration::from_secs(secs as u64));
+}
+
+/// Wait for at least `duration`, at a microsecond resolution.
+pub fn sleep(duration: Duration) {
+    // the counter may be about to tick as it is read, so one more is waited for
+    let deadline = Instant::now()
+        .checked_add(duration)
+        .and_then(|deadline| deadline.checked_add(Duration::from_micros(1)));
+    sleep_until(deadline.unwrap_or(Instant::from_micros(u64::MAX)));
+}
+
+/// Wait until `deadline`. Waiting for a deadline rather than a duration, a loop keeps its pace
+/// however long each pass takes, as long as it takes less than the period.
+pub fn sleep_until(deadline: Instant) {
+    let remaining_us = deadline
+        .as_micros()
+        .saturating_sub(Instant::now().as_micros());
+    // enough cycles for the time left at any clock, in case the counter stands still
+    let cycles = remaining_us.saturating_mul(MAX_ARM_MHZ);
+    let mut counted: u64 = 0;
+    let mut last = cpu::cycle_count();
+    while counted < cycles && Instant::now() < deadline {
+        let count = cpu::cycle_count();
+        counted += count.wrapping_sub(last) as u64;
+        last = count;
+    }
+}
+
+// The whole counter. The low word is read between two reads of the high word, so that a carry
+// in between is noticed.
+unsafe fn get_ticks_64() -> u64 {
+    cpu::dev_barrier();
+    loop {
+        let hi = TIME_HI.read_volatile();
+        let lo = TIME.read_volatile();
+        if TIME_HI.read_volatile() == hi {
+            return (hi as u64) << 32 | lo as u64;
+        }
+    }
+}
+
+/// A point in time, counted in microseconds since boot by the 64 bit system timer, which does not
+/// wrap around for over half a million years.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    micros: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            micros: unsafe { get_ticks_64() },
+        }
+    }
+
+    pub const fn from_micros(micros: u64) -> Self {
+        Instant { micros }
+    }
+
+    /// Microseconds since boot.
+    pub const fn as_micros(self) -> u64 {
+        self.micros
+    }
+
+    /// Time since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.micros.saturating_sub(earlier.micros))
+    }
+
+    /// Time since `self`.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    /// `self + duration`, rounded up to whole microseconds, or None if that overflows.
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        Some(Instant {
+            micros: self.micros.checked_add(duration_us(duration)?)?,
+        })
+    }
+
+    /// `self - duration`, rounded up to whole microseconds, or None before boot.
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        Some(Instant {
+            micros: self.micros.checked_sub(duration_us(duration)?)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// Like `duration_since()`, zero if `earlier` is later.
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// Whole microseconds in `duration`, rounded up, or None if they don't fit in a u64
+fn duration_us(duration: Duration) -> Option<u64> {
+    let micros = (duration.subsec_nanos() as u64 + 999) / 1000;
+    duration
+        .as_secs()
+        .checked_mul(1_000_000)?
+        .checked_add(micros)
+}
+
+/// The compare registers the GPU leaves to the ARM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Drives `after()` and `every()`.
+    C1 = 1,
+    /// Clocks the bits of `soft_uart::SoftUart`.
+    C3 = 3,
+}
+
+impl Channel {
+    /// The interrupt controller source raised while the channel has matched.
+    pub fn interrupt_source(self) -> u32 {
+        match self {
+            Channel::C1 => interrupts::INTERRUPTS_TIMER1,
+            Channel::C3 => interrupts::INTERRUPTS_TIMER3,
+        }
+    }
+}
+
+/// Have `channel` match once `get_ticks()` reaches `ticks`, clearing an earlier match.
+///
+/// The counter has to pass `ticks` exactly: if it is already past, the match only comes after
+/// the counter wraps around, 71 minutes later.
+pub unsafe fn set_compare(channel: Channel, ticks: u32) {
+    clear_match(channel);
+    COMPARE.add(channel as usize).write_volatile(ticks);
+    cpu::dev_barrier();
+}
+
+/// Whether `channel` has matched since its match was last cleared.
+pub unsafe fn has_matched(channel: Channel) -> bool {
+    cpu::dev_barrier();
+    CS.read_volatile() & (1 << channel as u32) != 0
+}
+
+/// Clear the match of `channel`, which acknowledges its interrupt.
+pub unsafe fn clear_match(channel: Channel) {
+    cpu::dev_barrier();
+    CS.write_volatile(1 << channel as u32); // write 1 to clear
+    cpu::dev_barrier();
+}
+
+/// Identifies a timer started by `after()` or `every()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimerHandle(u32);
+
+impl TimerHandle {
+    /// Stop the timer. Returns false if it had already run, for a one-shot timer, or had been
+    /// cancelled.
+    ///
+    /// A periodic timer may cancel itself from its callback.
+    pub fn cancel(self) -> bool {
+        let saved = exception::local_irq_mask_save();
+        let cancelled = unsafe {
+            if RUNNING == Some(self.0) && !RUNNING_CANCELLED {
+                RUNNING_CANCELLED = true;
+                true
+            } else {
+                match TIMERS.iter().position(|timer| timer.id == self.0) {
+                    Some(index) => {
+                        TIMERS.remove(index);
+                        schedule();
+                        true
+                    }
+                    None => false,
+                }
+            }
+        };
+        exception::local_irq_restore(saved);
+        cancelled
+    }
+}
+
+struct SoftTimer {
+    id: u32,
+    deadline: Instant,
+    // 0 for a one-shot timer
+    period_us: u64,
+    callback: Box<dyn FnMut()>,
+}
+
+// Pending timers, the soonest first; those due at the same time in the order they were started
+static mut TIMERS: Vec<SoftTimer> = Vec::new();
+static mut NEXT_ID: u32 = 0;
+// The periodic timer whose callback is running, and whether it cancelled itself meanwhile
+static mut RUNNING: Option<u32> = None;
+static mut RUNNING_CANCELLED: bool = false;
+static mut INITIALIZED: bool = false;
+
+/// Start taking the C1 interrupt for `after()` and `every()`.
+///
+/// Must be called after `interrupts::init()`.
+pub unsafe fn init() {
+    TIMERS.clear();
+    clear_match(Channel::C1);
+    let source = Channel::C1.interrupt_source();
+    interrupts::register_handler(source, || interrupt_handler());
+    interrupts::enable_source(source);
+    INITIALIZED = true;
+}
+
+/// Run `callback` once, in IRQ mode, `duration` from now.
+pub fn after<F>(duration: Duration, callback: F) -> TimerHandle
+where
+    F: FnOnce() + 'static,
+{
+    let mut callback = Some(callback);
+    start(
+        duration,
+        0,
+        Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback()
+            }
+        }),
+    )
+}
+
+/// Run `callback` in IRQ mode every `period`, first `period` from now.
+///
+/// Runs missed because IRQs were masked for longer than a period are skipped rather than made up
+/// for back to back.
+pub fn every<F>(period: Duration, callback: F) -> TimerHandle
+where
+    F: FnMut() + 'static,
+{
+    let period_us = duration_us(period).unwrap_or(u64::MAX).max(1);
+    start(period, period_us, Box::new(callback))
+}
+
+fn start(delay: Duration, period_us: u64, callback: Box<dyn FnMut()>) -> TimerHandle {
+    unsafe {
+        assert!(INITIALIZED, "timer::init() has not been called");
+        let saved = exception::local_irq_mask_save();
+        NEXT_ID = NEXT_ID.wrapping_add(1);
+        let id = NEXT_ID;
+        insert(SoftTimer {
+            id,
+            deadline: Instant::now()
+                .checked_add(delay)
+                .unwrap_or(Instant::from_micros(u64::MAX)),
+            period_us,
+            callback,
+        });
+        schedule();
+        exception::local_irq_restore(saved);
+        TimerHandle(id)
+    }
+}
+
+// Must be called with IRQs masked
+unsafe fn insert(timer: SoftTimer) {
+    let index = TIMERS
+        .iter()
+        .position(|pending| pending.deadline > timer.deadline)
+        .unwrap_or(TIMERS.len());
+    TIMERS.insert(index, timer);
+}
+
+// Sets C1 for the soonest deadline, or not at all if there are no timers. Must be called with
+// IRQs masked.
+unsafe fn schedule() {
+    let deadline = match TIMERS.first() {
+        Some(timer) => timer.deadline,
+        None => {
+            clear_match(Channel::C1);
+            return;
+        }
+    };
+    loop {
+        let now = Instant::now();
+        let lead = deadline
+            .as_micros()
+            .saturating_sub(now.as_micros())
+            .clamp(MIN_LEAD_US as u64, MAX_LEAD_US);
+        let target = (now.as_micros() + lead) as u32;
+        set_compare(Channel::C1, target);
+        // in case the counter got there first after all
+        if has_matched(Channel::C1) || (get_ticks().wrapping_sub(target) as i32) < 0 {
+            return;
+        }
+    }
+}
+
+unsafe fn interrupt_handler() {
+    if !has_matched(Channel::C1) {
+        return;
+    }
+    clear_match(Channel::C1);
+
+    while TIMERS
+        .first()
+        .map_or(false, |timer| timer.deadline <= Instant::now())
+    {
+        let mut timer = TIMERS.remove(0);
+        if timer.period_us == 0 {
+            (timer.callback)();
+            continue;
+        }
+        // it is out of TIMERS meanwhile, so it may cancel itself and start others
+        RUNNING = Some(timer.id);
+        RUNNING_CANCELLED = false;
+        (timer.callback)();
+        RUNNING = None;
+        if !RUNNING_CANCELLED {
+            let now = Instant::now().as_micros();
+            let mut deadline = timer.deadline.as_micros().saturating_add(timer.period_us);
+            if deadline <= now {
+                let missed = (now - deadline) / timer.period_us + 1;
+                deadline += missed * timer.period_us;
+            }
+            timer.deadline = Instant::from_micros(deadline);
+            insert(timer);
+        }
+    }
+    schedule();
+}
+
+#[test_case]
+fn test_duration_us() {
+    assert_eq!(duration_us(Duration::from_millis(3)), Some(3000));
+    assert_eq!(duration_us(Duration::from_nanos(1500)), Some(2));
+    assert_eq!(duration_us(Duration::from_secs(5000)), Some(5_000_000_000));
+    assert_eq!(duration_us(Duration::from_secs(u64::MAX)), None);
+}
+
+#[test_case]
+fn test_sleep_is_not_short() {
+    for &us in &[0, 1, 2, 15, 1000] {
+        let start = Instant::now();
+        let cycles = cpu::cycle_count();
+        sleep(Duration::from_micros(us));
+        assert!(start.elapsed() >= Duration::from_micros(us));
+        // the cycle counter runs too, well below the default 700MHz being enough
+        assert!(cpu::cycle_count().wrapping_sub(cycles) as u64 >= us * 100);
+    }
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let boot = Instant::from_micros(0);
+    let later = boot + Duration::from_secs(5000);
+    // past where get_ticks() wraps around
+    assert_eq!(later.as_micros(), 5_000_000_000);
+    assert_eq!(later - boot, Duration::from_secs(5000));
+    assert_eq!(boot - later, Duration::from_secs(0));
+    assert_eq!(
+        later - Duration::from_micros(1),
+        Instant::from_micros(4_999_999_999)
+    );
+    assert_eq!(boot.checked_sub(Duration::from_micros(1)), None);
+    assert!(boot < later);
+
+    let mut deadline = Instant::now();
+    for _ in 0..3 {
+        deadline += Duration::from_millis(2);
+        sleep_until(deadline);
+    }
+    assert!(deadline.elapsed() < Duration::from_millis(1));
+}
+
+#[test_case]
+fn test_after_and_every() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static FIRED: AtomicU32 = AtomicU32::new(0);
+    static TICKS: AtomicU32 = AtomicU32::new(0);
+    unsafe {
+        let start = get_ticks();
+        after(Duration::from_millis(2), || {
+            FIRED.store(get_ticks(), Ordering::Relaxed);
+        });
+        let cancelled = after(Duration::from_millis(1), || panic!("cancelled timer ran"));
+        let periodic = every(Duration::from_millis(1), || {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(cancelled.cancel());
+        assert!(!cancelled.cancel());
+        delay_ms(10);
+        assert!(periodic.cancel());
+
+        let fired = FIRED.load(Ordering::Relaxed);
+        assert!(fired.wrapping_sub(start) >= 2000 && fired.wrapping_sub(start) < 3000);
+        let ticks = TICKS.load(Ordering::Relaxed);
+        assert!((9..=10).contains(&ticks), "{} ticks", ticks);
+        delay_ms(2);
+        assert_eq!(TICKS.load(Ordering::Relaxed), ticks);
+    }
 }
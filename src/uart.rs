@@ -1,30 +1,56 @@
 // Author: Flynn Dreilinger <flynnd@stanford.edu>
 // based on uart.c by Pat Hanrahan: https://github.com/cs107e/cs107e.github.io/blob/master/cs107e/src/uart.c
 
-use crate::cpu;
+use crate::ring_buffer::{ByteQueue, RingBuffer};
+use crate::serial::{BreakByte, Overruns, Serial};
+use crate::{cpu, exception, interrupts, mailbox};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 // AUX bits
+const AUX_IRQ: *const u32 = 0x20215000 as *const u32;
+const AUX_IRQ_MINI_UART: u32 = 0x00000001;
 const AUX_ENABLES: u32 = 0x20215004;
 const AUX_ENABLE: u32 = 0x00000001;
 
 // Mini UART
 const MINI_UART_BASE: u32 = 0x20215040;
 
+// The data sheet swaps the RX and TX enable bits, and bits 3:2 are needed for any interrupt
+// to be raised at all (see the BCM2835 errata).
+const MINI_UART_IER_RX_ENABLE: u32 = 0x00000001;
+const MINI_UART_IER_TX_ENABLE: u32 = 0x00000002;
+const MINI_UART_IER_REQUIRED: u32 = 0x0000000C;
+
 const MINI_UART_IIR_RX_FIFO_CLEAR: u32 = 0x00000002;
 const MINI_UART_IIR_TX_FIFO_CLEAR: u32 = 0x00000004;
 const MINI_UART_IIR_RX_FIFO_ENABLE: u32 = 0x00000080;
 const MINI_UART_IIR_TX_FIFO_ENABLE: u32 = 0x00000040;
 
+const MINI_UART_LCR_7BIT: u32 = 0x00000000;
 const MINI_UART_LCR_8BIT: u32 = 0x00000003;
 
+// The baud rate register is 16 bits wide; BAUD_DIVISOR_MAX is one more than its largest value
+const BAUD_DIVISOR_MAX: u64 = 0x10000;
+// The rate both ends use may differ by about 5% before bits are sampled wrong, so allow half
+const BAUD_TOLERANCE_PERMILLE: u64 = 25;
+// The core clock the firmware runs at unless configured otherwise, and the divisor for 115200
+// baud from it: ((250,000,000/115200)/8)-1 = 270
+const DEFAULT_CORE_CLOCK_HZ: u32 = 250_000_000;
+const DEFAULT_BAUD_DIVISOR: u32 = 270;
+
 const MINI_UART_LSR_RX_READY: u32 = 0x00000001;
+const MINI_UART_LSR_RX_OVERRUN: u32 = 0x00000002;
 // const MINI_UART_LSR_TX_READY: u32 = 0x00000010;
 const MINI_UART_LSR_TX_EMPTY: u32 = 0x00000020;
 
 const MINI_UART_CNTL_TX_ENABLE: u32 = 0x00000002;
 const MINI_UART_CNTL_RX_ENABLE: u32 = 0x00000001;
+const MINI_UART_CNTL_RTS_FLOW: u32 = 0x00000004;
+const MINI_UART_CNTL_CTS_FLOW: u32 = 0x00000008;
 
 const GPIO_BASE: u32 = 0x20200000; // leave here to test GPIO module
+const GPIO_FUNC_ALT5: u32 = 0b010;
 
 #[repr(C)]
 pub struct Uart {
@@ -44,6 +70,143 @@ static mut UART: *mut Uart = MINI_UART_BASE as u32 as *mut Uart as *mut Uart;
 
 static mut INITIALIZED: bool = false;
 
+// Queues between the interrupt handler and readers/writers in interrupt mode, see
+// enable_interrupts(). None while polling.
+static mut RX_QUEUE: Option<&'static dyn ByteQueue> = None;
+static mut TX_QUEUE: Option<&'static dyn ByteQueue> = None;
+
+// Bytes lost on receive, see overruns()
+static FIFO_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static QUEUE_OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static BREAK_BYTE: BreakByte = BreakByte::new();
+
+/// Number of data bits per character. The mini UART has no parity and always sends one stop bit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Seven,
+    Eight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// Hardware flow control with CTS on GPIO 16 and RTS on GPIO 17.
+    RtsCts,
+}
+
+/// Line settings for `init_with()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for Config {
+    /// 115200 baud 8N1 without flow control, what `init()` sets up.
+    fn default() -> Self {
+        Config {
+            baud: 115200,
+            data_bits: DataBits::Eight,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UartError {
+    /// The firmware did not report the core clock through the mailbox.
+    ClockUnavailable,
+    /// The core clock can't be divided down to within BAUD_TOLERANCE_PERMILLE of the requested
+    /// rate; `closest` is the nearest rate it can.
+    BaudRate { requested: u32, closest: u32 },
+}
+
+// Returns the baud rate register value for the rate closest to `baud`, which the mini UART
+// derives from the core clock as core_clock / (8 * (divisor + 1)).
+fn baud_divisor(core_clock: u32, baud: u32) -> Result<u32, UartError> {
+    let eight_baud = 8 * baud as u64;
+    let divisor = if baud == 0 {
+        BAUD_DIVISOR_MAX
+    } else {
+        ((core_clock as u64 + eight_baud / 2) / eight_baud).clamp(1, BAUD_DIVISOR_MAX)
+    };
+    let closest = core_clock as u64 / (8 * divisor);
+
+    let error = if closest > baud as u64 {
+        closest - baud as u64
+    } else {
+        baud as u64 - closest
+    };
+    if error * 1000 > baud as u64 * BAUD_TOLERANCE_PERMILLE {
+        return Err(UartError::BaudRate {
+            requested: baud,
+            closest: closest as u32,
+        });
+    }
+    Ok(divisor as u32 - 1)
+}
+
+#[test_case]
+fn test_baud_divisor() {
+    // the firmware's default core clock, and overclocked with core_freq=400
+    assert_eq!(
+        baud_divisor(DEFAULT_CORE_CLOCK_HZ, 115200),
+        Ok(DEFAULT_BAUD_DIVISOR)
+    );
+    assert_eq!(baud_divisor(400_000_000, 115200), Ok(433));
+    assert_eq!(baud_divisor(250_000_000, 9600), Ok(3254));
+    // the divisor can't get small enough, nor large enough
+    assert_eq!(
+        baud_divisor(250_000_000, 3_000_000),
+        Err(UartError::BaudRate {
+            requested: 3_000_000,
+            closest: 3_125_000
+        })
+    );
+    assert_eq!(
+        baud_divisor(250_000_000, 300),
+        Err(UartError::BaudRate {
+            requested: 300,
+            closest: 476
+        })
+    );
+    assert!(baud_divisor(250_000_000, 0).is_err());
+}
+
+/// Set up the UART for 115200 baud 8N1, with the divisor derived from the core clock.
+///
+/// If the mailbox can't tell the core clock, it is assumed to run at the firmware's default
+/// 250 MHz, so that there is a console no matter what.
+pub unsafe fn init() {
+    let config = Config::default();
+    if init_with(config).is_err() {
+        configure(DEFAULT_BAUD_DIVISOR, config);
+    }
+}
+
+/// Set up the UART with the line settings in `config`.
+///
+/// The baud rate divisor is computed from the core clock the firmware reports, which changes
+/// with overclocking or `core_freq` in config.txt. Fails without touching the UART if the rate
+/// can't be hit within 2.5%. Bytes still waiting to be sent are sent with the old settings
+/// first.
+pub unsafe fn init_with(config: Config) -> Result<(), UartError> {
+    let core_clock =
+        mailbox::get_clock_rate(mailbox::CLOCK_CORE).ok_or(UartError::ClockUnavailable)?;
+    let divisor = baud_divisor(core_clock, config.baud)?;
+    configure(divisor, config);
+    Ok(())
+}
+
+// Selects alt function 5, the mini UART, for `pin`. This stays away from the gpio module so the
+// console keeps working while that is being tested.
+unsafe fn select_alt5(pin: u32) {
+    let fsel = (GPIO_BASE as *mut u32).offset(pin as isize / 10);
+    let shift = (pin % 10) * 3;
+    fsel.write_volatile((fsel.read_volatile() & !(0b111 << shift)) | (GPIO_FUNC_ALT5 << shift));
+}
+
 /* Key detail from the Broadcom Peripherals data sheet p.10
 *
 * GPIO pins should be set up first the before enabling the UART.
@@ -53,23 +216,36 @@ static mut INITIALIZED: bool = false;
 * that will be seen as a start bit and the UART will start receiving 0x00-characters.
 * [...] The result will be that the FIFO is full and overflowing in no time flat.
 */
-pub unsafe fn init() {
+unsafe fn configure(divisor: u32, config: Config) {
+    if INITIALIZED {
+        flush();
+    }
     cpu::dev_barrier();
-    let gpio = GPIO_BASE as *const u32;
-    let fsel_1 = gpio.offset(1) as *mut u32;
 
-    // configure tx (14) and rx (15) as alt fn 5
-    {
-        *(fsel_1) = 1001 << 10;
+    // configure tx (14) and rx (15), and for flow control cts (16) and rts (17), as alt fn 5
+    select_alt5(14);
+    select_alt5(15);
+    if config.flow_control == FlowControl::RtsCts {
+        select_alt5(16);
+        select_alt5(17);
     }
 
     // must enable mini-uart before accessing registers
     let aux: *mut u32 = AUX_ENABLES as u32 as *mut u32;
     *aux |= AUX_ENABLE as u32;
 
+    let lcr = match config.data_bits {
+        DataBits::Seven => MINI_UART_LCR_7BIT,
+        DataBits::Eight => MINI_UART_LCR_8BIT,
+    };
+    let mut cntl = MINI_UART_CNTL_TX_ENABLE | MINI_UART_CNTL_RX_ENABLE;
+    if config.flow_control == FlowControl::RtsCts {
+        cntl |= MINI_UART_CNTL_RTS_FLOW | MINI_UART_CNTL_CTS_FLOW;
+    }
+
     core::ptr::write_volatile(&mut (*UART).ier, 0_u32); // wait for char
     core::ptr::write_volatile(&mut (*UART).cntl, 0_u32);
-    core::ptr::write_volatile(&mut (*UART).lcr, MINI_UART_LCR_8BIT as u32);
+    core::ptr::write_volatile(&mut (*UART).lcr, lcr);
     core::ptr::write_volatile(&mut (*UART).mcr, 0_u32);
     core::ptr::write_volatile(&mut (*UART).ier, 0_u32);
     core::ptr::write_volatile(
@@ -79,12 +255,15 @@ pub unsafe fn init() {
             | MINI_UART_IIR_TX_FIFO_CLEAR
             | MINI_UART_IIR_TX_FIFO_ENABLE) as u32,
     );
-    // baud rate ((250,000,000/115200)/8)-1 = 270
-    core::ptr::write_volatile(&mut (*UART).baud, 270_u32);
-    core::ptr::write_volatile(
-        &mut (*UART).cntl,
-        (MINI_UART_CNTL_TX_ENABLE | MINI_UART_CNTL_RX_ENABLE) as u32,
-    );
+    core::ptr::write_volatile(&mut (*UART).baud, divisor);
+    core::ptr::write_volatile(&mut (*UART).cntl, cntl);
+    if RX_QUEUE.is_some() {
+        // reconfigured in interrupt mode
+        core::ptr::write_volatile(
+            &mut (*UART).ier,
+            MINI_UART_IER_REQUIRED | MINI_UART_IER_RX_ENABLE,
+        );
+    }
     INITIALIZED = true;
     cpu::dev_barrier();
 }
@@ -97,11 +276,23 @@ unsafe fn send(byte: u8) {
 }
 
 unsafe fn receive() -> u8 {
-    while !has_char() {}
-    (*UART).data as u8
+    loop {
+        if let Some(byte) = try_read() {
+            return byte;
+        }
+    }
 }
 
+/// Wait until every byte written has been sent.
 pub unsafe fn flush() {
+    if let Some(queue) = TX_QUEUE {
+        // with IRQs masked we may take over the interrupt handler's side of the queue
+        let saved = exception::local_irq_mask_save();
+        while let Some(byte) = queue.pop() {
+            send(byte);
+        }
+        exception::local_irq_restore(saved);
+    }
     while (*UART).lsr & MINI_UART_LSR_TX_EMPTY as u32 == 0 {}
 }
 
@@ -132,9 +323,38 @@ pub unsafe fn put_u8(character: u8) {
     if !INITIALIZED {
         init();
     }
-    cpu::dev_barrier();
-    send(character);
-    cpu::dev_barrier();
+    match TX_QUEUE {
+        Some(queue) => queue_byte(queue, character),
+        None => {
+            cpu::dev_barrier();
+            send(character);
+            cpu::dev_barrier();
+        }
+    }
+}
+
+// Hands a byte to the interrupt handler, waiting for room in the queue. With IRQs masked
+// nobody else makes room, so the oldest queued byte is sent right away instead.
+//
+// Handlers print too, and the queue takes a single producer, so every push is made with IRQs
+// masked. They are unmasked in between, for the interrupt handler to make room.
+unsafe fn queue_byte(queue: &dyn ByteQueue, byte: u8) {
+    let masked = exception::local_irq_is_masked();
+    loop {
+        let saved = exception::local_irq_mask_save();
+        let pushed = queue.push(byte);
+        if pushed {
+            enable_tx_interrupt();
+        } else if masked {
+            if let Some(oldest) = queue.pop() {
+                send(oldest);
+            }
+        }
+        exception::local_irq_restore(saved);
+        if pushed {
+            return;
+        }
+    }
 }
 
 #[test_case]
@@ -162,42 +382,207 @@ fn test_put_u8() {
     }
 }
 
-/*
+// Sends the UTF-8 encoding of `character`, one to four bytes
 pub unsafe fn put_utf8_char(character: char) {
-    cpu::dev_barrier();
+    let mut buffer = [0; 4];
+    for byte in character.encode_utf8(&mut buffer).bytes() {
+        put_u8(byte);
+    }
+}
+
+#[test_case]
+fn test_put_utf8_char() {
+    unsafe {
+        put_utf8_char('h');
+        put_utf8_char('é');
+        put_utf8_char('€');
+        put_utf8_char('🚀');
+    }
+}
+
+pub unsafe fn put_string(string: &str) {
+    for byte in string.bytes() {
+        put_u8(byte);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Interrupt mode
+//--------------------------------------------------------------------------------------------------
+
+/// Switch to interrupt driven operation, with a receive queue of `RX` - 1 bytes and a transmit
+/// queue of `TX` - 1 bytes.
+///
+/// Afterwards bytes are received in the background, and `write()`, `put_u8()` and printing only
+/// wait when the transmit queue is full. Calling it again has no effect. Must be called after
+/// `interrupts::init()`.
+pub unsafe fn enable_interrupts<const RX: usize, const TX: usize>() {
+    assert!(RX > 1 && TX > 1, "uart queues must hold at least one byte");
     if !INITIALIZED {
         init();
     }
-    for i in (0..4).rev() {
-        put_u8((character as u32 >> (i * 2)) as u8 & 0xFF as u8);
+    if TX_QUEUE.is_some() {
+        return;
     }
+    flush();
+    RX_QUEUE = Some(Box::leak(Box::new(RingBuffer::<RX>::new())));
+    TX_QUEUE = Some(Box::leak(Box::new(RingBuffer::<TX>::new())));
+
+    interrupts::register_handler(interrupts::INTERRUPTS_AUX, || interrupt_handler());
+    interrupts::enable_source(interrupts::INTERRUPTS_AUX);
+    cpu::dev_barrier();
+    core::ptr::write_volatile(
+        &mut (*UART).ier,
+        MINI_UART_IER_REQUIRED | MINI_UART_IER_RX_ENABLE,
+    );
     cpu::dev_barrier();
 }
 
+// The transmit interrupt fires while the TX FIFO is empty, so it is only enabled while there
+// is something queued; the handler turns it off again.
+unsafe fn enable_tx_interrupt() {
+    cpu::dev_barrier();
+    core::ptr::write_volatile(
+        &mut (*UART).ier,
+        MINI_UART_IER_REQUIRED | MINI_UART_IER_RX_ENABLE | MINI_UART_IER_TX_ENABLE,
+    );
+    cpu::dev_barrier();
+}
+
+unsafe fn interrupt_handler() {
+    cpu::dev_barrier();
+    if AUX_IRQ.read_volatile() & AUX_IRQ_MINI_UART == 0 {
+        return; // one of the AUX SPI masters
+    }
+    let (rx_queue, tx_queue) = match (RX_QUEUE, TX_QUEUE) {
+        (Some(rx_queue), Some(tx_queue)) => (rx_queue, tx_queue),
+        _ => return,
+    };
+
+    loop {
+        let lsr = core::ptr::read_volatile(&(*UART).lsr);
+        if lsr & MINI_UART_LSR_RX_OVERRUN != 0 {
+            FIFO_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & MINI_UART_LSR_RX_READY == 0 {
+            break;
+        }
+        let byte = core::ptr::read_volatile(&(*UART).data) as u8;
+        if BREAK_BYTE.is_data(byte) && !rx_queue.push(byte) {
+            QUEUE_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    while core::ptr::read_volatile(&(*UART).lsr) & MINI_UART_LSR_TX_EMPTY != 0 {
+        match tx_queue.pop() {
+            Some(byte) => core::ptr::write_volatile(&mut (*UART).data, byte as u32),
+            None => {
+                core::ptr::write_volatile(
+                    &mut (*UART).ier,
+                    MINI_UART_IER_REQUIRED | MINI_UART_IER_RX_ENABLE,
+                );
+                break;
+            }
+        }
+    }
+    cpu::dev_barrier();
+}
+
+/// Returns the next byte received without waiting, if there is one.
+pub unsafe fn try_read() -> Option<u8> {
+    if !INITIALIZED {
+        init();
+    }
+    match RX_QUEUE {
+        // with IRQs masked nobody fills the queue, so the FIFO is read once it is empty
+        Some(queue) => match queue.pop() {
+            None if exception::local_irq_is_masked() && has_char() => Some((*UART).data as u8),
+            byte => byte,
+        },
+        None if has_char() => Some((*UART).data as u8),
+        None => None,
+    }
+}
+
+/// Queue as many of `bytes` as fit for sending and return how many that was.
+///
+/// Only interrupt mode has a queue; while polling every byte is sent before returning.
+pub unsafe fn write(bytes: &[u8]) -> usize {
+    if !INITIALIZED {
+        init();
+    }
+    let queue = match TX_QUEUE {
+        Some(queue) => queue,
+        None => {
+            for &byte in bytes {
+                send(byte);
+            }
+            return bytes.len();
+        }
+    };
+    // a single producer at a time, see queue_byte()
+    let saved = exception::local_irq_mask_save();
+    let written = bytes.iter().take_while(|&&byte| queue.push(byte)).count();
+    enable_tx_interrupt();
+    exception::local_irq_restore(saved);
+    written
+}
+
+/// Bytes lost on receive since boot.
+pub fn overruns() -> Overruns {
+    Overruns {
+        fifo: FIFO_OVERRUNS.load(Ordering::Relaxed),
+        queue: QUEUE_OVERRUNS.load(Ordering::Relaxed),
+    }
+}
+
 #[test_case]
-fn test_put_utf8_char() {
-    // say hello
+fn test_interrupt_mode() {
     unsafe {
-        cpu::dev_barrier();
-        put_utf8_char('h');
-        put_utf8_char('e'); // TODO this test breaks something and does not panic, not sure what
-                            // is going on here. Will fix in subsequent PR
-        cpu::dev_barrier();
+        // runtime_init() switched to interrupt mode already, so this changes nothing
+        enable_interrupts::<64, 16>();
+        let tx_queue = TX_QUEUE.expect("runtime_init() enables interrupt mode");
+        assert_eq!(RX_QUEUE.map(|queue| queue.capacity()), Some(255));
+        assert_eq!(tx_queue.capacity(), 4095);
+
+        // more than fits in the queue, so the interrupt handler has to drain it meanwhile
+        let line = b"sent from the transmit interrupt\r\n";
+        for _ in 0..130 {
+            let mut written = 0;
+            while written < line.len() {
+                written += write(&line[written..]);
+            }
+        }
+        flush();
+        assert!(tx_queue.is_empty());
     }
 }
 
-pub unsafe fn launch() {
-    put_u8(0xF0);
-    put_u8(0x9F);
-    put_u8(0x9A);
-    put_u8(0x80);
-}
-*/
+/// The mini UART behind the `Serial` interface, e.g. for `serial::set_console()`.
+pub struct MiniUart;
+
+impl Serial for MiniUart {
+    fn try_read(&self) -> Option<u8> {
+        unsafe { try_read() }
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        unsafe { write(bytes) }
+    }
 
-/*
-pub unsafe fn put_string(str: str) {
-    for c in str.chars() {
-        put_u8(c as u8);
+    fn put_u8(&self, byte: u8) {
+        unsafe { put_u8(byte) }
+    }
+
+    fn flush(&self) {
+        unsafe { flush() }
+    }
+
+    fn overruns(&self) -> Overruns {
+        overruns()
+    }
+
+    fn set_break_byte(&self, byte: Option<u8>) {
+        BREAK_BYTE.set(byte);
     }
 }
-*/
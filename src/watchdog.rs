@@ -0,0 +1,147 @@
+/*
+ * Driver for the watchdog of the BCM2835 power management block, which
+ * resets the board when its counter runs out. Besides catching hangs, it is
+ * the only way to restart or halt the board from software.
+ *
+ * Every register takes writes only with the password in the top byte. The
+ * counter ticks 65536 times a second, so the longest timeout is 16s.
+ *
+ * With the bootloader on the SD card (see README.md), a reset lands back in
+ * it, ready for the next kernel.
+ *
+ * Based off of the Linux bcm2835_wdt driver; the data sheet leaves the block
+ * out.
+ */
+
+use crate::cpu;
+use crate::timer::Duration;
+
+const PM_BASE: u32 = 0x20100000;
+const PM_RSTC: *mut u32 = (PM_BASE + 0x1C) as *mut u32;
+const PM_RSTS: *mut u32 = (PM_BASE + 0x20) as *mut u32;
+const PM_WDOG: *mut u32 = (PM_BASE + 0x24) as *mut u32;
+
+const PM_PASSWORD: u32 = 0x5A000000;
+const PM_WDOG_TIME_MASK: u32 = 0x000FFFFF;
+const PM_RSTC_WRCFG_MASK: u32 = 0x00000030;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x00000020;
+const PM_RSTC_RESET: u32 = 0x00000102;
+// Sticky flags of past resets
+const PM_RSTS_HADWRH: u32 = 0x00000040;
+const PM_RSTS_HADPOR: u32 = 0x00001000;
+// The firmware boots from the partition in the even bits; 63 has it halt instead
+const PM_RSTS_PARTITION_MASK: u32 = 0x00000555;
+const PM_RSTS_PARTITION_HALT: u32 = 0x00000555;
+
+const TICKS_PER_SECOND: u64 = 65536;
+
+/// The longest timeout `start()` takes.
+pub const MAX_TIMEOUT: Duration =
+    Duration::from_micros(PM_WDOG_TIME_MASK as u64 * 1_000_000 / TICKS_PER_SECOND);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The timeout is shorter than a tick or longer than MAX_TIMEOUT.
+    Timeout(Duration),
+}
+
+/// What last reset the board, as the power management block remembers it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    /// The watchdog ran out, or `reboot()` or `halt()` had it reset the board.
+    Watchdog,
+    Unknown,
+}
+
+// The timeout of the running watchdog, in ticks
+static mut TIMEOUT_TICKS: u32 = 0;
+
+// Ticks in `timeout`, rounded down, or None if that is 0 or more than the counter holds
+fn timeout_ticks(timeout: Duration) -> Option<u32> {
+    if timeout > MAX_TIMEOUT {
+        return None;
+    }
+    match (timeout.as_micros() * TICKS_PER_SECOND as u128 / 1_000_000) as u32 {
+        0 => None,
+        ticks => Some(ticks),
+    }
+}
+
+#[test_case]
+fn test_timeout_ticks() {
+    assert_eq!(timeout_ticks(Duration::from_secs(1)), Some(65536));
+    assert_eq!(timeout_ticks(Duration::from_millis(250)), Some(16384));
+    assert_eq!(timeout_ticks(MAX_TIMEOUT), Some(PM_WDOG_TIME_MASK - 1));
+    assert_eq!(timeout_ticks(Duration::from_micros(10)), None);
+    assert_eq!(timeout_ticks(Duration::from_secs(16)), None);
+}
+
+// Have the board reset once `ticks` run out
+unsafe fn arm(ticks: u32) {
+    cpu::dev_barrier();
+    PM_WDOG.write_volatile(PM_PASSWORD | ticks & PM_WDOG_TIME_MASK);
+    let rstc = PM_RSTC.read_volatile() & !PM_RSTC_WRCFG_MASK;
+    PM_RSTC.write_volatile(PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+    cpu::dev_barrier();
+}
+
+/// Reset the board unless `pet()` is called at least every `timeout`. Calling it again
+/// changes the timeout.
+pub unsafe fn start(timeout: Duration) -> Result<(), WatchdogError> {
+    let ticks = timeout_ticks(timeout).ok_or(WatchdogError::Timeout(timeout))?;
+    TIMEOUT_TICKS = ticks;
+    arm(ticks);
+    Ok(())
+}
+
+/// Start counting the timeout again. Does nothing while the watchdog is stopped.
+pub unsafe fn pet() {
+    if is_running() {
+        cpu::dev_barrier();
+        PM_WDOG.write_volatile(PM_PASSWORD | TIMEOUT_TICKS);
+        cpu::dev_barrier();
+    }
+}
+
+pub unsafe fn stop() {
+    cpu::dev_barrier();
+    PM_RSTC.write_volatile(PM_PASSWORD | PM_RSTC_RESET);
+    cpu::dev_barrier();
+}
+
+pub unsafe fn is_running() -> bool {
+    cpu::dev_barrier();
+    PM_RSTC.read_volatile() & PM_RSTC_WRCFG_FULL_RESET != 0
+}
+
+pub fn reset_reason() -> ResetReason {
+    let rsts = unsafe {
+        cpu::dev_barrier();
+        PM_RSTS.read_volatile()
+    };
+    // a power-on reset clears the flag of an earlier watchdog reset, not the other way around
+    if rsts & PM_RSTS_HADWRH != 0 {
+        ResetReason::Watchdog
+    } else if rsts & PM_RSTS_HADPOR != 0 {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+/// Have the watchdog reset the board right away.
+pub fn reboot() -> ! {
+    unsafe { arm(1) };
+    cpu::wait_forever()
+}
+
+/// Reset the board into a halt: the firmware stops rather than boot, until the power is cycled.
+pub fn halt() -> ! {
+    unsafe {
+        cpu::dev_barrier();
+        let rsts = PM_RSTS.read_volatile() & !(0xFF000000 | PM_RSTS_PARTITION_MASK);
+        PM_RSTS.write_volatile(PM_PASSWORD | rsts | PM_RSTS_PARTITION_HALT);
+    }
+    reboot()
+}